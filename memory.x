@@ -3,10 +3,24 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
-  RAM : ORIGIN = 0x20000000, LENGTH = 8K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 32K - 4K
+  /* last two 2K pages, parameter store, see src/config_store.rs */
+  CONFIG : ORIGIN = 0x08000000 + 32K - 4K, LENGTH = 4K
+  RAM : ORIGIN = 0x20000000, LENGTH = 8K - 256
+  /* crash record kept across resets, see src/crash.rs */
+  NOINIT : ORIGIN = 0x20000000 + 8K - 256, LENGTH = 256
 }
 
+_config_start = ORIGIN(CONFIG);
+
+SECTIONS {
+  .noinit (NOLOAD) : ALIGN(4)
+  {
+    *(.noinit .noinit.*);
+    . = ALIGN(4);
+  } > NOINIT
+} INSERT AFTER .got;
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* You may want to use this variable to locate the call stack and static
@@ -1,29 +1,1188 @@
-use core::convert::TryInto;
-
+use crate::control_table::{
+    self, decay_mode, hardware_error, moving_status, operating_mode, timeout_action, ControlTable,
+    Item,
+};
+use crate::current::{CurrentLoop, CurrentSense, CurrentSenseConfig};
+use crate::dc_motor_driver::{DcMotorDriver, DecayMode, Fault, PwmConfig, DUTY_ONE};
+use crate::dynamixel::{self, ErrorCode};
+use crate::encoder::Encoder;
+use crate::i2t::{I2tConfig, I2tModel};
 use crate::indicator::Indicator;
+use crate::pid::{self, AntiWindup, Pid, PidConfig, Ramp};
+use crate::supply::{SupplyConfig, SupplyMonitor};
+use crate::temperature::{TemperatureConfig, TemperatureMonitor};
+use crate::velocity::{VelocityConfig, VelocityEstimator};
+use crate::watchdog::ResetCause;
+
+// Control table gain -> controller gain, output in PWM units, velocity in counts/s
+const VELOCITY_P_SCALE: f32 = 1.0 / 128.0;
+const VELOCITY_I_SCALE: f32 = 1.0 / 64.0;
+const VELOCITY_D_SCALE: f32 = 1.0 / 16384.0;
+// position loop output is a velocity setpoint in counts/s
+const POSITION_P_SCALE: f32 = 1.0 / 128.0;
+const POSITION_I_SCALE: f32 = 1.0 / 1024.0;
+const POSITION_D_SCALE: f32 = 1.0 / 16384.0;
+// current loop output is a duty in -1.0..=1.0, current in A
+const CURRENT_P_SCALE: f32 = 1.0 / 1024.0;
+const CURRENT_I_SCALE: f32 = 1.0 / 16.0;
+// full duty to zero in 0.5s after a command timeout
+const TIMEOUT_RAMP_RATE: f32 = control_table::PWM_MAX as f32 * 2.0;
 
-pub struct App<T0, T1>
+pub struct App<T0, T1, M, E>
 where
     T0: Indicator,
     T1: Indicator,
+    M: DcMotorDriver,
+    E: Encoder,
 {
     led0: T0,
     led1: T1,
+    motor: M,
+    encoder: E,
+    table: ControlTable,
+    pending_baud_rate: Option<u32>,
+    velocity: VelocityEstimator,
+    velocity_pid: Pid,
+    velocity_ramp: Ramp,
+    position_pid: Pid,
+    /// Position trajectory limited by `ProfileVelocity`.
+    position_ramp: Ramp,
+    current_sense: CurrentSense,
+    current_loop: CurrentLoop,
+    /// Keeps the measured current within the I²t limit outside current mode,
+    /// by bringing `current_ceiling` down.
+    current_limiter: CurrentLoop,
+    /// Largest duty magnitude allowed by `current_limiter`.
+    current_ceiling: f32,
+    supply: SupplyMonitor,
+    temperature: TemperatureMonitor,
+    i2t: I2tModel,
+    last_time_us: Option<u32>,
+    /// Time of the last goal write while torque was on.
+    last_command_us: Option<u32>,
+    /// The host went quiet, torque is being taken away.
+    failsafe: bool,
+    failsafe_ramp: Ramp,
+    /// Persistent items changed since the last `config_saved`.
+    config_dirty: bool,
+    /// A driver did not come up, torque is refused.
+    init_failed: bool,
 }
 
-impl<T0, T1> App<T0, T1>
+impl<T0, T1, M, E> App<T0, T1, M, E>
 where
     T0: Indicator,
     T1: Indicator,
+    M: DcMotorDriver,
+    E: Encoder,
 {
-    pub fn new(led0: T0, led1: T1) -> Self {
-        Self {
+    pub fn new(led0: T0, led1: T1, motor: M, encoder: E) -> Self {
+        let mut app = Self {
             led0,
             led1,
+            motor,
+            encoder,
+            table: ControlTable::new(),
+            pending_baud_rate: None,
+            velocity: VelocityEstimator::new(VelocityConfig::default()),
+            velocity_pid: Pid::new(PidConfig::default()),
+            velocity_ramp: Ramp::new(0.0),
+            position_pid: Pid::new(PidConfig::default()),
+            position_ramp: Ramp::new(0.0),
+            current_sense: CurrentSense::new(CurrentSenseConfig::default()),
+            current_loop: CurrentLoop::new(),
+            current_limiter: CurrentLoop::new(),
+            current_ceiling: 1.0,
+            supply: SupplyMonitor::new(SupplyConfig::default()),
+            temperature: TemperatureMonitor::new(TemperatureConfig::default()),
+            i2t: I2tModel::new(I2tConfig::default()),
+            last_time_us: None,
+            last_command_us: None,
+            failsafe: false,
+            failsafe_ramp: Ramp::new(0.0),
+            config_dirty: false,
+            init_failed: false,
+        };
+        app.motor.disable();
+        app.drive_pwm(0);
+        app
+    }
+    /// Loads the parameter block saved by `config_to_save`.
+    pub fn restore_config(&mut self, version: u16, data: &[u8]) {
+        if !self.table.restore(version, data) {
+            warn!("config version {} is newer than this firmware", version);
         }
+        self.apply(Item::PwmFrequency);
+        self.apply(Item::DecayMode);
+        self.apply(Item::MinVoltageLimit);
     }
+
+    /// The parameter block to save, once persistent items have changed.
+    /// Held back while torque is on, writing the flash stalls the CPU.
+    /// Stays pending until `config_saved`.
+    pub fn config_to_save(&self, out: &mut [u8]) -> Option<usize> {
+        if !self.config_dirty || self.table.torque_enabled() {
+            return None;
+        }
+        Some(self.table.save(out))
+    }
+
+    /// The block from `config_to_save` is in flash.
+    pub fn config_saved(&mut self) {
+        self.config_dirty = false;
+    }
+
+    /// Heartbeat on led0. led1 blinks while derating and stays on after an
+    /// over-temperature shutdown, otherwise it follows the `Led` item.
     pub fn periodic_task(&self) {
         self.led0.toggle();
-        self.led1.toggle();
+        let thermal = self.temperature.status();
+        if thermal.shutdown {
+            self.led1.on();
+        } else if thermal.is_derating() {
+            self.led1.toggle();
+        } else if self.table.get(Item::Led) != 0 {
+            self.led1.on();
+        } else {
+            self.led1.off();
+        }
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        control_table::baud_rate(self.table.get(Item::BaudRate) as u8).unwrap_or(57_600)
+    }
+
+    /// Baud rate written over the bus, to be applied after the reply has been sent.
+    pub fn take_pending_baud_rate(&mut self) -> Option<u32> {
+        self.pending_baud_rate.take()
+    }
+
+    pub fn return_delay_us(&self) -> u32 {
+        self.table.get(Item::ReturnDelayTime) as u32 * 2
+    }
+
+    /// Runs the feedback loop. Call at a fixed rate, 1kHz nominal.
+    pub fn control_task(&mut self) {
+        // 1kHz, so the tick counts milliseconds
+        let tick = (self.table.get(Item::RealtimeTick) + 1) % 32768;
+        self.table.set(Item::RealtimeTick, tick);
+        let sample = self.encoder.sample();
+        let dt = match self.last_time_us {
+            Some(t) => sample.time_us.wrapping_sub(t) as f32 * 1e-6,
+            None => 0.0,
+        };
+        self.last_time_us = Some(sample.time_us);
+
+        let sign = self.direction_sign();
+        let velocity = sign * self.velocity.update(sample);
+        let position = if sign < 0.0 {
+            -sample.position
+        } else {
+            sample.position
+        };
+        let offset = self.table.get(Item::HomingOffset);
+        self.table.set(
+            Item::PresentPosition,
+            (position as i32).wrapping_add(offset),
+        );
+        self.table.set(Item::PresentVelocity, velocity as i32);
+        self.update_moving_status(velocity);
+        if dt > 0.0 {
+            self.i2t_task(dt);
+        }
+
+        if self.table.torque_enabled() || self.motor.is_enabled() {
+            if let Some(fault) = self.motor.fault() {
+                self.driver_fault(fault);
+            }
+        }
+        if !self.table.torque_enabled() || dt <= 0.0 {
+            return;
+        }
+        if self.command_timed_out(sample.time_us) {
+            self.enter_failsafe();
+            if !self.table.torque_enabled() {
+                // braking, the duty must not be driven again this tick
+                return;
+            }
+        }
+        if self.failsafe {
+            let pwm = self.failsafe_ramp.update(0.0, TIMEOUT_RAMP_RATE, dt);
+            self.drive_pwm(pwm as i32);
+            if pwm == 0.0 {
+                self.table.set(Item::TorqueEnable, 0);
+                self.apply(Item::TorqueEnable);
+            }
+            return;
+        }
+        if self.following_error() {
+            // zero duty until the host enables torque again
+            self.drive_pwm(0);
+            return;
+        }
+        match self.operating_mode() {
+            operating_mode::VELOCITY => {
+                let goal = self.table.get(Item::GoalVelocity) as f32;
+                let pwm = self.velocity_loop(goal, velocity, dt);
+                self.drive_pwm(pwm as i32);
+            }
+            operating_mode::POSITION => {
+                let goal = self.table.get(Item::GoalPosition) as f32;
+                let present = self.table.get(Item::PresentPosition) as f32;
+                let profile_velocity = self.table.get(Item::ProfileVelocity) as f32;
+                let target = self.position_ramp.update(goal, profile_velocity, dt);
+
+                let following_error_limit = self.table.get(Item::FollowingErrorLimit) as f32;
+                let following_error = target - present;
+                if following_error_limit > 0.0
+                    && (following_error > following_error_limit
+                        || following_error < -following_error_limit)
+                {
+                    self.drive_pwm(0);
+                    self.set_hardware_error(hardware_error::FOLLOWING_ERROR);
+                    return;
+                }
+
+                self.position_pid.set_config(self.position_pid_config());
+                let velocity_setpoint = self.position_pid.update(target, present, dt);
+                let pwm = self.velocity_loop(velocity_setpoint, velocity, dt);
+                self.drive_pwm(pwm as i32);
+            }
+            operating_mode::PWM => {
+                // again every tick, so derating and the current limits reach
+                // a duty that is already running
+                self.drive_pwm(self.table.get(Item::GoalPwm));
+            }
+            _ => (),
+        }
+    }
+
+    /// Runs the current loop on a fresh shunt sample, taken `dt` seconds after the previous one.
+    ///
+    /// The first samples after boot calibrate the zero-current offset; torque
+    /// cannot be enabled until that is done.
+    pub fn current_task(&mut self, raw: u16, dt: f32) {
+        if !self.current_sense.is_calibrated() {
+            if self.current_sense.calibrate(raw) {
+                info!("current offset: {}", self.current_sense.offset());
+            }
+            return;
+        }
+        let amps = self.direction_sign() * self.current_sense.amps(raw);
+        self.table.set(Item::PresentCurrent, (amps * 1000.0) as i32);
+
+        if !self.table.torque_enabled() || self.failsafe {
+            return;
+        }
+        let kp = self.table.get(Item::CurrentPGain) as f32 * CURRENT_P_SCALE;
+        let ki = self.table.get(Item::CurrentIGain) as f32 * CURRENT_I_SCALE;
+        if self.operating_mode() == operating_mode::CURRENT {
+            // the I²t limit is in the target already
+            self.current_ceiling = 1.0;
+            let derating = self.temperature.status().derating;
+            let limit = self.pwm_limit() as f32 / control_table::PWM_MAX as f32;
+            self.current_loop.set_gains(kp, ki, limit);
+            let max = (self.table.get(Item::CurrentLimit) as f32 * 0.001 * derating)
+                .min(self.i2t.current_limit());
+            let target = pid::clamp(self.table.get(Item::GoalCurrent) as f32 * 0.001, -max, max);
+            let duty = self.current_loop.update(target, amps, dt);
+            self.drive_pwm((duty * control_table::PWM_MAX as f32) as i32);
+        } else if self.i2t.duty_limit(self.supply.volts()).is_none() {
+            // settles at full scale while the current is below the limit
+            self.current_limiter.set_gains(kp, ki, 1.0);
+            let magnitude = if amps < 0.0 { -amps } else { amps };
+            let ceiling = self
+                .current_limiter
+                .update(self.i2t.current_limit(), magnitude, dt);
+            self.current_ceiling = pid::clamp(ceiling, 0.0, 1.0);
+        }
+    }
+
+    fn set_supply_config(&mut self, config: SupplyConfig) {
+        self.supply.set_config(config);
+    }
+
+    /// Checks the motor supply against `MinVoltageLimit`..`MaxVoltageLimit`.
+    pub fn supply_task(&mut self, raw: u16) {
+        let was = self.supply.fault();
+        let min = self.table.get(Item::MinVoltageLimit) as f32 * 0.1;
+        let max = self.table.get(Item::MaxVoltageLimit) as f32 * 0.1;
+        let fault = self.supply.update(raw, min, max);
+        self.table.set(
+            Item::PresentInputVoltage,
+            (self.supply.volts() * 10.0) as i32,
+        );
+        if fault == was {
+            return;
+        }
+        let status = self.table.get(Item::HardwareErrorStatus) as u8;
+        match fault {
+            Some(fault) => {
+                warn!("supply {}: {}V", fault as u8, self.supply.volts());
+                self.table.set(
+                    Item::HardwareErrorStatus,
+                    (status | hardware_error::INPUT_VOLTAGE) as i32,
+                );
+                // whatever the shutdown mask says
+                self.torque_off();
+            }
+            // back inside the limits, torque stays off until enabled again
+            None => self.table.set(
+                Item::HardwareErrorStatus,
+                (status & !hardware_error::INPUT_VOLTAGE) as i32,
+            ),
+        }
+    }
+
+    pub fn set_temperature_config(&mut self, config: TemperatureConfig) {
+        self.temperature.set_config(config);
+    }
+
+    /// Updates the temperatures from the internal sensor and the NTC, shutting
+    /// down at `TemperatureLimit`. Call at the control rate.
+    pub fn temperature_task(&mut self, mcu_raw: u16, ntc_raw: u16) {
+        let was_shutdown = self.temperature.status().shutdown;
+        let limit = self.table.get(Item::TemperatureLimit) as f32;
+        let status = self.temperature.update(mcu_raw, ntc_raw, limit);
+        self.table
+            .set(Item::PresentTemperature, status.celsius() as i32);
+        if status.shutdown && !was_shutdown {
+            warn!("over-temperature: {}C", status.celsius());
+            self.set_hardware_error(hardware_error::OVERHEATING);
+            // whatever the shutdown mask says
+            self.torque_off();
+        }
+    }
+
+    /// The clock security system moved SYSCLK to HSI16: torque off and kept
+    /// off, PWM timing re-derived for the new timer clock. Latched until reset.
+    pub fn clock_failure(&mut self) {
+        if self.clock_failed() {
+            return;
+        }
+        error!("HSE failed, running on HSI16");
+        self.set_hardware_error(hardware_error::CLOCK_FAILURE);
+        // whatever the shutdown mask says
+        self.torque_off();
+        self.apply(Item::PwmFrequency);
+    }
+
+    /// Torque cannot be enabled until the cause has gone away, or at all
+    /// after a failed init or a clock failure.
+    fn torque_refused(&self) -> bool {
+        self.init_failed
+            || !self.current_sense.is_calibrated()
+            || self.supply.fault().is_some()
+            || self.temperature.status().shutdown
+            || self.clock_failed()
+    }
+
+    fn clock_failed(&self) -> bool {
+        self.table.get(Item::HardwareErrorStatus) as u8 & hardware_error::CLOCK_FAILURE != 0
+    }
+
+    fn following_error(&self) -> bool {
+        self.table.get(Item::HardwareErrorStatus) as u8 & hardware_error::FOLLOWING_ERROR != 0
+    }
+
+    fn i2t_task(&mut self, dt: f32) {
+        let duty = self.table.get(Item::PresentPwm) as f32 / control_table::PWM_MAX as f32;
+        let measured = self.table.get(Item::PresentCurrent) as f32 * 0.001;
+        let amps = self.i2t.amps(measured, duty, self.supply.volts());
+        self.i2t.update(amps, dt);
+        // the board temperature stands in for the ambient around the motor
+        let ambient = self.temperature.status().celsius();
+        let winding = pid::clamp(self.i2t.winding_celsius(ambient), 0.0, 255.0);
+        self.table
+            .set(Item::PresentWindingTemperature, winding as i32);
+    }
+
+    /// `PwmLimit` reduced by thermal derating and the I²t current limit.
+    fn pwm_limit(&self) -> i32 {
+        let limit = self.table.get(Item::PwmLimit) as f32 * self.temperature.status().derating;
+        let i2t = self
+            .i2t
+            .duty_limit(self.supply.volts())
+            .unwrap_or(self.current_ceiling);
+        limit.min(i2t * control_table::PWM_MAX as f32) as i32
+    }
+
+    /// Makes the previous run's crash readable over the bus.
+    pub fn report_crash(&mut self, kind: u8, address: u32) {
+        self.table.set(Item::LastCrash, kind as i32);
+        self.table.set(Item::CrashAddress, address as i32);
+    }
+
+    pub fn report_reset_cause(&mut self, cause: ResetCause) {
+        self.table.set(Item::ResetCause, cause as i32);
+    }
+
+    /// Startup problem the firmware worked around, 0 for none.
+    pub fn report_init_error(&mut self, code: u8) {
+        self.table.set(Item::InitError, code as i32);
+    }
+
+    /// A driver failed to come up and runs disabled. Torque stays refused
+    /// until reset.
+    pub fn report_init_failure(&mut self, code: u8) {
+        self.report_init_error(code);
+        self.init_failed = true;
+    }
+
+    pub fn set_hardware_error(&mut self, flags: u8) {
+        let status = self.table.get(Item::HardwareErrorStatus) as u8 | flags;
+        self.table.set(Item::HardwareErrorStatus, status as i32);
+        if self.table.get(Item::Shutdown) as u8 & flags != 0 {
+            self.torque_off();
+        }
+    }
+
+    /// Torque off with the bridge disarmed. Also catches a BRAKE timeout,
+    /// which leaves torque reading off while the bridge holds the brake.
+    fn torque_off(&mut self) {
+        if self.table.torque_enabled() || self.motor.is_enabled() {
+            self.table.set(Item::TorqueEnable, 0);
+            self.apply(Item::TorqueEnable);
+        }
+    }
+
+    fn command_timed_out(&self, now_us: u32) -> bool {
+        let timeout_ms = self.table.get(Item::CommandTimeout) as u32;
+        match self.last_command_us {
+            Some(t) if timeout_ms > 0 && !self.failsafe => {
+                now_us.wrapping_sub(t) > timeout_ms * 1000
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes torque away after a command timeout, as `TimeoutAction` says.
+    fn enter_failsafe(&mut self) {
+        warn!("command timeout");
+        let status =
+            self.table.get(Item::HardwareErrorStatus) as u8 | hardware_error::COMMAND_TIMEOUT;
+        self.table.set(Item::HardwareErrorStatus, status as i32);
+        if self.table.get(Item::TimeoutAction) as u8 == timeout_action::BRAKE {
+            // torque reads off, but the bridge stays armed to hold the brake
+            self.motor.brake();
+            self.table.set(Item::PresentPwm, 0);
+            self.table.set(Item::TorqueEnable, 0);
+        } else {
+            self.failsafe = true;
+            self.failsafe_ramp
+                .reset(self.table.get(Item::PresentPwm) as f32);
+        }
+    }
+
+    /// The bridge has been turned off in hardware, follow in the table.
+    fn driver_fault(&mut self, fault: Fault) {
+        error!("bridge tripped: {}", fault as u8);
+        self.set_hardware_error(match fault {
+            Fault::Break => hardware_error::ELECTRICAL_SHOCK,
+            Fault::Break2 => hardware_error::OVERLOAD,
+        });
+        // whatever the shutdown mask says
+        self.torque_off();
+    }
+
+    fn update_moving_status(&mut self, velocity: f32) {
+        let threshold = self.table.get(Item::MovingThreshold) as f32;
+        let moving = velocity > threshold || velocity < -threshold;
+        self.table.set(Item::Moving, moving as i32);
+
+        let mut status = 0;
+        if self.operating_mode() == operating_mode::POSITION {
+            let goal = self.table.get(Item::GoalPosition);
+            let present = self.table.get(Item::PresentPosition);
+            let window = self.table.get(Item::InPositionWindow);
+            if (goal.wrapping_sub(present)).abs() <= window {
+                status |= moving_status::IN_POSITION;
+            }
+            if self.position_ramp.value() != goal as f32 {
+                status |= moving_status::PROFILE_ONGOING;
+            }
+        }
+        self.table.set(Item::MovingStatus, status as i32);
+    }
+
+    fn operating_mode(&self) -> u8 {
+        self.table.get(Item::OperatingMode) as u8
+    }
+
+    fn direction_sign(&self) -> f32 {
+        if self.table.get(Item::DriveMode) & 0x01 != 0 {
+            -1.0
+        } else {
+            1.0
+        }
+    }
+
+    fn velocity_pid_config(&self) -> PidConfig {
+        let limit = self.pwm_limit() as f32;
+        let kp = self.table.get(Item::VelocityPGain) as f32 * VELOCITY_P_SCALE;
+        let ki = self.table.get(Item::VelocityIGain) as f32 * VELOCITY_I_SCALE;
+        let kd = self.table.get(Item::VelocityDGain) as f32 * VELOCITY_D_SCALE;
+        PidConfig {
+            kp,
+            ki,
+            kd,
+            output_min: -limit,
+            output_max: limit,
+            // tracking time constant equal to the integral time
+            anti_windup: if kp > 0.0 {
+                AntiWindup::BackCalculation { kt: ki / kp }
+            } else {
+                AntiWindup::Conditional
+            },
+            derivative_alpha: 0.5,
+        }
+    }
+
+    fn position_pid_config(&self) -> PidConfig {
+        let limit = self.table.get(Item::VelocityLimit) as f32;
+        PidConfig {
+            kp: self.table.get(Item::PositionPGain) as f32 * POSITION_P_SCALE,
+            ki: self.table.get(Item::PositionIGain) as f32 * POSITION_I_SCALE,
+            kd: self.table.get(Item::PositionDGain) as f32 * POSITION_D_SCALE,
+            output_min: -limit,
+            output_max: limit,
+            anti_windup: AntiWindup::Conditional,
+            derivative_alpha: 0.5,
+        }
+    }
+
+    /// Returns the PWM command for a velocity goal in counts/s.
+    fn velocity_loop(&mut self, goal: f32, velocity: f32, dt: f32) -> f32 {
+        self.velocity_pid.set_config(self.velocity_pid_config());
+        let acceleration = self.table.get(Item::ProfileAcceleration) as f32;
+        let setpoint = self.velocity_ramp.update(goal, acceleration, dt);
+        self.velocity_pid.update(setpoint, velocity, dt)
+    }
+
+    /// Starts the loops from the current state so that enabling or switching
+    /// the operating mode does not jerk the motor.
+    fn reset_loops(&mut self) {
+        let velocity = self.table.get(Item::PresentVelocity) as f32;
+        let position = self.table.get(Item::PresentPosition) as f32;
+        self.position_ramp.reset(position);
+        self.position_pid.reset(velocity);
+        self.velocity_ramp.reset(velocity);
+        self.velocity_pid
+            .reset(self.table.get(Item::PresentPwm) as f32);
+        self.current_loop
+            .reset(self.table.get(Item::PresentPwm) as f32 / control_table::PWM_MAX as f32);
+        self.current_limiter.reset(1.0);
+        self.current_ceiling = 1.0;
+    }
+
+    fn drive_pwm(&mut self, pwm: i32) {
+        let limit = self.pwm_limit();
+        let pwm = pwm.max(-limit).min(limit);
+        let signed = if self.direction_sign() < 0.0 {
+            -pwm
+        } else {
+            pwm
+        };
+        let nominal = self.table.get(Item::NominalVoltage);
+        let result = if nominal > 0 && signed != 0 {
+            // PWM units relative to the nominal supply, so the motor sees the
+            // same voltage as the battery drains
+            let volts = signed as f32 / control_table::PWM_MAX as f32 * nominal as f32 * 0.1;
+            self.motor.set_voltage(volts, self.supply.volts())
+        } else {
+            // PwmLimit keeps this within full scale
+            self.motor
+                .set_duty_fixed(signed * DUTY_ONE / control_table::PWM_MAX)
+        };
+        if let Err(e) = result {
+            warn!("duty {} not applied: {}", signed, e as u8);
+            // e.g. no supply reading yet, stop rather than keep the last duty
+            let _ = self.motor.set_duty_fixed(0);
+        }
+        // from what the bridge actually got, rounded back to PWM units
+        let duty = self.motor.duty_fixed();
+        let applied = (duty * control_table::PWM_MAX + duty.signum() * DUTY_ONE / 2) / DUTY_ONE;
+        let applied = if self.direction_sign() < 0.0 {
+            -applied
+        } else {
+            applied
+        };
+        self.table.set(Item::PresentPwm, applied);
+    }
+
+    /// A fresh setpoint, pushes the command timeout back. Ignored once it has expired.
+    fn refresh_command(&mut self) {
+        if self.table.torque_enabled() && !self.failsafe {
+            self.last_command_us = self.last_time_us;
+        }
+    }
+
+    /// Side effects of an item written from the bus.
+    fn apply(&mut self, item: Item) {
+        match item {
+            Item::TorqueEnable => {
+                if self.table.torque_enabled() && self.torque_refused() {
+                    self.table.set(Item::TorqueEnable, 0);
+                } else if self.table.torque_enabled() {
+                    // a tripped bridge only re-arms once the break input is released
+                    if let Err(fault) = self.motor.clear_fault() {
+                        self.table.set(Item::TorqueEnable, 0);
+                        self.driver_fault(fault);
+                        return;
+                    }
+                    // explicit re-enable after a command timeout, following error or break
+                    self.failsafe = false;
+                    let status = self.table.get(Item::HardwareErrorStatus) as u8
+                        & !(hardware_error::COMMAND_TIMEOUT
+                            | hardware_error::FOLLOWING_ERROR
+                            | hardware_error::ELECTRICAL_SHOCK
+                            | hardware_error::OVERLOAD);
+                    self.table.set(Item::HardwareErrorStatus, status as i32);
+                    self.last_command_us = self.last_time_us;
+                    // the bridge only arms from zero duty
+                    self.drive_pwm(0);
+                    self.motor.enable();
+                    if !self.motor.is_enabled() {
+                        self.table.set(Item::TorqueEnable, 0);
+                        return;
+                    }
+                    self.reset_loops();
+                    self.apply(Item::GoalPwm);
+                } else {
+                    self.failsafe = false;
+                    self.drive_pwm(0);
+                    self.motor.disable();
+                }
+            }
+            Item::OperatingMode if self.table.torque_enabled() => {
+                if self.operating_mode() == operating_mode::PWM {
+                    // hold the duty the closed loop left off with
+                    self.table
+                        .set(Item::GoalPwm, self.table.get(Item::PresentPwm));
+                }
+                self.reset_loops();
+            }
+            Item::GoalPwm => {
+                self.refresh_command();
+                if self.table.torque_enabled()
+                    && !self.failsafe
+                    && !self.following_error()
+                    && self.operating_mode() == operating_mode::PWM
+                {
+                    self.drive_pwm(self.table.get(Item::GoalPwm));
+                }
+            }
+            Item::GoalCurrent | Item::GoalVelocity | Item::GoalPosition => {
+                self.refresh_command();
+            }
+            Item::Led => {
+                if self.table.get(Item::Led) != 0 {
+                    self.led1.on();
+                } else {
+                    self.led1.off();
+                }
+            }
+            Item::BaudRate => {
+                self.pending_baud_rate = Some(self.baud_rate());
+            }
+            Item::PwmFrequency => {
+                let config = PwmConfig {
+                    frequency_hz: self.table.get(Item::PwmFrequency) as u32,
+                    ..PwmConfig::default()
+                };
+                if let Err(e) = self.motor.set_pwm_config(config) {
+                    warn!("pwm frequency {}: error {}", config.frequency_hz, e as u8);
+                }
+            }
+            Item::MinVoltageLimit | Item::MaxVoltageLimit => {
+                // a window narrower than twice the hysteresis never lets a fault clear
+                let window = (self.table.get(Item::MaxVoltageLimit)
+                    - self.table.get(Item::MinVoltageLimit)) as f32
+                    * 0.1;
+                let config = SupplyConfig::default();
+                self.set_supply_config(SupplyConfig {
+                    hysteresis_volts: pid::clamp(window * 0.25, 0.0, config.hysteresis_volts),
+                    ..config
+                });
+            }
+            Item::DecayMode => {
+                let mode = match self.table.get(Item::DecayMode) as u8 {
+                    decay_mode::SLOW => DecayMode::Slow,
+                    decay_mode::LOCKED_ANTIPHASE => DecayMode::LockedAntiphase,
+                    _ => DecayMode::Fast,
+                };
+                self.motor.set_decay_mode(mode);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<T0, T1, M, E> dynamixel::Device for App<T0, T1, M, E>
+where
+    T0: Indicator,
+    T1: Indicator,
+    M: DcMotorDriver,
+    E: Encoder,
+{
+    fn id(&self) -> u8 {
+        self.table.get(Item::Id) as u8
+    }
+    fn model_number(&self) -> u16 {
+        control_table::MODEL_NUMBER
+    }
+    fn firmware_version(&self) -> u8 {
+        control_table::FIRMWARE_VERSION
+    }
+    fn error_status(&self) -> u8 {
+        if self.table.get(Item::HardwareErrorStatus) != 0 || self.init_failed {
+            dynamixel::ALERT_BIT
+        } else {
+            0
+        }
+    }
+    fn status_return_level(&self) -> u8 {
+        self.table.get(Item::StatusReturnLevel) as u8
+    }
+    fn read(&mut self, address: u16, data: &mut [u8]) -> Result<(), ErrorCode> {
+        self.table.read(address, data)
+    }
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), ErrorCode> {
+        self.table.check_write(address, data)?;
+        for e in control_table::entries_in(address, data.len()) {
+            if e.item == Item::OperatingMode {
+                let mode = data[(e.address - address) as usize];
+                let supported = [
+                    operating_mode::CURRENT,
+                    operating_mode::PWM,
+                    operating_mode::VELOCITY,
+                    operating_mode::POSITION,
+                ];
+                if !supported.contains(&mode) {
+                    return Err(ErrorCode::DataRange);
+                }
+            }
+        }
+        let enable = control_table::entries_in(address, data.len())
+            .any(|e| e.item == Item::TorqueEnable && data[(e.address - address) as usize] != 0);
+        if enable && self.torque_refused() {
+            return Err(ErrorCode::Access);
+        }
+        self.table.write(address, data)?;
+        for e in control_table::entries_in(address, data.len()) {
+            self.config_dirty |= control_table::is_persistent(e);
+            self.apply(e.item);
+        }
+        // the bridge did not arm, e.g. the break input is still asserted
+        if enable && !self.table.torque_enabled() {
+            return Err(ErrorCode::Access);
+        }
+        Ok(())
+    }
+    fn factory_reset(&mut self, option: u8) -> Result<(), ErrorCode> {
+        let (keep_id, keep_baud_rate) = match option {
+            0xFF => (false, false),
+            0x01 => (true, false),
+            0x02 => (true, true),
+            _ => return Err(ErrorCode::DataRange),
+        };
+        let baud_rate = self.baud_rate();
+        self.drive_pwm(0);
+        self.motor.disable();
+        self.table.reset(keep_id, keep_baud_rate);
+        if self.baud_rate() != baud_rate {
+            self.pending_baud_rate = Some(self.baud_rate());
+        }
+        self.apply(Item::Led);
+        self.apply(Item::PwmFrequency);
+        self.apply(Item::DecayMode);
+        self.apply(Item::MinVoltageLimit);
+        self.config_dirty = true;
+        Ok(())
+    }
+    fn set_registered(&mut self, registered: bool) {
+        self.table
+            .set(Item::RegisteredInstruction, registered as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dc_motor_driver::{ClampPolicy, Duty, DutyError, PwmConfigError};
+    use crate::dynamixel::Device;
+    use crate::velocity::Sample;
+    use core::cell::Cell;
+
+    /// 12V, 10V and 5V with the default divider.
+    const SUPPLY_12V: u16 = 1354;
+    const SUPPLY_10V: u16 = 1128;
+    const SUPPLY_5V: u16 = 564;
+    /// Shunt amplifier output at zero current.
+    const ZERO_CURRENT: u16 = 2048;
+
+    struct Led;
+
+    impl Indicator for Led {
+        fn on(&self) {}
+        fn off(&self) {}
+        fn toggle(&self) {}
+    }
+
+    #[derive(Default)]
+    struct Bridge {
+        enabled: Cell<bool>,
+        duty: Cell<i32>,
+        /// Break input held asserted.
+        fault: Cell<Option<Fault>>,
+    }
+
+    impl DcMotorDriver for Bridge {
+        fn enable(&self) {
+            self.enabled.set(true);
+        }
+        fn disable(&self) {
+            self.enabled.set(false);
+        }
+        fn is_enabled(&self) -> bool {
+            self.enabled.get()
+        }
+        fn fault(&self) -> Option<Fault> {
+            self.fault.get()
+        }
+        fn clear_fault(&self) -> Result<(), Fault> {
+            match self.fault.get() {
+                Some(fault) => Err(fault),
+                None => Ok(()),
+            }
+        }
+        fn set_decay_mode(&self, _mode: DecayMode) {}
+        fn brake(&self) {
+            self.duty.set(0);
+        }
+        fn coast(&self) {
+            self.duty.set(0);
+        }
+        fn set_pwm_config(&self, _config: PwmConfig) -> Result<(), PwmConfigError> {
+            Ok(())
+        }
+        fn set_clamp_policy(&self, _policy: ClampPolicy) {}
+        fn set_duty_fixed(&self, duty: i32) -> Result<Duty, DutyError> {
+            if duty.abs() > DUTY_ONE {
+                return Err(DutyError::OutOfRange);
+            }
+            self.duty.set(duty);
+            Ok(Duty::Exact)
+        }
+        fn duty_fixed(&self) -> i32 {
+            self.duty.get()
+        }
+    }
+
+    /// A motor that does not turn, sampled every millisecond.
+    #[derive(Default)]
+    struct Standstill {
+        time_us: u32,
+    }
+
+    impl Encoder for Standstill {
+        fn position(&mut self) -> i64 {
+            0
+        }
+        fn delta(&mut self) -> i32 {
+            0
+        }
+        fn direction(&self) -> crate::encoder::Direction {
+            crate::encoder::Direction::Stopped
+        }
+        fn preset(&mut self, _position: i64) {}
+        fn sample(&mut self) -> Sample {
+            self.time_us = self.time_us.wrapping_add(1000);
+            Sample {
+                time_us: self.time_us,
+                position: 0,
+                edge: None,
+            }
+        }
+    }
+
+    type TestApp = App<Led, Led, Bridge, Standstill>;
+
+    fn write(app: &mut TestApp, item: Item, value: i32) -> Result<(), ErrorCode> {
+        let e = control_table::entry(item);
+        app.write(e.address, &value.to_le_bytes()[..e.size as usize])
+    }
+
+    /// Current offset calibrated, supply at 12V and the first control tick done.
+    fn app() -> TestApp {
+        let mut app = App::new(Led, Led, Bridge::default(), Standstill::default());
+        while !app.current_sense.is_calibrated() {
+            app.current_task(ZERO_CURRENT, 1e-4);
+        }
+        app.supply_task(SUPPLY_12V);
+        app.control_task();
+        app
+    }
+
+    /// Internal temperature sensor reading at `celsius`.
+    fn mcu_raw(celsius: f32) -> u16 {
+        let v30 = TemperatureConfig::default().ts_cal1 as f32 * 3.0 / 4095.0;
+        ((v30 + (celsius - 30.0) * 0.0025) * 4095.0 / 3.3 + 0.5) as u16
+    }
+
+    #[test]
+    fn running_pwm_is_derated() {
+        let mut app = app();
+        app.set_temperature_config(TemperatureConfig {
+            filter_alpha: 1.0,
+            ..TemperatureConfig::default()
+        });
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        app.control_task();
+        assert_eq!(app.table.get(Item::PresentPwm), 800);
+
+        // halfway through the 65..80°C band, GoalPwm not written again
+        app.temperature_task(mcu_raw(72.5), 0);
+        app.control_task();
+        let pwm = app.table.get(Item::PresentPwm);
+        assert!(pwm > 400 && pwm < 480, "{}", pwm);
+        assert!(app.motor.duty_fixed() < DUTY_ONE * 480 / control_table::PWM_MAX);
+    }
+
+    #[test]
+    fn over_temperature_shutdown_is_latched() {
+        let mut app = app();
+        app.set_temperature_config(TemperatureConfig {
+            filter_alpha: 1.0,
+            ..TemperatureConfig::default()
+        });
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 400).unwrap();
+        app.temperature_task(mcu_raw(81.0), 0);
+        assert!(!app.table.torque_enabled());
+        assert!(!app.motor.is_enabled());
+        let status = app.table.get(Item::HardwareErrorStatus) as u8;
+        assert_eq!(status, hardware_error::OVERHEATING);
+
+        // still within the hysteresis below the 80°C limit
+        app.temperature_task(mcu_raw(75.0), 0);
+        assert_eq!(
+            write(&mut app, Item::TorqueEnable, 1),
+            Err(ErrorCode::Access)
+        );
+        assert!(!app.table.torque_enabled());
+
+        // cooled down: torque can be enabled again, the error stays latched
+        app.temperature_task(mcu_raw(60.0), 0);
+        app.control_task();
+        assert!(!app.table.torque_enabled());
+        assert_eq!(write(&mut app, Item::TorqueEnable, 1), Ok(()));
+        let status = app.table.get(Item::HardwareErrorStatus) as u8;
+        assert_eq!(status, hardware_error::OVERHEATING);
+    }
+
+    #[test]
+    fn held_pwm_is_reduced_once_the_i2t_budget_is_spent() {
+        let mut app = app();
+        app.i2t.set_config(I2tConfig {
+            time_constant_s: 1.0,
+            ..I2tConfig::default()
+        });
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        // 1.5A whatever the duty, under the 2A peak but over the 1A rating
+        let amps_per_count = CurrentSenseConfig::default().amps_per_count;
+        let raw = ZERO_CURRENT + (1.5 / amps_per_count) as u16;
+        let run = |app: &mut TestApp, ms: u32| {
+            for _ in 0..ms {
+                for _ in 0..4 {
+                    app.current_task(raw, 2.5e-4);
+                }
+                app.control_task();
+            }
+        };
+        run(&mut app, 100);
+        assert_eq!(app.table.get(Item::PresentPwm), 800);
+
+        run(&mut app, 3000);
+        assert!(app.i2t.current_limit() < 1.5);
+        let pwm = app.table.get(Item::PresentPwm);
+        assert!(pwm < 400, "{}", pwm);
+        assert_eq!(app.table.get(Item::GoalPwm), 800);
+    }
+
+    #[test]
+    fn running_pwm_follows_the_supply() {
+        let mut app = app();
+        write(&mut app, Item::NominalVoltage, 120).unwrap();
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 400).unwrap();
+        app.control_task();
+        let duty = app.motor.duty_fixed() as f32;
+
+        // the battery sags to 10V, GoalPwm not written again
+        for _ in 0..500 {
+            app.supply_task(SUPPLY_10V);
+        }
+        app.control_task();
+        let expected = duty * 12.0 / 10.0;
+        let compensated = app.motor.duty_fixed() as f32;
+        assert!(
+            (compensated - expected).abs() < expected * 0.01,
+            "{} instead of {}",
+            compensated,
+            expected
+        );
+    }
+
+    /// `ms` control ticks.
+    fn run(app: &mut TestApp, ms: u32) {
+        for _ in 0..ms {
+            app.control_task();
+        }
+    }
+
+    #[test]
+    fn command_timeout_ramps_the_duty_down() {
+        let mut app = app();
+        write(&mut app, Item::CommandTimeout, 100).unwrap();
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        run(&mut app, 100);
+        assert_eq!(app.table.get(Item::PresentPwm), 800);
+        assert_eq!(app.table.get(Item::HardwareErrorStatus), 0);
+
+        // expired, a late setpoint does not bring the duty back
+        run(&mut app, 100);
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        let pwm = app.table.get(Item::PresentPwm);
+        assert!(pwm > 500 && pwm < 700, "{}", pwm);
+        assert!(app.table.torque_enabled());
+        let status = app.table.get(Item::HardwareErrorStatus) as u8;
+        assert_eq!(status, hardware_error::COMMAND_TIMEOUT);
+
+        // full duty to zero within 0.5s, then torque off
+        run(&mut app, 400);
+        assert_eq!(app.table.get(Item::PresentPwm), 0);
+        assert!(!app.table.torque_enabled());
+        assert!(!app.motor.is_enabled());
+
+        // enabling again clears the timeout
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        assert_eq!(app.table.get(Item::HardwareErrorStatus), 0);
+        assert!(app.motor.is_enabled());
+    }
+
+    #[test]
+    fn command_timeout_with_brake_holds_the_bridge() {
+        let mut app = app();
+        write(&mut app, Item::CommandTimeout, 100).unwrap();
+        write(&mut app, Item::TimeoutAction, timeout_action::BRAKE as i32).unwrap();
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        run(&mut app, 101);
+
+        // torque reads off at once, the bridge stays armed and braking
+        assert!(!app.table.torque_enabled());
+        assert!(app.motor.is_enabled());
+        assert_eq!(app.motor.duty_fixed(), 0);
+        assert_eq!(app.table.get(Item::PresentPwm), 0);
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        run(&mut app, 10);
+        assert_eq!(app.motor.duty_fixed(), 0);
+
+        // released by turning torque off
+        write(&mut app, Item::TorqueEnable, 0).unwrap();
+        assert!(!app.motor.is_enabled());
+    }
+
+    #[test]
+    fn torque_enable_on_a_supply_fault_is_refused() {
+        let mut app = app();
+        for _ in 0..200 {
+            app.supply_task(SUPPLY_5V);
+        }
+        assert_eq!(
+            write(&mut app, Item::TorqueEnable, 1),
+            Err(ErrorCode::Access)
+        );
+        assert!(!app.table.torque_enabled());
+        assert!(!app.motor.is_enabled());
+        assert_eq!(app.error_status(), dynamixel::ALERT_BIT);
+    }
+
+    #[test]
+    fn supply_fault_while_running_cuts_torque() {
+        let mut app = app();
+        write(&mut app, Item::TorqueEnable, 1).unwrap();
+        write(&mut app, Item::GoalPwm, 800).unwrap();
+        app.control_task();
+        for _ in 0..200 {
+            app.supply_task(SUPPLY_5V);
+        }
+        // at once, no ramp
+        assert!(!app.table.torque_enabled());
+        assert!(!app.motor.is_enabled());
+        assert_eq!(app.motor.duty_fixed(), 0);
+        let status = app.table.get(Item::HardwareErrorStatus) as u8;
+        assert_eq!(status, hardware_error::INPUT_VOLTAGE);
+
+        // back inside the limits, torque stays off until enabled again
+        for _ in 0..200 {
+            app.supply_task(SUPPLY_12V);
+        }
+        app.control_task();
+        assert_eq!(app.table.get(Item::HardwareErrorStatus), 0);
+        assert!(!app.table.torque_enabled());
+        assert_eq!(write(&mut app, Item::TorqueEnable, 1), Ok(()));
+    }
+
+    #[test]
+    fn torque_enable_before_current_calibration_is_refused() {
+        let mut app = App::new(Led, Led, Bridge::default(), Standstill::default());
+        app.supply_task(SUPPLY_12V);
+        app.control_task();
+        assert_eq!(
+            write(&mut app, Item::TorqueEnable, 1),
+            Err(ErrorCode::Access)
+        );
+        assert!(!app.motor.is_enabled());
+        while !app.current_sense.is_calibrated() {
+            app.current_task(ZERO_CURRENT, 1e-4);
+        }
+        assert_eq!(write(&mut app, Item::TorqueEnable, 1), Ok(()));
+    }
+
+    #[test]
+    fn torque_enable_with_the_break_input_asserted_is_refused() {
+        let mut app = app();
+        app.motor.fault.set(Some(Fault::Break));
+        assert_eq!(
+            write(&mut app, Item::TorqueEnable, 1),
+            Err(ErrorCode::Access)
+        );
+        assert!(!app.table.torque_enabled());
+        assert!(!app.motor.is_enabled());
+        let status = app.table.get(Item::HardwareErrorStatus) as u8;
+        assert_eq!(status, hardware_error::ELECTRICAL_SHOCK);
+
+        // released, enabling clears the trip
+        app.motor.fault.set(None);
+        assert_eq!(write(&mut app, Item::TorqueEnable, 1), Ok(()));
+        assert_eq!(app.table.get(Item::HardwareErrorStatus), 0);
+    }
+
+    #[test]
+    fn torque_enable_after_a_failed_init_is_refused() {
+        let mut app = app();
+        app.report_init_failure(1);
+        assert_eq!(
+            write(&mut app, Item::TorqueEnable, 1),
+            Err(ErrorCode::Access)
+        );
+        assert_eq!(app.error_status(), dynamixel::ALERT_BIT);
+        // turning it off is always fine
+        assert_eq!(write(&mut app, Item::TorqueEnable, 0), Ok(()));
     }
 }
@@ -0,0 +1,507 @@
+//! Parameter block kept in the last two flash pages.
+//!
+//! Each page starts with a header holding a sequence number; the page with
+//! the newest valid header is active. Records are appended to the active page
+//! until it is full, then the other page is erased, the record written there
+//! and only then its header, so a power failure at any point leaves at least
+//! one complete record behind. A record is
+//!
+//! | tag u16 | version u16 | len u16 | 0xFFFF | payload, padded to 8 | crc u32 | !crc u32 |
+//!
+//! and the last one with a good CRC wins.
+
+const PAGE_MAGIC: u32 = 0x3147_4643; // "CFG1"
+const RECORD_TAG: u16 = 0xC0F6;
+const HEADER_LEN: usize = 8;
+const RECORD_OVERHEAD: usize = 16;
+const PAGES: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    /// Write protection or a bad address.
+    Protected,
+    /// Programming or erase reported an error.
+    Program,
+    /// The flash stayed busy.
+    Timeout,
+}
+
+/// Flash pages reserved for the store. Erased bytes read as 0xFF.
+pub trait Flash {
+    const PAGE_SIZE: usize;
+    /// `offset` counts from the start of the first page.
+    fn read(&self, offset: usize, buf: &mut [u8]);
+    fn erase(&mut self, page: usize) -> Result<(), FlashError>;
+    /// `offset` and `data.len()` are multiples of 8, the target is erased.
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreError {
+    TooLarge,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for StoreError {
+    fn from(e: FlashError) -> Self {
+        StoreError::Flash(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub version: u16,
+    pub len: usize,
+    offset: usize,
+    crc: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Page {
+    index: usize,
+    sequence: u32,
+    latest: Option<Record>,
+    /// Where the next record goes, `None` after a torn write.
+    free: Option<usize>,
+}
+
+pub struct ConfigStore<F: Flash> {
+    flash: F,
+    active: Option<Page>,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    /// Payload bytes that fit in one record.
+    pub const MAX_LEN: usize = F::PAGE_SIZE - HEADER_LEN - RECORD_OVERHEAD;
+
+    pub fn new(flash: F) -> Self {
+        let mut store = Self {
+            flash,
+            active: None,
+        };
+        store.active = store.newest_page();
+        store
+    }
+
+    /// Copies the newest record into `buf`. `None` if there is none intact,
+    /// in which case the defaults apply.
+    pub fn load(&self, buf: &mut [u8]) -> Option<Record> {
+        let record = self.active?.latest?;
+        let len = record.len.min(buf.len());
+        self.flash.read(record.offset + HEADER_LEN, &mut buf[..len]);
+        Some(Record { len, ..record })
+    }
+
+    /// Appends a record, moving to the other page when this one is full.
+    /// Does not touch the flash if the newest record already holds the same data.
+    ///
+    /// Moving to the other page erases it, which stalls every fetch from flash
+    /// for the page erase time (up to 40ms on the STM32G0), interrupts included.
+    pub fn save(&mut self, version: u16, payload: &[u8]) -> Result<(), StoreError> {
+        if payload.len() > Self::MAX_LEN {
+            return Err(StoreError::TooLarge);
+        }
+        let header = record_header(version, payload.len());
+        let crc = record_crc(&header, payload);
+        if let Some(latest) = self.active.and_then(|p| p.latest) {
+            if latest.version == version && latest.len == payload.len() && latest.crc == crc {
+                return Ok(());
+            }
+        }
+        let size = record_size(payload.len());
+
+        let page = match self.active {
+            Some(page) if page.free.is_some_and(|free| free + size <= F::PAGE_SIZE) => page,
+            active => {
+                let (index, sequence) = match active {
+                    Some(page) => ((page.index + 1) % PAGES, page.sequence.wrapping_add(1)),
+                    None => (0, 0),
+                };
+                let base = index * F::PAGE_SIZE;
+                self.flash.erase(index)?;
+                self.write_record(base + HEADER_LEN, &header, payload, crc)?;
+                // the page only counts once its header is there
+                let mut page_header = [0u8; HEADER_LEN];
+                page_header[..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+                page_header[4..].copy_from_slice(&sequence.to_le_bytes());
+                self.flash.program(base, &page_header)?;
+                self.active = Some(Page {
+                    index,
+                    sequence,
+                    latest: Some(Record {
+                        version,
+                        len: payload.len(),
+                        offset: base + HEADER_LEN,
+                        crc,
+                    }),
+                    free: Some(HEADER_LEN + size),
+                });
+                return Ok(());
+            }
+        };
+
+        let free = page.free.unwrap_or(F::PAGE_SIZE);
+        let offset = page.index * F::PAGE_SIZE + free;
+        self.write_record(offset, &header, payload, crc)?;
+        self.active = Some(Page {
+            latest: Some(Record {
+                version,
+                len: payload.len(),
+                offset,
+                crc,
+            }),
+            free: Some(free + size),
+            ..page
+        });
+        Ok(())
+    }
+
+    fn write_record(
+        &mut self,
+        offset: usize,
+        header: &[u8; 8],
+        payload: &[u8],
+        crc: u32,
+    ) -> Result<(), FlashError> {
+        self.flash.program(offset, header)?;
+        let mut at = offset + HEADER_LEN;
+        for chunk in payload.chunks(8) {
+            let mut word = [0xFFu8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.flash.program(at, &word)?;
+            at += 8;
+        }
+        let mut trailer = [0u8; 8];
+        trailer[..4].copy_from_slice(&crc.to_le_bytes());
+        trailer[4..].copy_from_slice(&(!crc).to_le_bytes());
+        self.flash.program(at, &trailer)
+    }
+
+    /// Newest page with a valid header and an intact record.
+    fn newest_page(&self) -> Option<Page> {
+        let mut newest: Option<Page> = None;
+        for index in 0..PAGES {
+            let page = match self.scan(index) {
+                Some(page) if page.latest.is_some() => page,
+                _ => continue,
+            };
+            newest = match newest {
+                Some(n) if (page.sequence.wrapping_sub(n.sequence) as i32) <= 0 => Some(n),
+                _ => Some(page),
+            };
+        }
+        newest
+    }
+
+    fn scan(&self, index: usize) -> Option<Page> {
+        let base = index * F::PAGE_SIZE;
+        let mut header = [0u8; HEADER_LEN];
+        self.flash.read(base, &mut header);
+        if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != PAGE_MAGIC {
+            return None;
+        }
+        let mut page = Page {
+            index,
+            sequence: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+            latest: None,
+            free: None,
+        };
+
+        let mut offset = HEADER_LEN;
+        while offset + RECORD_OVERHEAD <= F::PAGE_SIZE {
+            let mut header = [0u8; 8];
+            self.flash.read(base + offset, &mut header);
+            if header == [0xFF; 8] {
+                page.free = Some(offset);
+                break;
+            }
+            let tag = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+            if tag != RECORD_TAG || offset + record_size(len) > F::PAGE_SIZE {
+                break;
+            }
+            let crc = match self.check(base + offset, &header, len) {
+                Some(crc) => crc,
+                None => break,
+            };
+            page.latest = Some(Record {
+                version: u16::from_le_bytes([header[2], header[3]]),
+                len,
+                offset: base + offset,
+                crc,
+            });
+            offset += record_size(len);
+        }
+        Some(page)
+    }
+
+    /// CRC of an intact record.
+    fn check(&self, offset: usize, header: &[u8; 8], len: usize) -> Option<u32> {
+        let mut crc = crc32_update(0xFFFF_FFFF, header);
+        let mut word = [0u8; 8];
+        let mut at = offset + HEADER_LEN;
+        let mut left = len;
+        while left > 0 {
+            self.flash.read(at, &mut word);
+            let n = left.min(8);
+            crc = crc32_update(crc, &word[..n]);
+            left -= n;
+            at += 8;
+        }
+        let crc = !crc;
+        self.flash.read(at, &mut word);
+        let stored = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        let inverted = u32::from_le_bytes([word[4], word[5], word[6], word[7]]);
+        if stored == crc && inverted == !crc {
+            Some(crc)
+        } else {
+            None
+        }
+    }
+}
+
+fn record_header(version: u16, len: usize) -> [u8; 8] {
+    let mut header = [0xFFu8; 8];
+    header[..2].copy_from_slice(&RECORD_TAG.to_le_bytes());
+    header[2..4].copy_from_slice(&version.to_le_bytes());
+    header[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    header
+}
+
+fn record_size(len: usize) -> usize {
+    RECORD_OVERHEAD + len.div_ceil(8) * 8
+}
+
+fn record_crc(header: &[u8; 8], payload: &[u8]) -> u32 {
+    !crc32_update(crc32_update(0xFFFF_FFFF, header), payload)
+}
+
+/// CRC-32 (IEEE), bitwise to stay small.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = 128;
+
+    /// Two pages of RAM behaving like flash: programming needs erased bytes.
+    /// `budget` counts the erase/program calls left before the power fails.
+    #[derive(Clone)]
+    struct RamFlash {
+        mem: [u8; PAGE * PAGES],
+        budget: Option<usize>,
+        ops: usize,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                mem: [0xFF; PAGE * PAGES],
+                budget: None,
+                ops: 0,
+            }
+        }
+
+        fn spend(&mut self) -> Result<(), FlashError> {
+            self.ops += 1;
+            match self.budget {
+                Some(0) => Err(FlashError::Program),
+                Some(n) => {
+                    self.budget = Some(n - 1);
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        const PAGE_SIZE: usize = PAGE;
+        fn read(&self, offset: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+        }
+        fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+            self.spend()?;
+            self.mem[page * PAGE..(page + 1) * PAGE].fill(0xFF);
+            Ok(())
+        }
+        fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+            assert!(offset.is_multiple_of(8) && data.len().is_multiple_of(8));
+            assert!(
+                self.mem[offset..offset + data.len()]
+                    .iter()
+                    .all(|&b| b == 0xFF),
+                "programming {} without an erase",
+                offset
+            );
+            self.spend()?;
+            self.mem[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn payload(n: u8) -> [u8; 20] {
+        let mut p = [0u8; 20];
+        for (i, b) in p.iter_mut().enumerate() {
+            *b = n.wrapping_mul(31).wrapping_add(i as u8);
+        }
+        p
+    }
+
+    /// Version and payload of what a fresh boot finds.
+    fn reboot(flash: &RamFlash) -> Option<(u16, [u8; 20])> {
+        let mut flash = flash.clone();
+        flash.budget = None;
+        let store = ConfigStore::new(flash);
+        let mut buf = [0u8; 20];
+        let record = store.load(&mut buf)?;
+        assert_eq!(record.len, 20);
+        Some((record.version, buf))
+    }
+
+    #[test]
+    fn empty_flash_has_no_record() {
+        assert_eq!(reboot(&RamFlash::new()), None);
+    }
+
+    #[test]
+    fn saved_record_survives_a_reboot() {
+        let mut store = ConfigStore::new(RamFlash::new());
+        store.save(3, &payload(1)).unwrap();
+        assert_eq!(reboot(&store.flash), Some((3, payload(1))));
+        store.save(3, &payload(2)).unwrap();
+        assert_eq!(reboot(&store.flash), Some((3, payload(2))));
+    }
+
+    #[test]
+    fn same_data_is_not_written_again() {
+        let mut store = ConfigStore::new(RamFlash::new());
+        store.save(1, &payload(1)).unwrap();
+        let ops = store.flash.ops;
+        store.save(1, &payload(1)).unwrap();
+        assert_eq!(store.flash.ops, ops);
+        store.save(2, &payload(1)).unwrap();
+        assert!(store.flash.ops > ops);
+    }
+
+    #[test]
+    fn too_large_is_refused() {
+        let mut store = ConfigStore::new(RamFlash::new());
+        let big = [0u8; PAGE];
+        assert_eq!(store.save(1, &big), Err(StoreError::TooLarge));
+        assert!(store
+            .save(1, &big[..ConfigStore::<RamFlash>::MAX_LEN])
+            .is_ok());
+    }
+
+    #[test]
+    fn pages_rotate_when_full() {
+        // three 40 byte records per page
+        let mut store = ConfigStore::new(RamFlash::new());
+        for n in 0..20u8 {
+            store.save(1, &payload(n)).unwrap();
+            assert_eq!(reboot(&store.flash), Some((1, payload(n))));
+            let page = store.active.unwrap().index;
+            assert_eq!(page, (n as usize / 3) % PAGES, "record {}", n);
+        }
+    }
+
+    #[test]
+    fn power_cut_keeps_a_complete_record() {
+        // the third save fills page 0, the fourth erases page 1, writes the
+        // record and then the page header
+        for records in 1..=7u8 {
+            let mut store = ConfigStore::new(RamFlash::new());
+            for n in 0..records - 1 {
+                store.save(1, &payload(n)).unwrap();
+            }
+            let before = reboot(&store.flash);
+            let mut probe = ConfigStore::new(store.flash.clone());
+            probe.flash.ops = 0;
+            probe.save(1, &payload(records - 1)).unwrap();
+            let total = probe.flash.ops;
+
+            for budget in 0..total {
+                let mut flash = store.flash.clone();
+                flash.budget = Some(budget);
+                let mut torn = ConfigStore::new(flash);
+                assert!(torn.save(1, &payload(records - 1)).is_err());
+                let after = reboot(&torn.flash);
+                assert!(
+                    after == before || after == Some((1, payload(records - 1))),
+                    "record {} cut after {} of {} writes",
+                    records,
+                    budget,
+                    total
+                );
+                // the next save after the reboot goes through
+                let mut flash = torn.flash.clone();
+                flash.budget = None;
+                let mut next = ConfigStore::new(flash);
+                next.save(2, &payload(99)).unwrap();
+                assert_eq!(reboot(&next.flash), Some((2, payload(99))));
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_record_falls_back_to_the_previous_one() {
+        let mut store = ConfigStore::new(RamFlash::new());
+        store.save(1, &payload(1)).unwrap();
+        store.save(1, &payload(2)).unwrap();
+        let latest = store.active.unwrap().latest.unwrap();
+        let mut flash = store.flash.clone();
+        flash.mem[latest.offset + HEADER_LEN + 3] ^= 0x10;
+        assert_eq!(reboot(&flash), Some((1, payload(1))));
+        // or its CRC
+        let mut flash = store.flash.clone();
+        flash.mem[latest.offset + HEADER_LEN + 24] ^= 0x01;
+        assert_eq!(reboot(&flash), Some((1, payload(1))));
+    }
+
+    #[test]
+    fn corrupt_page_header_falls_back_to_the_other_page() {
+        let mut store = ConfigStore::new(RamFlash::new());
+        for n in 0..4u8 {
+            store.save(1, &payload(n)).unwrap();
+        }
+        assert_eq!(store.active.unwrap().index, 1);
+        let mut flash = store.flash.clone();
+        flash.mem[PAGE] ^= 0x01;
+        assert_eq!(reboot(&flash), Some((1, payload(2))));
+    }
+
+    #[test]
+    fn sequence_number_wraps() {
+        let mut store = ConfigStore::new(RamFlash::new());
+        store.save(1, &payload(1)).unwrap();
+        // page 0 written at the end of the sequence range
+        let mut flash = store.flash.clone();
+        flash.mem[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut store = ConfigStore::new(flash);
+        for n in 2..5u8 {
+            store.save(1, &payload(n)).unwrap();
+        }
+        let page = store.active.unwrap();
+        assert_eq!((page.index, page.sequence), (1, 0));
+        // sequence 0 is newer than u32::MAX
+        assert_eq!(reboot(&store.flash), Some((1, payload(4))));
+        assert_eq!(
+            ConfigStore::new(store.flash.clone()).active.unwrap().index,
+            1
+        );
+    }
+}
@@ -0,0 +1,690 @@
+//! Control table shared by every bus protocol and `app::App`.
+//!
+//! The address map follows the Dynamixel X series so existing tools can be
+//! used as is; items of our own only take addresses the X series leaves
+//! unused. Each item carries its own access right and bounds; the
+//! raw bytes are kept in one array that the protocol reads and writes directly.
+
+use crate::dynamixel::ErrorCode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Area {
+    /// Only writable while torque is disabled.
+    Eeprom,
+    /// Kept with the EEPROM items but may change while torque is enabled.
+    EepromLive,
+    Ram,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    R,
+    RW,
+}
+
+/// A bound that depends on another item, checked on write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    None,
+    /// |value| <= item
+    Abs(Item),
+    /// min item <= value <= max item
+    Range(Item, Item),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Item {
+    ModelNumber,
+    FirmwareVersion,
+    Id,
+    BaudRate,
+    ReturnDelayTime,
+    DriveMode,
+    OperatingMode,
+    NominalVoltage,
+    DecayMode,
+    PwmFrequency,
+    HomingOffset,
+    MovingThreshold,
+    CommandTimeout,
+    TimeoutAction,
+    TemperatureLimit,
+    MaxVoltageLimit,
+    MinVoltageLimit,
+    PwmLimit,
+    CurrentLimit,
+    FollowingErrorLimit,
+    VelocityLimit,
+    MaxPositionLimit,
+    MinPositionLimit,
+    InPositionWindow,
+    Shutdown,
+    TorqueEnable,
+    Led,
+    StatusReturnLevel,
+    RegisteredInstruction,
+    HardwareErrorStatus,
+    LastCrash,
+    ResetCause,
+    InitError,
+    VelocityDGain,
+    VelocityIGain,
+    VelocityPGain,
+    PositionDGain,
+    PositionIGain,
+    PositionPGain,
+    CurrentPGain,
+    CurrentIGain,
+    CrashAddress,
+    GoalPwm,
+    GoalCurrent,
+    GoalVelocity,
+    ProfileAcceleration,
+    ProfileVelocity,
+    GoalPosition,
+    RealtimeTick,
+    Moving,
+    MovingStatus,
+    PresentPwm,
+    PresentCurrent,
+    PresentVelocity,
+    PresentPosition,
+    PresentInputVoltage,
+    PresentTemperature,
+    PresentWindingTemperature,
+}
+
+pub struct Entry {
+    pub item: Item,
+    pub address: u16,
+    pub size: u8,
+    pub area: Area,
+    pub access: Access,
+    pub min: i32,
+    pub max: i32,
+    pub limit: Limit,
+    pub default: i32,
+}
+
+impl Entry {
+    const fn end(&self) -> usize {
+        self.address as usize + self.size as usize
+    }
+}
+
+pub const MODEL_NUMBER: u16 = 0x4D44;
+pub const FIRMWARE_VERSION: u8 = 1;
+
+/// Layout of the parameter block written by `ControlTable::save`.
+/// 2: `PwmFrequency` moved off the X series' Secondary ID / Protocol Type.
+pub const CONFIG_VERSION: u16 = 2;
+
+/// Full scale of `GoalPwm` / `PresentPwm`.
+pub const PWM_MAX: i32 = 885;
+
+pub mod operating_mode {
+    pub const CURRENT: u8 = 0;
+    pub const VELOCITY: u8 = 1;
+    pub const POSITION: u8 = 3;
+    pub const PWM: u8 = 16;
+}
+
+/// Bits of `MovingStatus`.
+pub mod moving_status {
+    pub const IN_POSITION: u8 = 1 << 0;
+    pub const PROFILE_ONGOING: u8 = 1 << 1;
+}
+
+/// Bits of `HardwareErrorStatus`.
+pub mod hardware_error {
+    pub const INPUT_VOLTAGE: u8 = 1 << 0;
+    /// Position mode lagged the trajectory by more than `FollowingErrorLimit`.
+    /// The duty is held at zero until torque is enabled again.
+    pub const FOLLOWING_ERROR: u8 = 1 << 1;
+    pub const OVERHEATING: u8 = 1 << 2;
+    /// The encoder did not come up at startup.
+    pub const MOTOR_ENCODER: u8 = 1 << 3;
+    /// BKIN tripped the bridge. This and `OVERLOAD` (BKIN2) clear when torque
+    /// is enabled again with the input released.
+    pub const ELECTRICAL_SHOCK: u8 = 1 << 4;
+    pub const OVERLOAD: u8 = 1 << 5;
+    /// No goal written within `CommandTimeout`, cleared by enabling torque again.
+    pub const COMMAND_TIMEOUT: u8 = 1 << 6;
+    /// The external clock failed and the MCU fell back to HSI16. Latched until reset.
+    pub const CLOCK_FAILURE: u8 = 1 << 7;
+}
+
+/// `DecayMode` values, how the bridge spends the PWM off-time.
+pub mod decay_mode {
+    pub const FAST: u8 = 0;
+    pub const SLOW: u8 = 1;
+    pub const LOCKED_ANTIPHASE: u8 = 2;
+}
+
+/// `TimeoutAction` values.
+pub mod timeout_action {
+    /// Ramp the duty down, then turn torque off.
+    pub const RAMP: u8 = 0;
+    /// Short the motor and hold it until the host writes `TorqueEnable`.
+    pub const BRAKE: u8 = 1;
+}
+
+/// Baud rate for the `BaudRate` item value.
+pub fn baud_rate(value: u8) -> Option<u32> {
+    match value {
+        0 => Some(9_600),
+        1 => Some(57_600),
+        2 => Some(115_200),
+        3 => Some(1_000_000),
+        4 => Some(2_000_000),
+        5 => Some(3_000_000),
+        6 => Some(4_000_000),
+        _ => None,
+    }
+}
+
+macro_rules! entry {
+    ($item:ident, $address:expr, $size:expr, $area:ident, $access:ident, $min:expr, $max:expr, $limit:expr, $default:expr) => {
+        Entry {
+            item: Item::$item,
+            address: $address,
+            size: $size,
+            area: Area::$area,
+            access: Access::$access,
+            min: $min,
+            max: $max,
+            limit: $limit,
+            default: $default,
+        }
+    };
+}
+
+const POSITION_RANGE: i32 = 1_048_575;
+
+#[rustfmt::skip]
+pub static ENTRIES: [Entry; 58] = [
+    entry!(ModelNumber,           0,   2, Eeprom, R,  0, 0xFFFF, Limit::None, MODEL_NUMBER as i32),
+    entry!(FirmwareVersion,       6,   1, Eeprom, R,  0, 0xFF, Limit::None, FIRMWARE_VERSION as i32),
+    entry!(Id,                    7,   1, Eeprom, RW, 0, 252, Limit::None, 1),
+    entry!(BaudRate,              8,   1, Eeprom, RW, 0, 6, Limit::None, 1),
+    entry!(ReturnDelayTime,       9,   1, Eeprom, RW, 0, 254, Limit::None, 250),
+    entry!(DriveMode,             10,  1, Eeprom, RW, 0, 1, Limit::None, 0),
+    entry!(OperatingMode,         11,  1, EepromLive, RW, 0, 16, Limit::None, operating_mode::PWM as i32),
+    entry!(NominalVoltage,        14,  2, Eeprom, RW, 0, 300, Limit::None, 0),
+    entry!(DecayMode,             16,  1, Eeprom, RW, 0, 2, Limit::None, decay_mode::FAST as i32),
+    entry!(PwmFrequency,          18,  2, Eeprom, RW, 1000, 50000, Limit::None, 25000),
+    entry!(HomingOffset,          20,  4, Eeprom, RW, -POSITION_RANGE, POSITION_RANGE, Limit::None, 0),
+    entry!(MovingThreshold,       24,  4, Eeprom, RW, 0, 1023, Limit::None, 10),
+    entry!(CommandTimeout,        28,  2, Eeprom, RW, 0, 10000, Limit::None, 0),
+    entry!(TimeoutAction,         30,  1, Eeprom, RW, 0, 1, Limit::None, 0),
+    entry!(TemperatureLimit,      31,  1, Eeprom, RW, 0, 100, Limit::None, 80),
+    entry!(MaxVoltageLimit,       32,  2, Eeprom, RW, 0, 300, Limit::None, 160),
+    entry!(MinVoltageLimit,       34,  2, Eeprom, RW, 0, 300, Limit::None, 95),
+    entry!(PwmLimit,              36,  2, Eeprom, RW, 0, PWM_MAX, Limit::None, PWM_MAX),
+    entry!(CurrentLimit,          38,  2, Eeprom, RW, 0, 5000, Limit::None, 2000),
+    entry!(FollowingErrorLimit,   40,  4, Eeprom, RW, 0, POSITION_RANGE, Limit::None, 0),
+    entry!(VelocityLimit,         44,  4, Eeprom, RW, 0, 1_000_000, Limit::None, 100_000),
+    entry!(MaxPositionLimit,      48,  4, Eeprom, RW, -POSITION_RANGE, POSITION_RANGE, Limit::None, POSITION_RANGE),
+    entry!(MinPositionLimit,      52,  4, Eeprom, RW, -POSITION_RANGE, POSITION_RANGE, Limit::None, -POSITION_RANGE),
+    entry!(InPositionWindow,      56,  4, Eeprom, RW, 0, POSITION_RANGE, Limit::None, 10),
+    entry!(Shutdown,              63,  1, Eeprom, RW, 0, 0xFF, Limit::None, 0x36),
+    entry!(TorqueEnable,          64,  1, Ram,    RW, 0, 1, Limit::None, 0),
+    entry!(Led,                   65,  1, Ram,    RW, 0, 1, Limit::None, 0),
+    entry!(StatusReturnLevel,     68,  1, Ram,    RW, 0, 2, Limit::None, 2),
+    entry!(RegisteredInstruction, 69,  1, Ram,    R,  0, 1, Limit::None, 0),
+    entry!(HardwareErrorStatus,   70,  1, Ram,    R,  0, 0xFF, Limit::None, 0),
+    entry!(LastCrash,             71,  1, Ram,    R,  0, 2, Limit::None, 0),
+    entry!(ResetCause,            72,  1, Ram,    R,  0, 7, Limit::None, 0),
+    entry!(InitError,             73,  1, Ram,    R,  0, 7, Limit::None, 0),
+    entry!(VelocityDGain,         74,  2, Ram,    RW, 0, 16383, Limit::None, 0),
+    entry!(VelocityIGain,         76,  2, Ram,    RW, 0, 16383, Limit::None, 1920),
+    entry!(VelocityPGain,         78,  2, Ram,    RW, 0, 16383, Limit::None, 100),
+    entry!(PositionDGain,         80,  2, Ram,    RW, 0, 16383, Limit::None, 0),
+    entry!(PositionIGain,         82,  2, Ram,    RW, 0, 16383, Limit::None, 0),
+    entry!(PositionPGain,         84,  2, Ram,    RW, 0, 16383, Limit::None, 800),
+    entry!(CurrentPGain,          92,  2, Ram,    RW, 0, 16383, Limit::None, 266),
+    entry!(CurrentIGain,          94,  2, Ram,    RW, 0, 16383, Limit::None, 8000),
+    entry!(CrashAddress,          96,  4, Ram,    R,  i32::MIN, i32::MAX, Limit::None, 0),
+    entry!(GoalPwm,               100, 2, Ram,    RW, -PWM_MAX, PWM_MAX, Limit::Abs(Item::PwmLimit), 0),
+    entry!(GoalCurrent,           102, 2, Ram,    RW, -5000, 5000, Limit::Abs(Item::CurrentLimit), 0),
+    entry!(GoalVelocity,          104, 4, Ram,    RW, -1_000_000, 1_000_000, Limit::Abs(Item::VelocityLimit), 0),
+    entry!(ProfileAcceleration,   108, 4, Ram,    RW, 0, 10_000_000, Limit::None, 0),
+    entry!(ProfileVelocity,       112, 4, Ram,    RW, 0, 1_000_000, Limit::None, 0),
+    entry!(GoalPosition,          116, 4, Ram,    RW, -POSITION_RANGE, POSITION_RANGE, Limit::Range(Item::MinPositionLimit, Item::MaxPositionLimit), 0),
+    entry!(RealtimeTick,          120, 2, Ram,    R,  0, 32767, Limit::None, 0),
+    entry!(Moving,                122, 1, Ram,    R,  0, 1, Limit::None, 0),
+    entry!(MovingStatus,          123, 1, Ram,    R,  0, 0xFF, Limit::None, 0),
+    entry!(PresentPwm,            124, 2, Ram,    R,  -PWM_MAX, PWM_MAX, Limit::None, 0),
+    entry!(PresentCurrent,        126, 2, Ram,    R,  -5000, 5000, Limit::None, 0),
+    entry!(PresentVelocity,       128, 4, Ram,    R,  i32::MIN, i32::MAX, Limit::None, 0),
+    entry!(PresentPosition,       132, 4, Ram,    R,  i32::MIN, i32::MAX, Limit::None, 0),
+    entry!(PresentInputVoltage,   144, 2, Ram,    R,  0, 0xFFFF, Limit::None, 0),
+    entry!(PresentTemperature,    146, 1, Ram,    R,  0, 0xFF, Limit::None, 0),
+    // I²t estimate
+    entry!(PresentWindingTemperature, 147, 1, Ram, R, 0, 0xFF, Limit::None, 0),
+];
+
+pub const TABLE_SIZE: usize = 148;
+
+// `entry` indexes ENTRIES by Item, so they have to be declared in the same
+// order and every Item needs its entry
+const _: () = {
+    let mut i = 0;
+    while i < ENTRIES.len() {
+        assert!(
+            ENTRIES[i].item as usize == i,
+            "ENTRIES is not in Item order"
+        );
+        assert!(
+            ENTRIES[i].end() <= TABLE_SIZE,
+            "entry past the end of the table"
+        );
+        i += 1;
+    }
+    assert!(
+        Item::PresentWindingTemperature as usize + 1 == ENTRIES.len(),
+        "Item without an entry"
+    );
+};
+
+/// Kept across a power cycle: the EEPROM area and the gains.
+pub fn is_persistent(e: &Entry) -> bool {
+    let gain = matches!(
+        e.item,
+        Item::VelocityDGain
+            | Item::VelocityIGain
+            | Item::VelocityPGain
+            | Item::PositionDGain
+            | Item::PositionIGain
+            | Item::PositionPGain
+            | Item::CurrentPGain
+            | Item::CurrentIGain
+    );
+    e.access == Access::RW && (e.area != Area::Ram || gain)
+}
+
+/// Address of an item in a block saved by an older firmware, `None` if it
+/// no longer exists. Items are keyed by address, so added items simply keep
+/// their defaults; only a moved or rescaled item needs a case here.
+fn migrate(version: u16, address: u16) -> Option<u16> {
+    match (version, address) {
+        // PwmFrequency
+        (1, 12) => Some(18),
+        _ => Some(address),
+    }
+}
+
+pub fn entry(item: Item) -> &'static Entry {
+    // same order, checked at compile time above
+    &ENTRIES[item as usize]
+}
+
+/// Entries touched by `address..address + len`, in address order.
+pub fn entries_in(address: u16, len: usize) -> impl Iterator<Item = &'static Entry> {
+    let start = address as usize;
+    let end = start + len;
+    ENTRIES
+        .iter()
+        .filter(move |e| (e.address as usize) < end && e.end() > start)
+}
+
+pub struct ControlTable {
+    data: [u8; TABLE_SIZE],
+}
+
+impl Default for ControlTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlTable {
+    pub fn new() -> Self {
+        let mut table = Self {
+            data: [0; TABLE_SIZE],
+        };
+        for e in ENTRIES.iter() {
+            table.set(e.item, e.default);
+        }
+        table
+    }
+
+    /// Restores the writable items to their defaults, optionally keeping the
+    /// ID and baud rate. Read-only items are left alone, so latched hardware
+    /// errors and the crash and reset diagnostics survive a factory reset.
+    pub fn reset(&mut self, keep_id: bool, keep_baud_rate: bool) {
+        for e in ENTRIES.iter().filter(|e| e.access == Access::RW) {
+            match e.item {
+                Item::Id if keep_id => (),
+                Item::BaudRate if keep_baud_rate => (),
+                _ => self.set(e.item, e.default),
+            }
+        }
+    }
+
+    pub fn get(&self, item: Item) -> i32 {
+        let e = entry(item);
+        decode(e, &self.data[e.address as usize..e.end()])
+    }
+
+    /// Sets an item without access or range checks. Used for present values.
+    pub fn set(&mut self, item: Item, value: i32) {
+        let e = entry(item);
+        let bytes = value.to_le_bytes();
+        self.data[e.address as usize..e.end()].copy_from_slice(&bytes[..e.size as usize]);
+    }
+
+    /// Writes the persistent items to `out` as address u16, size u8 and the
+    /// raw bytes. Returns the length used.
+    pub fn save(&self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        for e in ENTRIES.iter().filter(|e| is_persistent(e)) {
+            let len = 3 + e.size as usize;
+            if n + len > out.len() {
+                break;
+            }
+            out[n..n + 2].copy_from_slice(&e.address.to_le_bytes());
+            out[n + 2] = e.size;
+            out[n + 3..n + len].copy_from_slice(&self.data[e.address as usize..e.end()]);
+            n += len;
+        }
+        n
+    }
+
+    /// Loads a block written by `save` of schema `version`. Unknown or out of
+    /// range items are skipped and keep their current value. A block from a
+    /// newer firmware is ignored as a whole.
+    pub fn restore(&mut self, version: u16, data: &[u8]) -> bool {
+        if version > CONFIG_VERSION {
+            return false;
+        }
+        let mut rest = data;
+        while rest.len() >= 3 {
+            let address = u16::from_le_bytes([rest[0], rest[1]]);
+            let size = rest[2] as usize;
+            if rest.len() < 3 + size {
+                break;
+            }
+            let bytes = &rest[3..3 + size];
+            rest = &rest[3 + size..];
+
+            let e = match migrate(version, address)
+                .and_then(|address| ENTRIES.iter().find(|e| e.address == address))
+            {
+                Some(e) if e.size as usize == size && is_persistent(e) => e,
+                _ => continue,
+            };
+            let value = decode(e, bytes);
+            if value >= e.min && value <= e.max {
+                self.set(e.item, value);
+            }
+        }
+        true
+    }
+
+    pub fn torque_enabled(&self) -> bool {
+        self.get(Item::TorqueEnable) != 0
+    }
+
+    /// Raw read for the bus. Unused addresses read as 0.
+    pub fn read(&self, address: u16, data: &mut [u8]) -> Result<(), ErrorCode> {
+        let start = address as usize;
+        if start + data.len() > TABLE_SIZE {
+            return Err(ErrorCode::Access);
+        }
+        data.copy_from_slice(&self.data[start..start + data.len()]);
+        Ok(())
+    }
+
+    /// Checks a bus write without applying it.
+    ///
+    /// Every touched item must be covered completely, be writable in the
+    /// current torque state and stay inside its bounds.
+    pub fn check_write(&self, address: u16, data: &[u8]) -> Result<(), ErrorCode> {
+        let start = address as usize;
+        if data.is_empty() || start + data.len() > TABLE_SIZE {
+            return Err(ErrorCode::Access);
+        }
+        let mut touched = false;
+        for e in entries_in(address, data.len()) {
+            touched = true;
+            if (e.address as usize) < start || e.end() > start + data.len() {
+                return Err(ErrorCode::DataLength);
+            }
+            if e.access != Access::RW {
+                return Err(ErrorCode::Access);
+            }
+            if e.area == Area::Eeprom && self.torque_enabled() {
+                return Err(ErrorCode::Access);
+            }
+            let offset = e.address as usize - start;
+            let value = decode(e, &data[offset..offset + e.size as usize]);
+            if value < e.min || value > e.max {
+                return Err(ErrorCode::DataRange);
+            }
+            let within_limit = match e.limit {
+                Limit::None => true,
+                Limit::Abs(limit) => value.abs() <= self.get(limit),
+                Limit::Range(min, max) => self.get(min) <= value && value <= self.get(max),
+            };
+            if !within_limit {
+                return Err(ErrorCode::DataLimit);
+            }
+        }
+        if touched {
+            Ok(())
+        } else {
+            Err(ErrorCode::Access)
+        }
+    }
+
+    /// Validated bus write. Bytes in the gaps between items are ignored.
+    pub fn write(&mut self, address: u16, data: &[u8]) -> Result<(), ErrorCode> {
+        self.check_write(address, data)?;
+        let start = address as usize;
+        for e in entries_in(address, data.len()) {
+            let offset = e.address as usize - start;
+            self.data[e.address as usize..e.end()]
+                .copy_from_slice(&data[offset..offset + e.size as usize]);
+        }
+        Ok(())
+    }
+}
+
+/// Items with a negative lower bound are signed.
+fn decode(e: &Entry, bytes: &[u8]) -> i32 {
+    match (e.size, e.min < 0) {
+        (1, false) => bytes[0] as i32,
+        (1, true) => bytes[0] as i8 as i32,
+        (2, false) => u16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        (2, true) => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(table: &mut ControlTable, item: Item, value: i32) -> Result<(), ErrorCode> {
+        let e = entry(item);
+        table.write(e.address, &value.to_le_bytes()[..e.size as usize])
+    }
+
+    #[test]
+    fn eeprom_is_locked_while_torque_is_on() {
+        let mut table = ControlTable::new();
+        write(&mut table, Item::TorqueEnable, 1).unwrap();
+        assert_eq!(write(&mut table, Item::Id, 5), Err(ErrorCode::Access));
+        assert_eq!(table.get(Item::Id), 1);
+        // live and RAM items stay writable
+        write(
+            &mut table,
+            Item::OperatingMode,
+            operating_mode::VELOCITY as i32,
+        )
+        .unwrap();
+        write(&mut table, Item::VelocityPGain, 200).unwrap();
+        write(&mut table, Item::TorqueEnable, 0).unwrap();
+        write(&mut table, Item::Id, 5).unwrap();
+        assert_eq!(table.get(Item::Id), 5);
+    }
+
+    #[test]
+    fn read_only_and_out_of_range_are_refused() {
+        let mut table = ControlTable::new();
+        assert_eq!(
+            write(&mut table, Item::ModelNumber, 1),
+            Err(ErrorCode::Access)
+        );
+        assert_eq!(write(&mut table, Item::Id, 253), Err(ErrorCode::DataRange));
+        assert_eq!(
+            write(&mut table, Item::PwmFrequency, 999),
+            Err(ErrorCode::DataRange)
+        );
+        // nothing but gaps
+        assert_eq!(table.write(12, &[1, 2]), Err(ErrorCode::Access));
+        assert_eq!(table.write(TABLE_SIZE as u16, &[0]), Err(ErrorCode::Access));
+    }
+
+    #[test]
+    fn abs_limit_follows_the_limit_item() {
+        let mut table = ControlTable::new();
+        write(&mut table, Item::PwmLimit, 500).unwrap();
+        write(&mut table, Item::GoalPwm, -500).unwrap();
+        assert_eq!(
+            write(&mut table, Item::GoalPwm, 501),
+            Err(ErrorCode::DataLimit)
+        );
+        assert_eq!(
+            write(&mut table, Item::GoalPwm, -501),
+            Err(ErrorCode::DataLimit)
+        );
+        assert_eq!(table.get(Item::GoalPwm), -500);
+    }
+
+    #[test]
+    fn range_limit_follows_both_items() {
+        let mut table = ControlTable::new();
+        write(&mut table, Item::MinPositionLimit, -1000).unwrap();
+        write(&mut table, Item::MaxPositionLimit, 2000).unwrap();
+        write(&mut table, Item::GoalPosition, -1000).unwrap();
+        write(&mut table, Item::GoalPosition, 2000).unwrap();
+        assert_eq!(
+            write(&mut table, Item::GoalPosition, 2001),
+            Err(ErrorCode::DataLimit)
+        );
+        assert_eq!(
+            write(&mut table, Item::GoalPosition, -1001),
+            Err(ErrorCode::DataLimit)
+        );
+    }
+
+    #[test]
+    fn partial_item_writes_are_refused() {
+        let mut table = ControlTable::new();
+        let goal = entry(Item::GoalVelocity).address;
+        assert_eq!(table.write(goal, &[1, 0]), Err(ErrorCode::DataLength));
+        assert_eq!(
+            table.write(goal + 1, &[1, 0, 0]),
+            Err(ErrorCode::DataLength)
+        );
+        // the tail of one item and the whole next one
+        assert_eq!(
+            table.write(goal + 2, &[0, 0, 0, 0, 0, 0]),
+            Err(ErrorCode::DataLength)
+        );
+        assert_eq!(table.get(Item::GoalVelocity), 0);
+        // several whole items at once, the gap after Led is ignored
+        let led = entry(Item::Led).address;
+        table.write(led, &[1, 0, 0, 1]).unwrap();
+        assert_eq!(table.get(Item::Led), 1);
+        assert_eq!(table.get(Item::StatusReturnLevel), 1);
+    }
+
+    #[test]
+    fn reset_keeps_read_only_items() {
+        let mut table = ControlTable::new();
+        write(&mut table, Item::Id, 9).unwrap();
+        write(&mut table, Item::BaudRate, 3).unwrap();
+        write(&mut table, Item::GoalPwm, 100).unwrap();
+        table.set(
+            Item::HardwareErrorStatus,
+            hardware_error::CLOCK_FAILURE as i32,
+        );
+        table.set(Item::LastCrash, 1);
+        table.set(Item::CrashAddress, 0x0800_1234);
+        table.set(Item::ResetCause, 2);
+        table.set(Item::InitError, 3);
+
+        table.reset(true, false);
+        assert_eq!(table.get(Item::Id), 9);
+        assert_eq!(table.get(Item::BaudRate), 1);
+        assert_eq!(table.get(Item::GoalPwm), 0);
+        assert_eq!(
+            table.get(Item::HardwareErrorStatus),
+            hardware_error::CLOCK_FAILURE as i32
+        );
+        assert_eq!(table.get(Item::LastCrash), 1);
+        assert_eq!(table.get(Item::CrashAddress), 0x0800_1234);
+        assert_eq!(table.get(Item::ResetCause), 2);
+        assert_eq!(table.get(Item::InitError), 3);
+        assert_eq!(table.get(Item::ModelNumber), MODEL_NUMBER as i32);
+    }
+
+    #[test]
+    fn save_and_restore_round_trip() {
+        let mut table = ControlTable::new();
+        write(&mut table, Item::Id, 9).unwrap();
+        write(&mut table, Item::HomingOffset, -12345).unwrap();
+        write(&mut table, Item::PositionPGain, 1234).unwrap();
+        write(&mut table, Item::GoalPwm, 100).unwrap();
+        let mut block = [0u8; 512];
+        let n = table.save(&mut block);
+
+        let mut restored = ControlTable::new();
+        assert!(restored.restore(CONFIG_VERSION, &block[..n]));
+        assert_eq!(restored.get(Item::Id), 9);
+        assert_eq!(restored.get(Item::HomingOffset), -12345);
+        assert_eq!(restored.get(Item::PositionPGain), 1234);
+        // RAM items other than the gains are not saved
+        assert_eq!(restored.get(Item::GoalPwm), 0);
+
+        // a newer layout is left alone
+        let mut newer = ControlTable::new();
+        assert!(!newer.restore(CONFIG_VERSION + 1, &block[..n]));
+        assert_eq!(newer.get(Item::Id), 1);
+    }
+
+    #[test]
+    fn restore_moves_pwm_frequency_from_version_1() {
+        // PwmFrequency = 20000 at its version 1 address
+        let block = [12, 0, 2, 0x20, 0x4E];
+        let mut table = ControlTable::new();
+        assert!(table.restore(1, &block));
+        assert_eq!(table.get(Item::PwmFrequency), 20000);
+    }
+
+    #[test]
+    fn restore_skips_bad_items() {
+        let id = entry(Item::Id).address.to_le_bytes();
+        let baud = entry(Item::BaudRate).address.to_le_bytes();
+        let tick = entry(Item::RealtimeTick).address.to_le_bytes();
+        #[rustfmt::skip]
+        let block = [
+            // out of range
+            id[0], id[1], 1, 253,
+            // wrong size
+            baud[0], baud[1], 2, 3, 0,
+            // not persistent
+            tick[0], tick[1], 2, 5, 0,
+            // cut off
+            baud[0], baud[1], 1,
+        ];
+        let mut table = ControlTable::new();
+        assert!(table.restore(CONFIG_VERSION, &block));
+        assert_eq!(table.get(Item::Id), 1);
+        assert_eq!(table.get(Item::BaudRate), 1);
+        assert_eq!(table.get(Item::RealtimeTick), 0);
+    }
+}
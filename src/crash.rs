@@ -0,0 +1,89 @@
+//! Crash record kept in RAM across a reset.
+//!
+//! The panic and HardFault handlers write it into the `NOINIT` region of
+//! `memory.x`, which the runtime neither zeroes nor initialises, and the next
+//! boot reads it back with `take`.
+//!
+//! A panic is recorded by kind only. Reading anything from its `PanicInfo`,
+//! even just the location, keeps a `Location` for every panic site in flash,
+//! close to 2K here, and formatting the message pulls in `core::fmt` on top.
+//! Neither fits the 28K image.
+
+use core::mem::MaybeUninit;
+use core::ptr;
+
+const MAGIC: u32 = 0xC4A5_11ED;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashKind {
+    Panic = 1,
+    HardFault = 2,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+    kind: u32,
+    address: u32,
+    checksum: u32,
+}
+
+#[link_section = ".noinit"]
+static mut CRASH: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+impl CrashRecord {
+    fn new(kind: CrashKind, address: u32) -> Self {
+        Self {
+            magic: MAGIC,
+            kind: kind as u32,
+            address,
+            checksum: 0,
+        }
+    }
+
+    fn sum(&self) -> u32 {
+        [self.magic, self.kind, self.address]
+            .iter()
+            .fold(0x811C_9DC5, |h, x| (h ^ *x).wrapping_mul(0x0100_0193))
+    }
+
+    pub fn kind(&self) -> CrashKind {
+        if self.kind == CrashKind::HardFault as u32 {
+            CrashKind::HardFault
+        } else {
+            CrashKind::Panic
+        }
+    }
+
+    /// HardFault: faulting PC. Panic: 0.
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+}
+
+fn store(mut record: CrashRecord) {
+    record.checksum = record.sum();
+    unsafe { ptr::addr_of_mut!(CRASH).cast::<CrashRecord>().write(record) };
+}
+
+pub fn record_panic() {
+    store(CrashRecord::new(CrashKind::Panic, 0));
+}
+
+pub fn record_hard_fault(pc: u32) {
+    store(CrashRecord::new(CrashKind::HardFault, pc));
+}
+
+/// The record left by the previous run, if it crashed. Cleared once read.
+pub fn take() -> Option<CrashRecord> {
+    let record = unsafe { ptr::addr_of!(CRASH).cast::<CrashRecord>().read() };
+    // whatever the RAM held at power-on
+    let valid = record.magic == MAGIC && record.checksum == record.sum();
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(CRASH).cast::<u32>(), 0) };
+    if valid {
+        Some(record)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,251 @@
+//! Motor current measurement and the inner current loop.
+
+use crate::pid::{AntiWindup, Pid, PidConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CurrentSenseConfig {
+    /// Shunt amplifier gain expressed in amperes per ADC count.
+    pub amps_per_count: f32,
+    /// Samples averaged for the zero-current offset at startup.
+    pub calibration_samples: u16,
+}
+
+impl Default for CurrentSenseConfig {
+    fn default() -> Self {
+        // 3.3V / 4096, 20V/V amplifier, 10mOhm shunt
+        Self {
+            amps_per_count: 3.3 / 4096.0 / 20.0 / 0.010,
+            calibration_samples: 1024,
+        }
+    }
+}
+
+/// Converts raw ADC readings of a bidirectional shunt amplifier to amperes.
+pub struct CurrentSense {
+    config: CurrentSenseConfig,
+    offset: f32,
+    sum: u32,
+    count: u16,
+}
+
+impl CurrentSense {
+    pub fn new(config: CurrentSenseConfig) -> Self {
+        Self {
+            config,
+            // mid-rail until calibrated
+            offset: 2048.0,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    /// Restarts the offset calibration. The bridge has to stay off until it is done.
+    pub fn start_calibration(&mut self) {
+        self.sum = 0;
+        self.count = 0;
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.count >= self.config.calibration_samples
+    }
+
+    /// Feeds a zero-current sample. Returns true once enough samples have been taken.
+    pub fn calibrate(&mut self, raw: u16) -> bool {
+        if !self.is_calibrated() {
+            self.sum += raw as u32;
+            self.count += 1;
+            if self.is_calibrated() {
+                self.offset = self.sum as f32 / self.count as f32;
+            }
+        }
+        self.is_calibrated()
+    }
+
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+
+    pub fn amps(&self, raw: u16) -> f32 {
+        (raw as f32 - self.offset) * self.config.amps_per_count
+    }
+}
+
+/// PI loop from a current target in amperes to a duty in -1.0..=1.0.
+pub struct CurrentLoop {
+    pid: Pid,
+}
+
+impl CurrentLoop {
+    pub fn new() -> Self {
+        Self {
+            pid: Pid::new(PidConfig::default()),
+        }
+    }
+
+    /// kp in duty per ampere, ki in duty per ampere-second.
+    pub fn set_gains(&mut self, kp: f32, ki: f32, duty_limit: f32) {
+        self.pid.set_config(PidConfig {
+            kp,
+            ki,
+            kd: 0.0,
+            output_min: -duty_limit,
+            output_max: duty_limit,
+            anti_windup: if kp > 0.0 {
+                AntiWindup::BackCalculation { kt: ki / kp }
+            } else {
+                AntiWindup::Conditional
+            },
+            derivative_alpha: 1.0,
+        });
+    }
+
+    pub fn reset(&mut self, duty: f32) {
+        self.pid.reset(duty);
+    }
+
+    pub fn update(&mut self, target: f32, measured: f32, dt: f32) -> f32 {
+        self.pid.update(target, measured, dt)
+    }
+}
+
+impl Default for CurrentLoop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loop period, 25kHz PWM with every 4th period sampled.
+    const DT: f32 = 4.0 / 25_000.0;
+
+    /// Winding as series R and L on a supply switched with the duty.
+    struct Winding {
+        volts: f32,
+        ohms: f32,
+        henries: f32,
+        amps: f32,
+    }
+
+    impl Winding {
+        fn new() -> Self {
+            Self {
+                volts: 12.0,
+                ohms: 2.0,
+                henries: 1e-3,
+                amps: 0.0,
+            }
+        }
+
+        fn step(&mut self, duty: f32) -> f32 {
+            let steady = self.volts * duty / self.ohms;
+            let decay = (-DT * self.ohms / self.henries).exp();
+            self.amps = steady + (self.amps - steady) * decay;
+            self.amps
+        }
+    }
+
+    /// Shunt amplifier output for `amps`, with the ADC's rounding.
+    fn adc(sense: &CurrentSenseConfig, offset: f32, amps: f32) -> u16 {
+        (offset + amps / sense.amps_per_count).round() as u16
+    }
+
+    fn tuned(duty_limit: f32) -> CurrentLoop {
+        // 500Hz crossover: kp = L wc / V, ki = R wc / V
+        let wc = 2.0 * core::f32::consts::PI * 500.0;
+        let mut current = CurrentLoop::new();
+        current.set_gains(1e-3 * wc / 12.0, 2.0 * wc / 12.0, duty_limit);
+        current
+    }
+
+    #[test]
+    fn calibration_averages_the_offset() {
+        let mut sense = CurrentSense::new(CurrentSenseConfig {
+            calibration_samples: 8,
+            ..CurrentSenseConfig::default()
+        });
+        assert!(!sense.is_calibrated());
+        assert_eq!(sense.offset(), 2048.0);
+        for &raw in &[2010, 2014, 2010, 2014, 2010, 2014, 2010] {
+            assert!(!sense.calibrate(raw));
+        }
+        assert!(sense.calibrate(2014));
+        assert_eq!(sense.offset(), 2012.0);
+        // later samples are ignored
+        assert!(sense.calibrate(0));
+        assert_eq!(sense.offset(), 2012.0);
+        assert_eq!(sense.amps(2012), 0.0);
+
+        sense.start_calibration();
+        assert!(!sense.is_calibrated());
+        for _ in 0..8 {
+            sense.calibrate(2100);
+        }
+        assert_eq!(sense.offset(), 2100.0);
+    }
+
+    #[test]
+    fn amps_are_signed_around_the_offset() {
+        let config = CurrentSenseConfig::default();
+        let mut sense = CurrentSense::new(config);
+        while !sense.calibrate(2000) {}
+        let amps = sense.amps(adc(&config, 2000.0, 1.5));
+        assert!((amps - 1.5).abs() <= config.amps_per_count);
+        let amps = sense.amps(adc(&config, 2000.0, -3.0));
+        assert!((amps + 3.0).abs() <= config.amps_per_count);
+    }
+
+    #[test]
+    fn step_settles_without_overshoot() {
+        let config = CurrentSenseConfig::default();
+        let mut sense = CurrentSense::new(config);
+        while !sense.calibrate(2031) {}
+        let mut current = tuned(1.0);
+        let mut winding = Winding::new();
+        let mut peak: f32 = 0.0;
+        for _ in 0..100 {
+            let measured = sense.amps(adc(&config, 2031.0, winding.amps));
+            let duty = current.update(2.0, measured, DT);
+            peak = peak.max(winding.step(duty));
+        }
+        assert!(peak < 2.0 * 1.05, "{}", peak);
+        assert!((winding.amps - 2.0).abs() < 0.02, "{}", winding.amps);
+    }
+
+    #[test]
+    fn duty_limit_holds_and_recovers() {
+        // 6A at full duty, 0.5 duty gives 3A at most
+        let mut current = tuned(0.5);
+        let mut winding = Winding::new();
+        for _ in 0..200 {
+            let duty = current.update(5.0, winding.amps, DT);
+            assert!(duty <= 0.5);
+            winding.step(duty);
+        }
+        assert!((winding.amps - 3.0).abs() < 0.01);
+        // back-calculation keeps the integrator near the limit
+        let mut settled = None;
+        for n in 0..200 {
+            let duty = current.update(1.0, winding.amps, DT);
+            winding.step(duty);
+            if settled.is_none() && (winding.amps - 1.0).abs() < 0.05 {
+                settled = Some(n);
+            }
+        }
+        assert!(settled.unwrap() < 20, "{:?}", settled);
+        assert!((winding.amps - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn reset_starts_from_the_given_duty() {
+        let mut current = tuned(1.0);
+        let mut winding = Winding::new();
+        winding.amps = 1.0;
+        // 1A steady state needs 1/6 duty
+        current.reset(1.0 / 6.0);
+        let duty = current.update(1.0, winding.amps, DT);
+        assert!((duty - 1.0 / 6.0).abs() < 1e-6);
+    }
+}
@@ -1,5 +1,183 @@
+/// How the bridge spends the off-time of each PWM period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecayMode {
+    /// Off-time coasts, current decays through the body diodes.
+    Fast,
+    /// Off-time shorts the motor, current recirculates.
+    Slow,
+    /// Both half-bridges switch in antiphase, 50% duty is standstill.
+    LockedAntiphase,
+}
+
+/// Full scale of the fixed-point duty, `DUTY_ONE` is 100% forward.
+pub const DUTY_ONE: i32 = 1 << 15;
+
+/// What to do with a duty outside -1.0..=1.0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClampPolicy {
+    /// Limit to full scale and report `Duty::Saturated`.
+    Saturate,
+    /// Refuse with `DutyError::OutOfRange`, the output keeps its last duty.
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duty {
+    Exact,
+    Saturated,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DutyError {
+    OutOfRange,
+    NotFinite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alignment {
+    Edge,
+    /// Counts up and down, on-time centred on the valley; halves the resolution.
+    Centre,
+}
+
+/// Where in the PWM period the sampling trigger fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplePoint {
+    /// Middle of the on-time, for motor current.
+    OnTime,
+    /// Middle of the off-time, for back-EMF while coasting.
+    OffTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PwmConfig {
+    pub frequency_hz: u32,
+    /// Fewest duty steps per period to accept; higher frequencies leave fewer.
+    pub min_steps: u16,
+    pub alignment: Alignment,
+    pub sample_point: SamplePoint,
+}
+
+impl Default for PwmConfig {
+    fn default() -> Self {
+        Self {
+            frequency_hz: 25_000,
+            min_steps: 256,
+            // current sampled at the valley, the centre of the period whatever the duty
+            alignment: Alignment::Centre,
+            sample_point: SamplePoint::OnTime,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PwmConfigError {
+    /// Fewer than `min_steps` per period at this frequency.
+    FrequencyTooHigh,
+    /// Beyond the prescaler range.
+    FrequencyTooLow,
+    /// The alignment can only change while the bridge is disabled.
+    Enabled,
+}
+
+/// Source of a hardware trip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Gate driver fault line
+    Break,
+    /// Over-current comparator
+    Break2,
+}
+
 pub trait DcMotorDriver {
+    /// Arms the bridge. Takes effect at once if the last command was zero duty,
+    /// otherwise on the next zero-duty command. Refused while a fault is latched.
     fn enable(&self);
+    /// Turns the bridge off and forces both outputs low.
     fn disable(&self);
-    fn set_pwm(&self, direction: f32, value: f32);
+    fn is_enabled(&self) -> bool;
+    /// Latched hardware trip, the bridge stays off until `clear_fault`.
+    fn fault(&self) -> Option<Fault>;
+    /// Fails with the fault while its input is still asserted.
+    fn clear_fault(&self) -> Result<(), Fault>;
+    /// Applies from the next duty command.
+    fn set_decay_mode(&self, mode: DecayMode);
+    /// Shorts the motor terminals. Counts as a zero-duty command.
+    fn brake(&self);
+    /// Leaves the motor terminals floating. Counts as a zero-duty command.
+    fn coast(&self);
+    /// Changes the PWM period and sampling point at the next period boundary,
+    /// keeping the duty.
+    fn set_pwm_config(&self, config: PwmConfig) -> Result<(), PwmConfigError>;
+    fn set_clamp_policy(&self, policy: ClampPolicy);
+    /// Signed duty, -1.0..=1.0, positive is forward.
+    fn set_duty(&self, duty: f32) -> Result<Duty, DutyError> {
+        if !duty.is_finite() {
+            return Err(DutyError::NotFinite);
+        }
+        // `as` saturates, which still lands outside the range
+        self.set_duty_fixed((duty * DUTY_ONE as f32) as i32)
+    }
+    /// Signed average voltage across the motor with the bridge fed from `supply_volts`.
+    /// A dead supply gives `DutyError::NotFinite`.
+    fn set_voltage(&self, volts: f32, supply_volts: f32) -> Result<Duty, DutyError> {
+        self.set_duty(volts / supply_volts)
+    }
+    /// Signed duty, -DUTY_ONE..=DUTY_ONE.
+    fn set_duty_fixed(&self, duty: i32) -> Result<Duty, DutyError>;
+    /// Duty the bridge is running with, after clamping. 0 after `brake` and `coast`.
+    fn duty_fixed(&self) -> i32;
+}
+
+/// A bridge that could not be set up. It never arms, so torque stays off.
+impl<T: DcMotorDriver> DcMotorDriver for Option<T> {
+    fn enable(&self) {
+        if let Some(m) = self {
+            m.enable();
+        }
+    }
+    fn disable(&self) {
+        if let Some(m) = self {
+            m.disable();
+        }
+    }
+    fn is_enabled(&self) -> bool {
+        self.as_ref().is_some_and(|m| m.is_enabled())
+    }
+    fn fault(&self) -> Option<Fault> {
+        self.as_ref().and_then(|m| m.fault())
+    }
+    fn clear_fault(&self) -> Result<(), Fault> {
+        self.as_ref().map_or(Ok(()), |m| m.clear_fault())
+    }
+    fn set_decay_mode(&self, mode: DecayMode) {
+        if let Some(m) = self {
+            m.set_decay_mode(mode);
+        }
+    }
+    fn brake(&self) {
+        if let Some(m) = self {
+            m.brake();
+        }
+    }
+    fn coast(&self) {
+        if let Some(m) = self {
+            m.coast();
+        }
+    }
+    fn set_pwm_config(&self, config: PwmConfig) -> Result<(), PwmConfigError> {
+        self.as_ref().map_or(Ok(()), |m| m.set_pwm_config(config))
+    }
+    fn set_clamp_policy(&self, policy: ClampPolicy) {
+        if let Some(m) = self {
+            m.set_clamp_policy(policy);
+        }
+    }
+    fn set_duty_fixed(&self, duty: i32) -> Result<Duty, DutyError> {
+        self.as_ref()
+            .map_or(Ok(Duty::Exact), |m| m.set_duty_fixed(duty))
+    }
+    fn duty_fixed(&self) -> i32 {
+        self.as_ref().map_or(0, |m| m.duty_fixed())
+    }
 }
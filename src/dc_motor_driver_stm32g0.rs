@@ -1,407 +1,2211 @@
 // interfaces
-use crate::indicator::Indicator;
-use crate::dc_motor_driver::DcMotorDriver;
+use motor_core::config_store::{Flash, FlashError};
+use motor_core::dc_motor_driver::{
+    Alignment, ClampPolicy, DcMotorDriver, DecayMode, Duty, DutyError, Fault, PwmConfig,
+    PwmConfigError, SamplePoint, DUTY_ONE,
+};
+use motor_core::encoder::{Direction, Encoder, MultiTurnCounter};
+use motor_core::indicator::Indicator;
+use motor_core::velocity::{Edge, Sample};
+use motor_core::watchdog::{ResetCause, Watchdog};
 
 //
-use core::cell::RefCell;
-use core::fmt::{self, Write};
-use core::time::Duration;
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 
-use stm32g0::stm32g030::{Interrupt, tim14};
-use stm32g0::stm32g030::Peripherals;
-use stm32g0::stm32g030::NVIC;
-use stm32g0::stm32g030::{exti, CorePeripherals};
+#[cfg(feature = "usart1")]
+use stm32g0::stm32g030::USART1;
+use stm32g0::stm32g030::{gpioa, rcc, tim1, usart1, Interrupt};
+use stm32g0::stm32g030::{CorePeripherals, Peripherals, NVIC};
+use stm32g0::stm32g030::{ADC, DBG, DMA, DMAMUX, FLASH, GPIOA, GPIOB, IWDG, RCC};
+use stm32g0::stm32g030::{TIM1, TIM16, TIM17, TIM3, USART2};
 
 use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::scb::VectActive;
+use cortex_m::peripheral::SCB;
 
-pub static G_PERIPHERAL: Mutex<RefCell<Option<stm32g0::stm32g030::Peripherals>>> =
-    Mutex::new(RefCell::new(None));
+/// GPIO mode typestates.
+pub struct Unconfigured;
+pub struct Output;
+pub struct Alternate<const AF: u8>;
+pub struct Analog;
 
-pub fn init_g_peripheral(perip: Peripherals) {
-    free(|cs| G_PERIPHERAL.borrow(cs).replace(Some(perip)));
+/// Pin `N` of port `P`, owned by whoever configured it.
+pub struct Pin<const P: char, const N: u8, MODE> {
+    _mode: PhantomData<MODE>,
 }
 
-pub fn clock_init(perip: &Peripherals, core_perip: &mut CorePeripherals) {
-    perip.RCC.cr.modify(|_, w| w.hsebyp().set_bit());
-    perip.RCC.cr.modify(|_, w| w.hseon().set_bit());
-    while perip.RCC.cr.read().hserdy().bit_is_clear() {}
+pub type PA0<MODE = Unconfigured> = Pin<'A', 0, MODE>;
+#[cfg(not(feature = "usart1"))]
+pub type PA1<MODE = Unconfigured> = Pin<'A', 1, MODE>;
+#[cfg(not(feature = "usart1"))]
+pub type PA2<MODE = Unconfigured> = Pin<'A', 2, MODE>;
+pub type PA3<MODE = Unconfigured> = Pin<'A', 3, MODE>;
+pub type PA4<MODE = Unconfigured> = Pin<'A', 4, MODE>;
+pub type PA5<MODE = Unconfigured> = Pin<'A', 5, MODE>;
+pub type PA6<MODE = Unconfigured> = Pin<'A', 6, MODE>;
+pub type PA7<MODE = Unconfigured> = Pin<'A', 7, MODE>;
+pub type PA8<MODE = Unconfigured> = Pin<'A', 8, MODE>;
+pub type PA11<MODE = Unconfigured> = Pin<'A', 11, MODE>;
+#[cfg(feature = "usart1")]
+pub type PA12<MODE = Unconfigured> = Pin<'A', 12, MODE>;
+pub type PB3<MODE = Unconfigured> = Pin<'B', 3, MODE>;
+#[cfg(feature = "usart1")]
+pub type PB6<MODE = Unconfigured> = Pin<'B', 6, MODE>;
+pub type PB7<MODE = Unconfigured> = Pin<'B', 7, MODE>;
+pub type PB12<MODE = Unconfigured> = Pin<'B', 12, MODE>;
+pub type PB13<MODE = Unconfigured> = Pin<'B', 13, MODE>;
+pub type PB14<MODE = Unconfigured> = Pin<'B', 14, MODE>;
 
-    // Disable the PLL
-    perip.RCC.cr.modify(|_, w| w.pllon().clear_bit());
-    // Wait until PLL is fully stopped
-    while perip.RCC.cr.read().pllrdy().bit_is_set() {}
-    perip
-        .RCC
-        .pllsyscfgr
-        .modify(|_, w| unsafe { w.pllsrc().bits(0b11) }); // HSE
-    perip
-        .RCC
-        .pllsyscfgr
-        .modify(|_, w| unsafe { w.pllm().bits(0b011) }); // div4
-    perip
-        .RCC
-        .pllsyscfgr
-        .modify(|_, w| unsafe { w.plln().bits(16) }); // div16
-    perip
-        .RCC
-        .pllsyscfgr
-        .modify(|_, w| unsafe { w.pllr().bits(0b010) }); // div3
+impl<const P: char, const N: u8, MODE> Pin<P, N, MODE> {
+    const fn new() -> Self {
+        Self { _mode: PhantomData }
+    }
+
+    fn port() -> &'static gpioa::RegisterBlock {
+        // GPIOB has the same register layout as GPIOA
+        match P {
+            'A' => unsafe { &*GPIOA::ptr() },
+            _ => unsafe { &*(GPIOB::ptr() as *const gpioa::RegisterBlock) },
+        }
+    }
 
-    perip.RCC.cr.modify(|_, w| w.pllon().set_bit());
-    while perip.RCC.cr.read().pllrdy().bit_is_clear() {}
-    perip.RCC.pllsyscfgr.modify(|_, w| w.pllren().set_bit());
+    fn set_mode(mode: u32) {
+        set_pin_mode(Self::port(), N as u32, mode);
+    }
+}
 
-    perip
-        .FLASH
-        .acr
-        .modify(|_, w| unsafe { w.latency().bits(4) });
-    while perip.FLASH.acr.read().latency().bits() != 4 {
-        defmt::info!("latency bit: {}", perip.FLASH.acr.read().latency().bits());
+// The pin helpers below are shared by every `Pin<P, N>` instead of being
+// stamped out per pin; the image has to fit below the config pages.
+
+// MODER and friends are shared by the whole port
+#[inline(never)]
+fn set_pin_mode(port: &gpioa::RegisterBlock, n: u32, mode: u32) {
+    free(|_| {
+        port.moder
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << (n * 2)) | mode << (n * 2)) });
+    });
+}
+
+#[inline(never)]
+fn set_pin_alternate(port: &gpioa::RegisterBlock, n: u32, af: u32) {
+    free(|_| {
+        if n < 8 {
+            port.afrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << (n * 4)) | af << (n * 4)) });
+        } else {
+            port.afrh.modify(|r, w| unsafe {
+                w.bits(r.bits() & !(0xF << ((n - 8) * 4)) | af << ((n - 8) * 4))
+            });
+        }
+        port.ospeedr
+            .modify(|r, w| unsafe { w.bits(r.bits() | 0b11 << (n * 2)) });
+    });
+    set_pin_mode(port, n, 0b10);
+}
+
+impl<const P: char, const N: u8> Pin<P, N, Unconfigured> {
+    /// A pin out of its reset (analog) mode is driven by something the
+    /// typestate does not know about.
+    fn claim() -> Result<(), InitError> {
+        if Self::port().moder.read().bits() >> (N as u32 * 2) & 0b11 == 0b11 {
+            Ok(())
+        } else {
+            Err(InitError::PinInUse)
+        }
+    }
+
+    pub fn into_output(self) -> Result<Pin<P, N, Output>, InitError> {
+        Self::claim()?;
+        Self::set_mode(0b01);
+        Ok(Pin::new())
+    }
+
+    /// Alternate function `AF` at very high speed.
+    pub fn into_alternate<const AF: u8>(self) -> Result<Pin<P, N, Alternate<AF>>, InitError> {
+        Self::claim()?;
+        set_pin_alternate(Self::port(), N as u32, AF as u32);
+        Ok(Pin::new())
+    }
+
+    pub fn into_analog(self) -> Result<Pin<P, N, Analog>, InitError> {
+        Self::claim()?;
+        Ok(Pin::new())
     }
+}
+
+// BSRR is write-only and per pin, no read-modify-write to protect
+impl<const P: char, const N: u8> Pin<P, N, Output> {
+    pub fn set_high(&self) {
+        Self::port().bsrr.write(|w| unsafe { w.bits(1 << N) });
+    }
+    pub fn set_low(&self) {
+        Self::port()
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << (N as u32 + 16)) });
+    }
+    pub fn is_set_high(&self) -> bool {
+        Self::port().odr.read().bits() & (1 << N) != 0
+    }
+    pub fn toggle(&self) {
+        if self.is_set_high() {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
+}
+
+/// The pins this board uses, each handed out once.
+///
+/// The TSSOP20 G030F6 shares one pad between PA8, PB0, PB1 and PB2, and
+/// another between PB3 to PB6; only one pin of each may be used. PB12 to
+/// PB14 are only bonded out on the LQFP48 G030C, with the `lqfp48` feature.
+/// PA12 and PB6 carry the bus instead of PA1 and PA2 with the `usart1`
+/// feature, which needs it.
+pub struct Pins {
+    pub pa0: PA0,
+    #[cfg(not(feature = "usart1"))]
+    pub pa1: PA1,
+    #[cfg(not(feature = "usart1"))]
+    pub pa2: PA2,
+    pub pa3: PA3,
+    pub pa4: PA4,
+    pub pa5: PA5,
+    pub pa6: PA6,
+    pub pa7: PA7,
+    pub pa8: PA8,
+    pub pa11: PA11,
+    #[cfg(feature = "usart1")]
+    pub pa12: PA12,
+    pub pb3: PB3,
+    #[cfg(feature = "usart1")]
+    pub pb6: PB6,
+    pub pb7: PB7,
+    #[cfg(feature = "lqfp48")]
+    pub pb12: PB12,
+    #[cfg(feature = "lqfp48")]
+    pub pb13: PB13,
+    #[cfg(feature = "lqfp48")]
+    pub pb14: PB14,
+}
 
-    perip.RCC.cfgr.modify(|_, w| unsafe { w.sw().bits(0b010) }); // PLL
-                                                                 // perip.RCC.cfgr.modify(|_, w| w.sw().hse());
-    defmt::debug!("sw bit: {}", perip.RCC.cfgr.read().sw().bits());
-    while !(perip.RCC.cfgr.read().sw().bits() == 0b010) {}
-    while !(perip.RCC.cfgr.read().sws().bits() == 0b010) {
-        defmt::info!("sw bit: {}", perip.RCC.cfgr.read().sw().bits());
-        defmt::info!("sws bit: {}", perip.RCC.cfgr.read().sws().bits());
+/// Peripherals split into per-function handles. RCC and TIM17 (`micros`)
+/// stay here, the rest is moved into the drivers.
+pub struct Board {
+    pub pins: Pins,
+    pub tim1: TIM1,
+    pub tim3: TIM3,
+    pub tim16: TIM16,
+    pub adc: ADC,
+    pub dma: DMA,
+    pub dmamux: DMAMUX,
+    #[cfg(feature = "usart1")]
+    pub usart1: USART1,
+    #[cfg(not(feature = "usart1"))]
+    pub usart2: USART2,
+    pub iwdg: IWDG,
+    pub dbg: DBG,
+    pub flash: FLASH,
+}
+
+impl Board {
+    /// Call after `clock_init`.
+    pub fn split(perip: Peripherals) -> Self {
+        // GPIOポートの電源投入(クロックの有効化)
+        perip
+            .RCC
+            .iopenr
+            .modify(|_, w| w.iopaen().set_bit().iopben().set_bit());
+        Self {
+            pins: Pins {
+                pa0: Pin::new(),
+                #[cfg(not(feature = "usart1"))]
+                pa1: Pin::new(),
+                #[cfg(not(feature = "usart1"))]
+                pa2: Pin::new(),
+                pa3: Pin::new(),
+                pa4: Pin::new(),
+                pa5: Pin::new(),
+                pa6: Pin::new(),
+                pa7: Pin::new(),
+                pa8: Pin::new(),
+                pa11: Pin::new(),
+                #[cfg(feature = "usart1")]
+                pa12: Pin::new(),
+                pb3: Pin::new(),
+                #[cfg(feature = "usart1")]
+                pb6: Pin::new(),
+                pb7: Pin::new(),
+                #[cfg(feature = "lqfp48")]
+                pb12: Pin::new(),
+                #[cfg(feature = "lqfp48")]
+                pb13: Pin::new(),
+                #[cfg(feature = "lqfp48")]
+                pb14: Pin::new(),
+            },
+            tim1: perip.TIM1,
+            tim3: perip.TIM3,
+            tim16: perip.TIM16,
+            adc: perip.ADC,
+            dma: perip.DMA,
+            dmamux: perip.DMAMUX,
+            #[cfg(feature = "usart1")]
+            usart1: perip.USART1,
+            #[cfg(not(feature = "usart1"))]
+            usart2: perip.USART2,
+            iwdg: perip.IWDG,
+            dbg: perip.DBG,
+            flash: perip.FLASH,
+        }
+    }
+}
+
+/// Clock enables are shared by every driver, only touched while initialising.
+fn rcc() -> &'static rcc::RegisterBlock {
+    unsafe { &*RCC::ptr() }
+}
+
+/// Startup failures. The clock ones leave the MCU running on HSI16.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitError {
+    /// HSE not ready in time.
+    HseTimeout = 1,
+    /// PLL did not lock, or did not stop for reconfiguration.
+    PllLock = 2,
+    /// FLASH_ACR did not take the wait states.
+    FlashLatency = 3,
+    /// SYSCLK did not switch over to the PLL.
+    ClockSwitch = 4,
+    /// Already taken, or did not come up in time.
+    PeripheralUnavailable = 5,
+    /// Pin already configured by someone else.
+    PinInUse = 6,
+    /// No divider setting gives the requested clocks.
+    ClockConfig = 7,
+}
+
+const HSI16_HZ: u32 = 16_000_000;
+const SYSCLK_MAX_HZ: u32 = 64_000_000;
+
+const HSE_TIMEOUT_US: u32 = 5_000;
+const PLL_TIMEOUT_US: u32 = 1_000;
+const SWITCH_TIMEOUT_US: u32 = 1_000;
+/// Control tick and current loop, below the timebase, encoder and USART
+/// interrupts. Only the top two bits are implemented.
+const CONTROL_PRIORITY: u8 = 0x80;
+/// Encoder overflow, between the timebase and the control loop so a count
+/// wrap is folded in before the control tick reads the position.
+const ENCODER_PRIORITY: u8 = 0x40;
+
+/// Where SYSCLK comes from, directly or through the PLL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    /// External clock on OSC_IN, 4..48MHz. No board here has a crystal on
+    /// OSC_IN/OSC_OUT.
+    HseBypass {
+        hz: u32,
+    },
+    Hsi16,
+}
+
+/// Requested clock tree, checked and turned into register settings by `clock_init`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockConfig {
+    source: ClockSource,
+    sysclk_hz: u32,
+    apb_divider: u32,
+}
+
+impl ClockConfig {
+    /// SYSCLK straight from `source`, APB undivided.
+    pub const fn new(source: ClockSource) -> Self {
+        let sysclk_hz = match source {
+            ClockSource::HseBypass { hz } => hz,
+            ClockSource::Hsi16 => HSI16_HZ,
+        };
+        Self {
+            source,
+            sysclk_hz,
+            apb_divider: 1,
+        }
     }
-    // while !perip.RCC.cfgr.read().sws().is_hse() {}
+
+    /// Anything but the source frequency goes through the PLL.
+    pub const fn sysclk(self, hz: u32) -> Self {
+        Self {
+            sysclk_hz: hz,
+            ..self
+        }
+    }
+
+    /// 1, 2, 4, 8 or 16.
+    pub const fn apb_divider(self, divider: u32) -> Self {
+        Self {
+            apb_divider: divider,
+            ..self
+        }
+    }
+
+    fn source_hz(&self) -> u32 {
+        match self.source {
+            ClockSource::HseBypass { hz } => hz,
+            ClockSource::Hsi16 => HSI16_HZ,
+        }
+    }
+
+    fn is_hse(&self) -> bool {
+        self.source != ClockSource::Hsi16
+    }
+
+    /// Dividers and wait states for this configuration.
+    fn plan(&self) -> Result<ClockPlan, InitError> {
+        let source_hz = self.source_hz();
+        if self.is_hse() && !(4_000_000..=48_000_000).contains(&source_hz) {
+            return Err(InitError::ClockConfig);
+        }
+        if self.sysclk_hz == 0 || self.sysclk_hz > SYSCLK_MAX_HZ {
+            return Err(InitError::ClockConfig);
+        }
+        let pll = if self.sysclk_hz == source_hz {
+            None
+        } else {
+            Some(pll_dividers(source_hz, self.sysclk_hz).ok_or(InitError::ClockConfig)?)
+        };
+        let ppre = match self.apb_divider {
+            1 => 0b000,
+            2 => 0b100,
+            4 => 0b101,
+            8 => 0b110,
+            16 => 0b111,
+            _ => return Err(InitError::ClockConfig),
+        };
+        // 0/1/2 wait states up to 24/48/64MHz (range 1)
+        let latency = match self.sysclk_hz {
+            0..=24_000_000 => 0,
+            24_000_001..=48_000_000 => 1,
+            _ => 2,
+        };
+        Ok(ClockPlan {
+            pll,
+            ppre,
+            latency,
+            clocks: Clocks::new(self.sysclk_hz, self.apb_divider),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PllDividers {
+    m: u32,
+    n: u32,
+    r: u32,
+}
+
+struct ClockPlan {
+    pll: Option<PllDividers>,
+    ppre: u8,
+    latency: u8,
+    clocks: Clocks,
+}
+
+/// M, N and R for exactly `output_hz`, within 2.66..16MHz at the PLL input
+/// and 64..344MHz at the VCO.
+fn pll_dividers(input_hz: u32, output_hz: u32) -> Option<PllDividers> {
+    for m in 1..=8 {
+        if input_hz < 2_660_000 * m || input_hz > 16_000_000 * m {
+            continue;
+        }
+        for r in 2..=8 {
+            // output_hz is at most SYSCLK_MAX_HZ, vco_hz * m stays below 2^32
+            let vco_hz = output_hz * r;
+            if !(vco_hz * m).is_multiple_of(input_hz) {
+                continue;
+            }
+            let n = vco_hz * m / input_hz;
+            if (8..=86).contains(&n) && (64_000_000..=344_000_000).contains(&vco_hz) {
+                return Some(PllDividers { m, n, r });
+            }
+        }
+    }
+    None
+}
+
+/// Frequencies the clock tree ends up at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clocks {
+    pub sysclk_hz: u32,
+    /// Core and AHB, undivided from SYSCLK.
+    pub hclk_hz: u32,
+    /// APB, feeds the USARTs and the ADC.
+    pub pclk_hz: u32,
+    /// Timer kernel clock, doubled when the APB prescaler divides.
+    pub timer_hz: u32,
+}
+
+impl Clocks {
+    /// The reset state.
+    const HSI16: Self = Self::new(HSI16_HZ, 1);
+
+    const fn new(sysclk_hz: u32, apb_divider: u32) -> Self {
+        let pclk_hz = sysclk_hz / apb_divider;
+        Self {
+            sysclk_hz,
+            hclk_hz: sysclk_hz,
+            pclk_hz,
+            timer_hz: if apb_divider == 1 {
+                pclk_hz
+            } else {
+                pclk_hz * 2
+            },
+        }
+    }
+}
+
+/// What `clocks()` can be, indexed by `G_CLOCKS`. Filled in by `clock_init`
+/// before CSS is on, so the NMI only has to store an index.
+static mut G_CLOCK_CHOICES: [Clocks; 3] = [Clocks::HSI16; 3];
+const CLOCKS_RESET: u8 = 0;
+const CLOCKS_CONFIGURED: u8 = 1;
+/// SYSCLK on HSI16 after an HSE failure, the APB divider left as configured.
+const CLOCKS_CSS_FALLBACK: u8 = 2;
+/// Clocks in effect. A single byte, the NMI can switch it without tearing.
+static G_CLOCKS: AtomicU8 = AtomicU8::new(CLOCKS_RESET);
+
+pub fn clocks() -> Clocks {
+    let index = G_CLOCKS.load(Ordering::Relaxed) as usize;
+    unsafe { G_CLOCK_CHOICES[index] }
+}
+
+/// Polls `ready` for about `us` microseconds, false if it never came true.
+// not generic over `ready`, one copy serves all the waits
+#[inline(never)]
+fn wait_until(us: u32, ready: &dyn Fn() -> bool) -> bool {
+    let cycles = (clocks().hclk_hz / 1_000_000).max(1);
+    for _ in 0..us {
+        if ready() {
+            return true;
+        }
+        cortex_m::asm::delay(cycles);
+    }
+    ready()
+}
+
+/// Sets up the clock tree as `config` asks. If the configuration is invalid
+/// or a step times out the clock is put back on HSI16 and the error returned;
+/// the timers are set up from whichever clock ended up running, so the
+/// firmware carries on either way.
+pub fn clock_init(
+    perip: &Peripherals,
+    core_perip: &mut CorePeripherals,
+    config: ClockConfig,
+) -> Result<Clocks, InitError> {
+    let result = config.plan().and_then(|plan| {
+        let divider = plan.clocks.sysclk_hz / plan.clocks.pclk_hz;
+        unsafe {
+            G_CLOCK_CHOICES[CLOCKS_CONFIGURED as usize] = plan.clocks;
+            G_CLOCK_CHOICES[CLOCKS_CSS_FALLBACK as usize] = Clocks::new(HSI16_HZ, divider);
+        }
+        switch_clocks(perip, &config, &plan).map(|_| plan.clocks)
+    });
+    match result {
+        Ok(_) => G_CLOCKS.store(CLOCKS_CONFIGURED, Ordering::Relaxed),
+        Err(_) => {
+            hsi16_fallback(perip);
+            G_CLOCKS.store(CLOCKS_RESET, Ordering::Relaxed);
+        }
+    }
+    // the HSE may already have failed once CSS was on
+    if clock_failure() {
+        G_CLOCKS.store(CLOCKS_CSS_FALLBACK, Ordering::Relaxed);
+    }
+    let clocks = clocks();
 
     perip.RCC.apbenr2.modify(|_, w| w.tim16en().set_bit());
-    perip.RCC.apbenr2.modify(|_, w| w.tim14en().set_bit());
+    perip.RCC.apbenr2.modify(|_, w| w.tim17en().set_bit());
 
+    let us_psc = micros_prescaler(clocks);
+    // TIM16: 1kHz update interrupt for the control tick, enabled by `Tick`
     let tim16 = &perip.TIM16;
-    tim16.psc.modify(|_, w| unsafe { w.bits(64_000 - 1) }); // 1kHz
-                                                           // tim16.arr.modify(|_, w| unsafe { w.bits(1000 - 1) });    // 1kHz
-
-    // tim16.dier.modify(|_, w| w.uie().set_bit());
+    tim16.psc.modify(|_, w| unsafe { w.bits(us_psc) }); // 1us
+    tim16.arr.modify(|_, w| unsafe { w.bits(1000 - 1) }); // 1kHz
+    tim16.cr1.modify(|_, w| w.urs().set_bit()); // UG only reloads, no interrupt
+    tim16.egr.write(|w| w.ug().set_bit()); // load PSC
+    tim16.sr.modify(|_, w| w.uif().clear_bit());
     tim16.cr1.modify(|_, w| w.cen().set_bit());
-    let tim14 = &perip.TIM14;
-    tim14.psc.modify(|_, w| unsafe { w.bits(64 - 1) }); // 1us
-    tim14.arr.modify(|_, w| unsafe { w.bits(10 - 1) }); // 10us
-    tim14.cr1.modify(|_, w| w.urs().set_bit()); // UGによるSW割り込みをOFFにする
 
-    // ARPE: ARRのPreloadは不要（どっちでもいい）なのでそのままにしておく
-    // UDIS
-    tim14.cr1.modify(|_, w| w.udis().clear_bit());
-    tim14.dier.modify(|_, w| w.uie().set_bit());
+    // TIM17: free running 1us timebase, extended to 32bit by the update interrupt
+    let tim17 = &perip.TIM17;
+    tim17.psc.modify(|_, w| unsafe { w.bits(us_psc) }); // 1us
+    tim17.arr.modify(|_, w| unsafe { w.bits(0xFFFF) });
+    tim17.cr1.modify(|_, w| w.urs().set_bit()); // UG only reloads, no interrupt
+    tim17.egr.write(|w| w.ug().set_bit()); // load PSC
+    tim17.sr.modify(|_, w| w.uif().clear_bit());
+    tim17.dier.modify(|_, w| w.uie().set_bit());
+    tim17.cr1.modify(|_, w| w.cen().set_bit());
 
     // 割り込み設定
     unsafe {
-        core_perip.NVIC.set_priority(Interrupt::TIM16, 0);
-        NVIC::unmask(Interrupt::TIM16);
-        core_perip.NVIC.set_priority(Interrupt::TIM14, 2);
-        NVIC::unmask(Interrupt::TIM14);
+        core_perip
+            .NVIC
+            .set_priority(Interrupt::TIM16, CONTROL_PRIORITY);
+        core_perip
+            .NVIC
+            .set_priority(Interrupt::DMA_CHANNEL1, CONTROL_PRIORITY);
+        core_perip.NVIC.set_priority(Interrupt::TIM17, 0);
+        NVIC::unmask(Interrupt::TIM17);
+        core_perip
+            .NVIC
+            .set_priority(Interrupt::TIM3, ENCODER_PRIORITY);
+        NVIC::unmask(Interrupt::TIM3);
     }
+    result
+}
+
+/// PSC for the 1us count of the tick (TIM16) and the timebase (TIM17).
+fn micros_prescaler(clocks: Clocks) -> u32 {
+    clocks.timer_hz / 1_000_000 - 1
 }
 
+/// Set by the NMI when the clock security system caught the HSE failing.
+static G_CLOCK_FAILURE: AtomicBool = AtomicBool::new(false);
 
-pub fn timer_interrupt_task() {
-    free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-        None => (),
-        Some(perip) => {
-            // 高速化のためにべた書きする
+/// The HSE stopped and SYSCLK has been on HSI16 since. Stays set until reset.
+pub fn clock_failure() -> bool {
+    G_CLOCK_FAILURE.load(Ordering::Relaxed)
+}
 
-            let tim14 = &perip.TIM14;
-            // Stop TIM14
-            tim14.cr1.modify(|_, w| w.cen().clear_bit());
-            // Clear tif
-            tim14.sr.modify(|_, w| w.uif().clear_bit());
-            // Toggle trigger out
-            let gpioa = &perip.GPIOA;
-            if gpioa.odr.read().odr6().is_low() {
-                gpioa.bsrr.write(|w| w.bs6().set());
-            } else {
-                gpioa.bsrr.write(|w| w.br6().reset());
+/// NMI entry. On an HSE failure the hardware has already moved SYSCLK to
+/// HSI16 and stopped the PLL; this turns the bridge off and re-derives
+/// `clocks()`, the timebases and the USART baud rates. TIM1 is left to
+/// `DcPwm::set_pwm_config` once the app has seen `clock_failure`.
+pub fn clock_security_task() {
+    let rcc = rcc();
+    if rcc.cifr.read().cssf().bit_is_clear() {
+        return;
+    }
+    // the NMI fires again as long as CSSF is set
+    rcc.cicr.write(|w| w.cssc().set_bit());
+    // torque goes off from the app
+    G_CLOCK_FAILURE.store(true, Ordering::Relaxed);
+    force_bridge_off(unsafe { &*TIM1::ptr() });
+
+    let old = clocks();
+    G_CLOCKS.store(CLOCKS_CSS_FALLBACK, Ordering::Relaxed);
+    let new = clocks();
+
+    // UG loads PSC right away but clears the counter, put it back so that
+    // `Tick` and `micros` carry on
+    let us_psc = micros_prescaler(new);
+    let tim16 = unsafe { &*TIM16::ptr() };
+    let count = tim16.cnt.read().bits();
+    tim16.psc.write(|w| unsafe { w.bits(us_psc) });
+    tim16.egr.write(|w| w.ug().set_bit());
+    tim16.cnt.write(|w| unsafe { w.bits(count) });
+    let tim17 = unsafe { &*TIM17::ptr() };
+    let count = tim17.cnt.read().bits();
+    tim17.psc.write(|w| unsafe { w.bits(us_psc) });
+    tim17.egr.write(|w| w.ug().set_bit());
+    tim17.cnt.write(|w| unsafe { w.bits(count) });
+
+    let ports = [
+        #[cfg(feature = "usart1")]
+        UsartPort::Usart1,
+        UsartPort::Usart2,
+    ];
+    for &port in ports.iter() {
+        let usart = usart_regs(port);
+        if usart.cr1.read().ue().bit_is_clear() {
+            continue;
+        }
+        // in kHz, a 16 bit BRR times 64MHz would not fit
+        let (old_khz, new_khz) = (old.pclk_hz / 1000, new.pclk_hz / 1000);
+        let brr = usart.brr.read().bits();
+        let brr = (brr * new_khz + old_khz / 2) / old_khz;
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.brr.write(|w| unsafe { w.bits(brr) });
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+}
+
+/// Both bridge channels forced inactive and the outputs to their idle level
+/// (OSSI). `DcPwm` writes these registers read-modify-write, so after an HSE
+/// failure it calls this again behind each write the NMI may have cut into.
+fn force_bridge_off(tim: &tim1::RegisterBlock) {
+    tim.ccmr1_output()
+        .modify(|_, w| w.oc1m().force_inactive().oc2m().force_inactive());
+    tim.bdtr.modify(|_, w| w.moe().clear_bit());
+}
+
+fn set_flash_latency(flash: &FLASH, latency: u8) -> Result<(), InitError> {
+    flash
+        .acr
+        .modify(|_, w| unsafe { w.latency().bits(latency) });
+    if flash.acr.read().latency().bits() != latency {
+        defmt::info!("latency bit: {}", flash.acr.read().latency().bits());
+        return Err(InitError::FlashLatency);
+    }
+    Ok(())
+}
+
+/// From the reset clock (HSISYS) to `plan`.
+fn switch_clocks(
+    perip: &Peripherals,
+    config: &ClockConfig,
+    plan: &ClockPlan,
+) -> Result<(), InitError> {
+    let rcc = &perip.RCC;
+    if config.is_hse() {
+        rcc.cr.modify(|_, w| w.hsebyp().set_bit());
+        rcc.cr.modify(|_, w| w.hseon().set_bit());
+        if !wait_until(HSE_TIMEOUT_US, &|| rcc.cr.read().hserdy().bit_is_set()) {
+            return Err(InitError::HseTimeout);
+        }
+    }
+
+    let sw = match plan.pll {
+        Some(pll) => {
+            // Disable the PLL
+            rcc.cr.modify(|_, w| w.pllon().clear_bit());
+            // Wait until PLL is fully stopped
+            if !wait_until(PLL_TIMEOUT_US, &|| rcc.cr.read().pllrdy().bit_is_clear()) {
+                return Err(InitError::PllLock);
+            }
+            let pllsrc = if config.is_hse() { 0b11 } else { 0b10 };
+            rcc.pllsyscfgr.modify(|_, w| unsafe {
+                w.pllsrc()
+                    .bits(pllsrc)
+                    .pllm()
+                    .bits(pll.m as u8 - 1)
+                    .plln()
+                    .bits(pll.n as u8)
+                    .pllr()
+                    .bits(pll.r as u8 - 1)
+            });
+            rcc.cr.modify(|_, w| w.pllon().set_bit());
+            if !wait_until(PLL_TIMEOUT_US, &|| rcc.cr.read().pllrdy().bit_is_set()) {
+                return Err(InitError::PllLock);
             }
+            rcc.pllsyscfgr.modify(|_, w| w.pllren().set_bit());
+            0b010 // PLLRCLK
+        }
+        None if config.is_hse() => 0b001, // HSE
+        None => 0b000,                    // HSISYS
+    };
+
+    // wait states go up before the clock does and come down after
+    let latency = perip.FLASH.acr.read().latency().bits();
+    if plan.latency > latency {
+        set_flash_latency(&perip.FLASH, plan.latency)?;
+    }
+    rcc.cfgr
+        .modify(|_, w| unsafe { w.ppre().bits(plan.ppre).sw().bits(sw) });
+    if !wait_until(SWITCH_TIMEOUT_US, &|| rcc.cfgr.read().sws().bits() == sw) {
+        defmt::info!("sws bit: {}", rcc.cfgr.read().sws().bits());
+        return Err(InitError::ClockSwitch);
+    }
+    if plan.latency < latency {
+        set_flash_latency(&perip.FLASH, plan.latency)?;
+    }
+    if config.is_hse() {
+        // clock security: an HSE failure switches to HSI16 and raises the NMI
+        rcc.cr.modify(|_, w| w.csson().set_bit());
+    }
+    Ok(())
+}
+
+/// Back to the reset clock, `Clocks::HSI16`. The flash wait states are left
+/// as they are, too many only costs speed.
+fn hsi16_fallback(perip: &Peripherals) {
+    let rcc = &perip.RCC;
+    rcc.cr
+        .modify(|_, w| unsafe { w.hsion().set_bit().hsidiv().bits(0) });
+    wait_until(SWITCH_TIMEOUT_US, &|| rcc.cr.read().hsirdy().bit_is_set());
+    rcc.cfgr
+        .modify(|_, w| unsafe { w.ppre().bits(0b000).sw().bits(0b000) }); // HSISYS
+    wait_until(SWITCH_TIMEOUT_US, &|| rcc.cfgr.read().sws().bits() == 0b000);
+    rcc.pllsyscfgr.modify(|_, w| w.pllren().clear_bit());
+    rcc.cr
+        .modify(|_, w| w.pllon().clear_bit().hseon().clear_bit());
+}
+
+/// Last resort for the panic and fault handlers: bridge off by stealing the
+/// registers from `DcPwm`, which the failing code may be in the middle of using.
+pub fn emergency_stop() {
+    let perip = unsafe { Peripherals::steal() };
+    // TIM1 outputs to their idle level, then the pins themselves to GPIO low
+    perip.TIM1.bdtr.modify(|_, w| w.moe().clear_bit());
+    perip.GPIOA.bsrr.write(|w| w.br8().reset());
+    perip
+        .GPIOB
+        .bsrr
+        .write(|w| w.br3().reset().br13().reset().br14().reset());
+    perip.GPIOA.moder.modify(|_, w| w.moder8().output());
+    perip
+        .GPIOB
+        .moder
+        .modify(|_, w| w.moder3().output().moder13().output().moder14().output());
+}
+
+/// Led0 on, `code` blinks of Led1 three times over, then reset.
+pub fn fault_blink_and_reset(code: u32) -> ! {
+    let cycles_per_ms = clocks().hclk_hz / 1_000;
+    let perip = unsafe { Peripherals::steal() };
+    perip.RCC.iopenr.modify(|_, w| w.iopaen().set_bit());
+    let gpioa = &perip.GPIOA;
+    gpioa
+        .moder
+        .modify(|_, w| w.moder4().output().moder5().output());
+    gpioa.bsrr.write(|w| w.br4().reset().bs5().set());
+    // keep a running IWDG from cutting the pattern short, the reset is ours
+    let pause = |ms: u32| {
+        for _ in 0..ms / 10 {
+            perip.IWDG.kr.write(|w| w.key().reset());
+            cortex_m::asm::delay(10 * cycles_per_ms);
+        }
+    };
+    for _ in 0..3 {
+        for _ in 0..code {
+            gpioa.bsrr.write(|w| w.br5().reset());
+            pause(200);
+            gpioa.bsrr.write(|w| w.bs5().set());
+            pause(200);
         }
+        pause(1000);
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Reads and clears the reset flags in RCC_CSR.
+pub fn reset_cause() -> ResetCause {
+    let rcc = rcc();
+    let csr = rcc.csr.read();
+    // PINRSTF is set along with every other cause, so it comes last
+    let cause = if csr.iwdgrstf().bit_is_set() {
+        ResetCause::IndependentWatchdog
+    } else if csr.wwdgrstf().bit_is_set() {
+        ResetCause::WindowWatchdog
+    } else if csr.lpwrrstf().bit_is_set() {
+        ResetCause::LowPower
+    } else if csr.sftrstf().bit_is_set() {
+        ResetCause::Software
+    } else if csr.pwrrstf().bit_is_set() {
+        ResetCause::BrownOut
+    } else if csr.oblrstf().bit_is_set() {
+        ResetCause::OptionByteLoad
+    } else if csr.pinrstf().bit_is_set() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    };
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+    cause
+}
+
+/// LSI start-up plus the 5 LSI cycles a prescaler/reload update takes.
+const IWDG_TIMEOUT_US: u32 = 2_000;
+
+pub struct Iwdg {
+    iwdg: IWDG,
+    dbg: DBG,
+}
+impl Iwdg {
+    pub fn new(iwdg: IWDG, dbg: DBG) -> Self {
+        Self { iwdg, dbg }
+    }
+}
+
+impl Watchdog for Iwdg {
+    fn start(&self, timeout_ms: u32) {
+        // デバッガで停止中は止めておく
+        free(|_| rcc().apbenr1.modify(|_, w| w.dbgen().set_bit()));
+        self.dbg.apb_fz1.modify(|_, w| w.dbg_iwdg_stop().set_bit());
+
+        // LSI 32kHz, smallest prescaler (/4 .. /256) that fits the 12bit reload
+        let ticks = timeout_ms * 32;
+        let mut pr = 0;
+        while pr < 6 && ticks / (4 << pr) > 0x1000 {
+            pr += 1;
+        }
+        let reload = (ticks / (4 << pr)).clamp(1, 0x1000) - 1;
+
+        let iwdg = &self.iwdg;
+        iwdg.kr.write(|w| w.key().start()); // also starts LSI
+        iwdg.kr.write(|w| w.key().enable());
+        iwdg.pr.write(|w| w.pr().bits(pr as u8));
+        iwdg.rlr.write(|w| w.rl().bits(reload as u16));
+        if !wait_until(IWDG_TIMEOUT_US, &|| iwdg.sr.read().bits() == 0) {
+            // still counts down, with the reset value's 512ms
+            defmt::warn!("IWDG timeout not applied");
+        }
+        iwdg.kr.write(|w| w.key().reset());
+    }
+    fn feed(&self) {
+        self.iwdg.kr.write(|w| w.key().reset());
+    }
+}
+
+extern "C" {
+    /// First of the pages reserved as `CONFIG` in memory.x.
+    static _config_start: u32;
+}
+
+const FLASH_BASE: usize = 0x0800_0000;
+
+/// The flash pages behind `config_store`.
+pub struct ConfigFlash {
+    flash: FLASH,
+    base: usize,
+}
+impl ConfigFlash {
+    pub fn new(flash: FLASH) -> Self {
+        Self {
+            flash,
+            base: unsafe { &_config_start as *const u32 as usize },
+        }
+    }
+}
+
+/// Page erase takes up to 40ms, a double word up to 125us.
+const FLASH_ERASE_TIMEOUT_US: u32 = 50_000;
+const FLASH_PROGRAM_TIMEOUT_US: u32 = 1_000;
+
+fn flash_unlock(flash: &FLASH) -> Result<(), FlashError> {
+    if !wait_until(FLASH_ERASE_TIMEOUT_US, &|| {
+        flash.sr.read().bsy().bit_is_clear()
+    }) {
+        return Err(FlashError::Timeout);
+    }
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.keyr().bits(0x4567_0123) });
+        flash.keyr.write(|w| unsafe { w.keyr().bits(0xCDEF_89AB) });
+    }
+    // errors left over from an earlier operation block the next one
+    flash.sr.write(|w| {
+        w.eop()
+            .set_bit()
+            .progerr()
+            .set_bit()
+            .wrperr()
+            .set_bit()
+            .pgaerr()
+            .set_bit()
+            .sizerr()
+            .set_bit()
+            .pgserr()
+            .set_bit()
+            .miserr()
+            .set_bit()
+            .fasterr()
+            .set_bit()
     });
+    Ok(())
 }
 
-pub struct EncoderPeripheral {}
-impl<'a> EncoderPeripheral {
-    pub fn new() -> Self {
-        Self {}
-    }
-    pub fn init(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                // GPIOポートの電源投入(クロックの有効化)
-                perip.RCC.iopenr.modify(|_, w| w.iopaen().set_bit());
-
-                let gpioa = &perip.GPIOA;
-                // PWM pin
-                gpioa.moder.modify(|_, w| w.moder6().alternate());
-                gpioa.moder.modify(|_, w| w.moder7().alternate());
-                gpioa.afrl.modify(|_, w| w.afsel6().af1()); // TIM3 CH1
-                gpioa.afrl.modify(|_, w| w.afsel7().af1()); // TIM3 CH2
-                gpioa.ospeedr.modify(|_, w| w.ospeedr6().very_high_speed());
-                gpioa.ospeedr.modify(|_, w| w.ospeedr7().very_high_speed());
-
-                perip.RCC.apbenr1.modify(|_, w| w.tim3en().set_bit());
-
-                // For PWM
-                let tim = &perip.TIM3;
-                // tim.psc.modify(|_, w| unsafe { w.bits(7 - 1) });
-                // tim.arr.modify(|_, w| unsafe { w.bits(800 - 1) }); // 25kHz
-
-                // 1. Select the proper TI1x source.
-                tim.tisel.modify(|_, w| unsafe { w.ti1sel().bits(0b0000) });
-                tim.tisel.modify(|_, w| unsafe { w.ti2sel().bits(0b0000) });
-
-                // 2. Select the active input: TIMx_CCR1 must be linked to the TI1 input, 
-                // the channel is configured in input and the TIMx_CCR1 register becomes read-only.
-                tim.ccmr1_input().modify(|_, w| unsafe { w.cc1s().bits(0b01) });
-                tim.ccmr1_input().modify(|_, w| unsafe { w.cc2s().bits(0b01) });
-
-                // 3. Program the appropriate input filter duration in relation with the signal connected to the
-                // timer. IC1F bits in the TIMx_CCMR1 register.
-                // no filter
-
-                // 4. Select the edge of the active transition on the TIx channel 
-                // by writing the CC1P and CC1NP bit in the TIMx_CCER register.
-                tim.ccer.modify(|_, w| w.cc1p().bit(false) );
-                tim.ccer.modify(|_, w| w.cc1np().bit(false) );
-                tim.ccer.modify(|_, w| w.cc2p().bit(false) );
-                tim.ccer.modify(|_, w| w.cc2np().bit(false) );
-
-                // 5. Program the input prescaler. In our example, we wish the capture to be performed at
-                // each valid transition, so the prescaler is disabled (write IC1PS bits to 00 in the
-                // TIMx_CCMR1 register).
-                tim.ccmr1_input().modify(|_, w| unsafe { w.ic1psc().bits(0b00) });
-                tim.ccmr1_input().modify(|_, w| unsafe { w.ic2psc().bits(0b00) });
-
-                // 6. Enable capture from the counter into the capture register by setting the CC1E bit in the
-                // TIMx_CCER register.
-                // CCxE enable output
-                tim.ccer.modify(|_, w| w.cc1e().set_bit());
-                tim.ccer.modify(|_, w| w.cc2e().set_bit());
-
-                // 7. If needed, enable the related interrupt request by setting the CC1IE bit in the
-                // TIMx_DIER register, and/or the DMA request by setting the CC1DE bit in the
-                // TIMx_DIER register
-                // no interrupt
-
-                // • SMS= 011 (TIMx_SMCR register, both inputs are active on both rising and falling
-                // edges)
-                tim.smcr.modify(|_, w| unsafe { w.sms().bits(0b0011) });
-
-
-                // OCxM mode
-                // tim.ccmr1_output().modify(|_, w| w.oc1m().pwm_mode1());
-                // tim.ccmr1_output().modify(|_, w| w.oc2m().pwm_mode1());
-                // CCRx
-                // tim.ccr1.modify(|_, w| unsafe { w.ccr1().bits(0) }); // x/800
-                // tim.ccr2.modify(|_, w| unsafe { w.ccr2().bits(0) }); // x/800
-
-                // Set polarity
-                // tim.ccer.modify(|_, w| w.cc1p().clear_bit());
-                // tim.ccer.modify(|_, w| w.cc2p().clear_bit());
-                // PWM mode
-                // tim.cr1.modify(|_, w| unsafe { w.cms().bits(0b00) }); 
-
-                // enable tim
-                tim.cr1.modify(|_, w| w.cen().set_bit());
-                // Main output enable
-                // tim.bdtr.modify(|_, w| w.moe().set_bit());
-            }
-        });
+fn flash_wait(flash: &FLASH, us: u32) -> Result<(), FlashError> {
+    if !wait_until(us, &|| flash.sr.read().bsy().bit_is_clear()) {
+        return Err(FlashError::Timeout);
+    }
+    let sr = flash.sr.read();
+    if sr.wrperr().bit_is_set() {
+        Err(FlashError::Protected)
+    } else if sr.progerr().bit_is_set()
+        || sr.pgaerr().bit_is_set()
+        || sr.sizerr().bit_is_set()
+        || sr.pgserr().bit_is_set()
+        || sr.miserr().bit_is_set()
+    {
+        Err(FlashError::Program)
+    } else {
+        Ok(())
     }
 }
 
+impl Flash for ConfigFlash {
+    const PAGE_SIZE: usize = 2048;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe { core::ptr::read_volatile((self.base + offset + i) as *const u8) };
+        }
+    }
 
-pub struct DcPwm {}
-impl<'a> DcPwm {
-    pub fn new() -> Self {
-        Self {}
-    }
-    pub fn init(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                // GPIOポートの電源投入(クロックの有効化)
-                perip.RCC.iopenr.modify(|_, w| w.iopaen().set_bit());
-                perip.RCC.iopenr.modify(|_, w| w.iopben().set_bit());
-
-                let gpioa = &perip.GPIOA;
-                let gpiob = &perip.GPIOB;
-                // PWM pin
-                gpioa.moder.modify(|_, w| w.moder8().alternate());
-                gpiob.moder.modify(|_, w| w.moder3().alternate());
-                gpioa.afrh.modify(|_, w| w.afsel8().af2()); // TIM1 CH1
-                gpiob.afrl.modify(|_, w| w.afsel3().af1()); // TIM1 CH2
-                gpioa.ospeedr.modify(|_, w| w.ospeedr8().very_high_speed());
-                gpiob.ospeedr.modify(|_, w| w.ospeedr3().very_high_speed());
-
-                perip.RCC.apbenr2.modify(|_, w| w.tim1en().set_bit());
-
-                // For PWM
-                let tim = &perip.TIM1;
-                tim.psc.modify(|_, w| unsafe { w.bits(7 - 1) });
-                tim.arr.modify(|_, w| unsafe { w.bits(800 - 1) }); // 25kHz
-
-                // OCxM mode
-                tim.ccmr1_output().modify(|_, w| w.oc1m().pwm_mode1());
-                tim.ccmr1_output().modify(|_, w| w.oc2m().pwm_mode1());
-                // CCRx
-                tim.ccr1.modify(|_, w| unsafe { w.ccr1().bits(0) }); // x/800
-                tim.ccr2.modify(|_, w| unsafe { w.ccr2().bits(0) }); // x/800
-
-                // Set polarity
-                // tim.ccer.modify(|_, w| w.cc1p().clear_bit());
-                // tim.ccer.modify(|_, w| w.cc2p().clear_bit());
-                // PWM mode
-                // tim.cr1.modify(|_, w| unsafe { w.cms().bits(0b00) }); 
-
-                // enable tim
-                tim.cr1.modify(|_, w| w.cen().set_bit());
-                // Main output enable
-                tim.bdtr.modify(|_, w| w.moe().set_bit());
-                // CCxE enable output
-                tim.ccer.modify(|_, w| w.cc1e().set_bit());
-                tim.ccer.modify(|_, w| w.cc2e().set_bit());
+    // CPU stalls on instruction fetch while the flash is busy, ~40ms for an erase.
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        let flash = &self.flash;
+        let pnb = ((self.base - FLASH_BASE) / Self::PAGE_SIZE + page) as u8;
+        flash_unlock(flash)?;
+        flash
+            .cr
+            .modify(|_, w| unsafe { w.per().set_bit().pnb().bits(pnb) });
+        flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = flash_wait(flash, FLASH_ERASE_TIMEOUT_US);
+        flash.cr.modify(|_, w| w.per().clear_bit().lock().set_bit());
+        result
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        let flash = &self.flash;
+        flash_unlock(flash)?;
+        flash.cr.modify(|_, w| w.pg().set_bit());
+        let mut result = Ok(());
+        for (i, word) in data.chunks_exact(8).enumerate() {
+            let address = (self.base + offset + i * 8) as *mut u32;
+            // one double word, low word first
+            unsafe {
+                core::ptr::write_volatile(
+                    address,
+                    u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+                );
+                core::ptr::write_volatile(
+                    address.add(1),
+                    u32::from_le_bytes([word[4], word[5], word[6], word[7]]),
+                );
             }
-        });
+            result = flash_wait(flash, FLASH_PROGRAM_TIMEOUT_US);
+            if result.is_err() {
+                break;
+            }
+        }
+        flash.cr.modify(|_, w| w.pg().clear_bit().lock().set_bit());
+        result
     }
 }
 
-impl DcMotorDriver for DcPwm {
-    fn enable(&self) {}
-    fn disable(&self) {}
-    /// 0~1
-    fn set_pwm(&self, direction: f32, value: f32) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let tim = &perip.TIM1;
-                if direction >= 0.0 {
-                    tim.ccr1
-                        .modify(|_, w| unsafe { w.ccr1().bits((value * 800.) as u16) }); // x/800
-                    tim.ccr2.modify(|_, w| unsafe { w.ccr2().bits(0) });
-                } else {
-                    tim.ccr2
-                        .modify(|_, w| unsafe { w.ccr2().bits((value * 800.) as u16) }); // x/800
-                    tim.ccr1.modify(|_, w| unsafe { w.ccr1().bits(0) });
-                }
-            }
+static G_MICROS_HIGH: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// TIM17 is set up by `clock_init` and never handed out, only these two touch it
+pub fn micros_interrupt_task() {
+    let tim = unsafe { &*TIM17::ptr() };
+    tim.sr.modify(|_, w| w.uif().clear_bit());
+    free(|cs| {
+        let high = G_MICROS_HIGH.borrow(cs);
+        high.set(high.get().wrapping_add(0x1_0000));
+    });
+}
+
+/// Microseconds since boot, wrapping every ~71 minutes.
+pub fn micros() -> u32 {
+    let tim = unsafe { &*TIM17::ptr() };
+    free(|cs| {
+        let mut high = G_MICROS_HIGH.borrow(cs).get();
+        let mut cnt = tim.cnt.read().cnt().bits();
+        if tim.sr.read().uif().bit_is_set() {
+            // overflowed but the interrupt has not run yet
+            cnt = tim.cnt.read().cnt().bits();
+            high = high.wrapping_add(0x1_0000);
+        }
+        high | cnt as u32
+    })
+}
+
+/// Milliseconds counted by the TIM16 update interrupt.
+static G_TICKS: AtomicU32 = AtomicU32::new(0);
+
+// TIM16 is set up by `clock_init` and owned by `Tick`, this only clears the flag
+pub fn tick_interrupt_task() {
+    let tim = unsafe { &*TIM16::ptr() };
+    tim.sr.modify(|_, w| w.uif().clear_bit());
+    G_TICKS.store(
+        G_TICKS.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// 1kHz tick from the TIM16 update interrupt, which runs the control task.
+pub struct Tick {
+    _tim: TIM16,
+}
+impl Tick {
+    pub fn new(tim: TIM16) -> Self {
+        tim.dier.modify(|_, w| w.uie().set_bit());
+        unsafe { NVIC::unmask(Interrupt::TIM16) };
+        Self { _tim: tim }
+    }
+    pub fn count(&self) -> u32 {
+        G_TICKS.load(Ordering::Relaxed)
+    }
+}
+
+/// Data shared by the control tick (TIM16) and the current loop
+/// (DMA_CHANNEL1).
+///
+/// Both handlers run at `CONTROL_PRIORITY` and never preempt each other, so
+/// they use it as it is. Thread mode masks only these two lines while it holds
+/// it; the timebase, encoder and USART interrupts keep running.
+///
+/// Empty until `init`. The value is not wrapped in an `Option`: `None` of a
+/// large `T` puts the whole cell into `.data`, a flash-resident copy of it.
+pub struct ControlCell<T> {
+    inner: UnsafeCell<MaybeUninit<T>>,
+    ready: AtomicBool,
+}
+
+unsafe impl<T: Send> Sync for ControlCell<T> {}
+
+impl<T> ControlCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// From thread mode only, once.
+    pub fn init(&self, value: T) {
+        self.with_masked(|| {
+            debug_assert!(!self.ready.load(Ordering::Relaxed));
+            unsafe { (*self.inner.get()).write(value) };
+            self.ready.store(true, Ordering::Relaxed);
         });
     }
+
+    /// From thread mode only. None before `init`.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.with_masked(|| unsafe { self.with(f) })
+    }
+
+    /// # Safety
+    ///
+    /// Only from the TIM16 and DMA_CHANNEL1 handlers, at `CONTROL_PRIORITY`.
+    pub unsafe fn borrow_from_interrupt<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.with(f)
+    }
+
+    // the `&mut T` never outlives `f`, callers keep the other side out
+    unsafe fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        if self.ready.load(Ordering::Relaxed) {
+            Some(f((*self.inner.get()).assume_init_mut()))
+        } else {
+            None
+        }
+    }
+
+    fn with_masked<R>(&self, f: impl FnOnce() -> R) -> R {
+        debug_assert!(SCB::vect_active() == VectActive::ThreadMode);
+        let tick = NVIC::is_enabled(Interrupt::TIM16);
+        let adc = NVIC::is_enabled(Interrupt::DMA_CHANNEL1);
+        NVIC::mask(Interrupt::TIM16);
+        NVIC::mask(Interrupt::DMA_CHANNEL1);
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+        let result = f();
+        unsafe {
+            if tick {
+                NVIC::unmask(Interrupt::TIM16);
+            }
+            if adc {
+                NVIC::unmask(Interrupt::DMA_CHANNEL1);
+            }
+        }
+        result
+    }
 }
 
+const RX_BUFFER_LEN: usize = 256;
 
-pub struct Led0 {}
+struct RxBuffer {
+    buf: [u8; RX_BUFFER_LEN],
+    head: usize,
+    tail: usize,
+}
 
-impl Indicator for Led0 {
-    fn on(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let gpioa = &perip.GPIOA;
-                gpioa.bsrr.write(|w| w.br4().reset());
+impl RxBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RX_BUFFER_LEN],
+            head: 0,
+            tail: 0,
+        }
+    }
+    fn push(&mut self, byte: u8) {
+        let next = (self.head + 1) % RX_BUFFER_LEN;
+        // drop the byte when full, the protocol CRC catches it
+        if next != self.tail {
+            self.buf[self.head] = byte;
+            self.head = next;
+        }
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.head == self.tail {
+            None
+        } else {
+            let byte = self.buf[self.tail];
+            self.tail = (self.tail + 1) % RX_BUFFER_LEN;
+            Some(byte)
+        }
+    }
+    fn clear(&mut self) {
+        self.tail = self.head;
+    }
+}
+
+static G_RX_BUFFER: Mutex<RefCell<RxBuffer>> = Mutex::new(RefCell::new(RxBuffer::new()));
+
+const TX_BUFFER_LEN: usize = 256;
+
+/// Reply being sent from the USART interrupt.
+struct TxBuffer {
+    buf: [u8; TX_BUFFER_LEN],
+    len: usize,
+    pos: usize,
+    /// Until the last stop bit has left, not just the last byte
+    busy: bool,
+    started_us: u32,
+    /// Time the frame needs on the wire, with margin
+    budget_us: u32,
+}
+
+impl TxBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; TX_BUFFER_LEN],
+            len: 0,
+            pos: 0,
+            busy: false,
+            started_us: 0,
+            budget_us: 0,
+        }
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.pos < self.len {
+            self.pos += 1;
+            Some(self.buf[self.pos - 1])
+        } else {
+            None
+        }
+    }
+}
+
+static G_TX_BUFFER: Mutex<RefCell<TxBuffer>> = Mutex::new(RefCell::new(TxBuffer::new()));
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UsartPort {
+    /// TX: PB6, DE: PA12. PB6 shares its TSSOP20 pad with the PWM on PB3.
+    #[cfg(feature = "usart1")]
+    Usart1,
+    /// TX: PA2, DE: PA1
+    Usart2,
+}
+
+// USART1 and USART2 share the register layout. The peripherals belong to
+// `HalfDuplexUsart`, only its interrupt and the clock failure NMI come here
+fn usart_regs(port: UsartPort) -> &'static usart1::RegisterBlock {
+    match port {
+        #[cfg(feature = "usart1")]
+        UsartPort::Usart1 => unsafe { &*USART1::ptr() },
+        UsartPort::Usart2 => unsafe { &*USART2::ptr() },
+    }
+}
+
+pub fn usart_interrupt_task(port: UsartPort) {
+    let usart = usart_regs(port);
+    let isr = usart.isr.read();
+    if isr.ore().bit_is_set() || isr.fe().bit_is_set() || isr.nf().bit_is_set() {
+        usart
+            .icr
+            .write(|w| w.orecf().set_bit().fecf().set_bit().ncf().set_bit());
+    }
+    if isr.rxne().bit_is_set() {
+        let byte = usart.rdr.read().bits() as u8;
+        free(|cs| G_RX_BUFFER.borrow(cs).borrow_mut().push(byte));
+    }
+    let cr1 = usart.cr1.read();
+    if cr1.txeie().bit_is_set() && isr.txe().bit_is_set() {
+        match free(|cs| G_TX_BUFFER.borrow(cs).borrow_mut().pop()) {
+            Some(byte) => usart.tdr.write(|w| unsafe { w.bits(byte as u32) }),
+            // the last byte is in the shift register
+            None => usart
+                .cr1
+                .modify(|_, w| w.txeie().clear_bit().tcie().set_bit()),
+        }
+    }
+    if cr1.tcie().bit_is_set() && isr.tc().bit_is_set() {
+        usart.icr.write(|w| w.tccf().set_bit());
+        usart.cr1.modify(|_, w| w.tcie().clear_bit().re().set_bit());
+        free(|cs| G_TX_BUFFER.borrow(cs).borrow_mut().busy = false);
+    }
+}
+
+enum UsartHandle {
+    #[cfg(feature = "usart1")]
+    Usart1(USART1, PB6<Alternate<0>>, PA12<Alternate<1>>),
+    #[cfg(not(feature = "usart1"))]
+    Usart2(USART2, PA2<Alternate<1>>, PA1<Alternate<1>>),
+}
+
+/// Single-wire half-duplex USART for the Dynamixel bus.
+/// The transceiver direction is driven by the USART's own DE output.
+pub struct HalfDuplexUsart {
+    usart: UsartHandle,
+    baud_rate: Cell<u32>,
+}
+
+impl HalfDuplexUsart {
+    #[cfg(feature = "usart1")]
+    pub fn usart1(usart: USART1, tx: PB6, de: PA12) -> Result<Self, InitError> {
+        let tx = tx.into_alternate::<0>()?; // USART1 TX
+        let de = de.into_alternate::<1>()?; // USART1 DE
+        free(|_| rcc().apbenr2.modify(|_, w| w.usart1en().set_bit()));
+        Ok(Self {
+            usart: UsartHandle::Usart1(usart, tx, de),
+            baud_rate: Cell::new(0),
+        })
+    }
+
+    #[cfg(not(feature = "usart1"))]
+    pub fn usart2(usart: USART2, tx: PA2, de: PA1) -> Result<Self, InitError> {
+        let tx = tx.into_alternate::<1>()?; // USART2 TX
+        let de = de.into_alternate::<1>()?; // USART2 DE
+        free(|_| rcc().apbenr1.modify(|_, w| w.usart2en().set_bit()));
+        Ok(Self {
+            usart: UsartHandle::Usart2(usart, tx, de),
+            baud_rate: Cell::new(0),
+        })
+    }
+
+    fn regs(&self) -> &usart1::RegisterBlock {
+        match &self.usart {
+            #[cfg(feature = "usart1")]
+            UsartHandle::Usart1(usart, ..) => usart,
+            #[cfg(not(feature = "usart1"))]
+            UsartHandle::Usart2(usart, ..) => usart,
+        }
+    }
+
+    /// Call only while not `busy`, a reply being sent would be cut off.
+    pub fn init(&self, baud_rate: u32) {
+        self.baud_rate.set(baud_rate);
+        let usart = self.regs();
+        // CR2, CR3 and BRR can only be written while UE is cleared
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart
+            .brr
+            .write(|w| unsafe { w.bits(clocks().pclk_hz / baud_rate) }); // oversampling 16
+        usart.cr3.modify(|_, w| w.hdsel().set_bit());
+        // DE active high, asserted/deasserted for one sample time around the frame
+        usart.cr3.modify(|_, w| w.dem().set_bit().dep().clear_bit());
+        usart.cr1.modify(|_, w| w.deat().bits(1).dedt().bits(1));
+        usart.cr1.modify(|_, w| w.rxneie().set_bit());
+        usart.cr1.modify(|_, w| w.te().set_bit().re().set_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+
+        free(|cs| G_RX_BUFFER.borrow(cs).borrow_mut().clear());
+        unsafe {
+            match self.usart {
+                #[cfg(feature = "usart1")]
+                UsartHandle::Usart1(..) => NVIC::unmask(Interrupt::USART1),
+                #[cfg(not(feature = "usart1"))]
+                UsartHandle::Usart2(..) => NVIC::unmask(Interrupt::USART2),
             }
+        }
+    }
+
+    pub fn read(&self) -> Option<u8> {
+        free(|cs| G_RX_BUFFER.borrow(cs).borrow_mut().pop())
+    }
+
+    /// Starts sending `data` from the interrupt and returns at once. The
+    /// receiver is switched off until the last stop bit has left, so our own
+    /// bytes are not echoed back into the receive buffer.
+    pub fn write(&self, data: &[u8]) {
+        let usart = self.regs();
+        let len = data.len().min(TX_BUFFER_LEN);
+        // 10 bits per byte, twice that before `stalled` gives up on it
+        let budget_us = (len as u32 * 10_000_000 / self.baud_rate.get().max(1)) * 2 + 1000;
+        let now = micros();
+        free(|cs| {
+            let mut tx = G_TX_BUFFER.borrow(cs).borrow_mut();
+            tx.buf[..len].copy_from_slice(&data[..len]);
+            tx.len = len;
+            tx.pos = 0;
+            tx.busy = true;
+            tx.started_us = now;
+            tx.budget_us = budget_us;
+            usart
+                .cr1
+                .modify(|_, w| w.re().clear_bit().txeie().set_bit());
         });
     }
-    fn off(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let gpioa = &perip.GPIOA;
-                gpioa.bsrr.write(|w| w.bs4().set());
-            }
+
+    /// A reply is still going out.
+    pub fn busy(&self) -> bool {
+        free(|cs| G_TX_BUFFER.borrow(cs).borrow().busy)
+    }
+
+    /// The reply has been going out for much longer than the baud rate allows.
+    pub fn stalled(&self) -> bool {
+        let now = micros();
+        free(|cs| {
+            let tx = G_TX_BUFFER.borrow(cs).borrow();
+            tx.busy && now.wrapping_sub(tx.started_us) > tx.budget_us
+        })
+    }
+}
+
+/// Raw counter and time of the first TIM3 CH1 edge after `arm_edge_capture`.
+static G_ENCODER_EDGE: Mutex<Cell<Option<(u16, u32)>>> = Mutex::new(Cell::new(None));
+
+/// TIM17 count at the last TIM3 CH1 capture, written by DMA1 channel 2.
+static mut ENCODER_EDGE_TICKS: u32 = 0;
+
+pub fn encoder_interrupt_task() {
+    // TIM3 belongs to `EncoderPeripheral`, which leaves CC1IE, CC1DE and CCR1 to this
+    let tim = unsafe { &*TIM3::ptr() };
+    if tim.sr.read().cc1if().bit_is_set() {
+        // one capture per control period is enough, rearmed from `sample`.
+        // stop the DMA first so the time and CCR1 belong to the same edge
+        tim.dier
+            .modify(|_, w| w.cc1ie().clear_bit().cc1de().clear_bit());
+        let ticks = unsafe { core::ptr::read_volatile(core::ptr::addr_of!(ENCODER_EDGE_TICKS)) };
+        // reading CCR1 clears CC1IF
+        let raw = tim.ccr1.read().ccr1_l().bits();
+        // the edge is less than one TIM17 period old, extend its low 16 bits with `micros`
+        let now = micros();
+        let time_us = now.wrapping_sub((now as u16).wrapping_sub(ticks as u16) as u32);
+        free(|cs| G_ENCODER_EDGE.borrow(cs).set(Some((raw, time_us))));
+    }
+}
+
+pub struct EncoderPeripheral {
+    tim: TIM3,
+    _pins: (PA6<Alternate<1>>, PA7<Alternate<1>>),
+    counter: MultiTurnCounter,
+}
+impl EncoderPeripheral {
+    /// TIM3 in encoder mode on CH1 (PA6) and CH2 (PA7).
+    ///
+    /// Edges are timestamped by DMA1 channel 2 copying the TIM17 count on the CH1
+    /// capture, so interrupt latency does not show up in the velocity. Channel 1
+    /// stays with `AdcPeripheral`.
+    pub fn new(
+        tim: TIM3,
+        ch1: PA6,
+        ch2: PA7,
+        dma: &DMA,
+        dmamux: &DMAMUX,
+    ) -> Result<Self, InitError> {
+        let pins = (ch1.into_alternate()?, ch2.into_alternate()?); // TIM3 CH1, CH2
+        free(|_| rcc().apbenr1.modify(|_, w| w.tim3en().set_bit()));
+        // tim.psc.modify(|_, w| unsafe { w.bits(7 - 1) });
+        // tim.arr.modify(|_, w| unsafe { w.bits(800 - 1) }); // 25kHz
+
+        // 1. Select the proper TI1x source.
+        tim.tisel.modify(|_, w| unsafe { w.ti1sel().bits(0b0000) });
+        tim.tisel.modify(|_, w| unsafe { w.ti2sel().bits(0b0000) });
+
+        // 2. Select the active input: TIMx_CCR1 must be linked to the TI1 input,
+        // the channel is configured in input and the TIMx_CCR1 register becomes read-only.
+        tim.ccmr1_input()
+            .modify(|_, w| unsafe { w.cc1s().bits(0b01) });
+        tim.ccmr1_input()
+            .modify(|_, w| unsafe { w.cc2s().bits(0b01) });
+
+        // 3. Program the appropriate input filter duration in relation with the signal connected to the
+        // timer. IC1F bits in the TIMx_CCMR1 register.
+        // no filter
+
+        // 4. Select the edge of the active transition on the TIx channel
+        // by writing the CC1P and CC1NP bit in the TIMx_CCER register.
+        tim.ccer.modify(|_, w| w.cc1p().bit(false));
+        tim.ccer.modify(|_, w| w.cc1np().bit(false));
+        tim.ccer.modify(|_, w| w.cc2p().bit(false));
+        tim.ccer.modify(|_, w| w.cc2np().bit(false));
+
+        // 5. Program the input prescaler. In our example, we wish the capture to be performed at
+        // each valid transition, so the prescaler is disabled (write IC1PS bits to 00 in the
+        // TIMx_CCMR1 register).
+        tim.ccmr1_input()
+            .modify(|_, w| unsafe { w.ic1psc().bits(0b00) });
+        tim.ccmr1_input()
+            .modify(|_, w| unsafe { w.ic2psc().bits(0b00) });
+
+        // 6. Enable capture from the counter into the capture register by setting the CC1E bit in the
+        // TIMx_CCER register.
+        // CCxE enable output
+        tim.ccer.modify(|_, w| w.cc1e().set_bit());
+        tim.ccer.modify(|_, w| w.cc2e().set_bit());
+
+        // 7. If needed, enable the related interrupt request by setting the CC1IE bit in the
+        // TIMx_DIER register, and/or the DMA request by setting the CC1DE bit in the
+        // TIMx_DIER register
+        // CC1IE and CC1DE are set from `arm_edge_capture`
+        free(|_| rcc().ahbenr.modify(|_, w| w.dmaen().set_bit()));
+        dmamux.c1cr.modify(|_, w| unsafe { w.dmareq_id().bits(32) }); // TIM3_CH1
+                                                                      // TIM17 is only read here, see `micros`
+        let tim17_cnt = unsafe { &*TIM17::ptr() }.cnt.as_ptr() as u32;
+        dma.ch2.par.write(|w| unsafe { w.bits(tim17_cnt) });
+        dma.ch2
+            .mar
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(ENCODER_EDGE_TICKS) as u32) });
+        dma.ch2.ndtr.write(|w| unsafe { w.bits(1) });
+        dma.ch2
+            .cr
+            .modify(|_, w| unsafe { w.msize().bits(0b10).psize().bits(0b10).circ().set_bit() });
+        dma.ch2.cr.modify(|_, w| w.en().set_bit());
+
+        // • SMS= 011 (TIMx_SMCR register, both inputs are active on both rising and falling
+        // edges)
+        tim.smcr.modify(|_, w| unsafe { w.sms().bits(0b0011) });
+
+        // OCxM mode
+        // tim.ccmr1_output().modify(|_, w| w.oc1m().pwm_mode1());
+        // tim.ccmr1_output().modify(|_, w| w.oc2m().pwm_mode1());
+        // CCRx
+        // tim.ccr1.modify(|_, w| unsafe { w.ccr1().bits(0) }); // x/800
+        // tim.ccr2.modify(|_, w| unsafe { w.ccr2().bits(0) }); // x/800
+
+        // Set polarity
+        // tim.ccer.modify(|_, w| w.cc1p().clear_bit());
+        // tim.ccer.modify(|_, w| w.cc2p().clear_bit());
+        // PWM mode
+        // tim.cr1.modify(|_, w| unsafe { w.cms().bits(0b00) });
+
+        tim.arr.write(|w| unsafe { w.bits(0xFFFF) });
+
+        // enable tim
+        tim.cr1.modify(|_, w| w.cen().set_bit());
+        // Main output enable
+        // tim.bdtr.modify(|_, w| w.moe().set_bit());
+        let raw = tim.cnt.read().cnt_l().bits();
+        Ok(Self {
+            tim,
+            _pins: pins,
+            counter: MultiTurnCounter::new(raw),
+        })
+    }
+
+    fn arm_edge_capture(&self) {
+        // DIER is shared with the capture interrupt
+        free(|_| {
+            self.tim.sr.modify(|_, w| w.cc1if().clear_bit());
+            self.tim
+                .dier
+                .modify(|_, w| w.cc1ie().set_bit().cc1de().set_bit());
         });
     }
-    fn toggle(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let gpioa = &perip.GPIOA;
-                if gpioa.odr.read().odr4().is_low() {
-                    gpioa.bsrr.write(|w| w.bs4().set());
-                } else {
-                    gpioa.bsrr.write(|w| w.br4().reset());
-                }
-            }
+
+    fn read_raw(&self) -> u16 {
+        self.tim.cnt.read().cnt_l().bits()
+    }
+}
+
+impl Encoder for EncoderPeripheral {
+    fn position(&mut self) -> i64 {
+        let raw = self.read_raw();
+        self.counter.update(raw)
+    }
+    fn delta(&mut self) -> i32 {
+        self.position();
+        self.counter.take_delta()
+    }
+    fn direction(&self) -> Direction {
+        self.counter.direction()
+    }
+    fn preset(&mut self, position: i64) {
+        let raw = self.read_raw();
+        self.counter.update(raw);
+        self.counter.preset(position);
+    }
+    fn sample(&mut self) -> Sample {
+        let time_us = micros();
+        let position = self.position();
+        let edge = free(|cs| G_ENCODER_EDGE.borrow(cs).take()).map(|(raw, time_us)| Edge {
+            time_us,
+            position: self.counter.extend(raw),
         });
+        self.arm_edge_capture();
+        Sample {
+            time_us,
+            position,
+            edge,
+        }
     }
 }
 
-impl Led0 {
-    pub fn new() -> Self {
-        Self {}
+/// Index into the ADC buffer, channels are converted in ascending channel number.
+pub mod adc_channel {
+    /// PA0 ADC_IN0, shunt amplifier
+    pub const CURRENT: usize = 0;
+    /// PA3 ADC_IN3, motor supply divider
+    pub const SUPPLY: usize = 1;
+    /// PB7 ADC_IN11, external NTC
+    pub const NTC: usize = 2;
+    /// ADC_IN12, internal temperature sensor
+    pub const TEMPERATURE: usize = 3;
+}
+const ADC_CHANNELS: usize = 4;
+/// Calibration takes about 100us, ready and channel config a few ADC clocks.
+const ADC_TIMEOUT_US: u32 = 1_000;
+const ADC_CHSELR: u32 = 1 << 0 | 1 << 3 | 1 << 11 | 1 << 12;
+
+/// Internal temperature sensor reading at 30°C, VDDA = 3.0V.
+pub fn ts_cal1() -> u16 {
+    unsafe { core::ptr::read_volatile(0x1FFF_75A8 as *const u16) }
+}
+
+/// Most recent conversions, whatever the decimation.
+pub fn adc_latest() -> [u16; ADC_CHANNELS] {
+    unsafe { core::ptr::read_volatile(core::ptr::addr_of!(ADC_BUFFER)) }
+}
+
+/// Run the current loop on every n-th PWM period.
+pub const CURRENT_LOOP_DECIMATION: u32 = 4;
+
+/// Written by DMA1 channel 1, the whole sequence on every TIM1 CC4 trigger.
+static mut ADC_BUFFER: [u16; ADC_CHANNELS] = [0; ADC_CHANNELS];
+/// Only written from the DMA interrupt.
+static G_ADC_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the latest conversions on every `CURRENT_LOOP_DECIMATION`-th transfer.
+pub fn adc_dma_interrupt_task() -> Option<[u16; ADC_CHANNELS]> {
+    // DMA belongs to `AdcPeripheral`, IFCR is write-1-to-clear
+    let dma = unsafe { &*DMA::ptr() };
+    dma.ifcr.write(|w| w.ctcif1().set_bit());
+    let count = G_ADC_COUNT.load(Ordering::Relaxed).wrapping_add(1);
+    G_ADC_COUNT.store(count, Ordering::Relaxed);
+    if count.is_multiple_of(CURRENT_LOOP_DECIMATION) {
+        Some(unsafe { core::ptr::read_volatile(core::ptr::addr_of!(ADC_BUFFER)) })
+    } else {
+        None
     }
+}
 
-    pub fn init(&self) {
-        free(|cs| {
-            match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-                None => (),
-                Some(perip) => {
-                    // GPIOポートの電源投入(クロックの有効化)
-                    perip.RCC.iopenr.modify(|_, w| w.iopaen().set_bit());
-                    // gpioモード変更
-                    let gpioa = &perip.GPIOA;
-                    gpioa.moder.modify(|_, w| w.moder4().output());
-                }
-            }
+/// ADC1 converting on TIM1 CC4, results moved by DMA1 channel 1.
+pub struct AdcPeripheral {
+    _adc: ADC,
+    _dma: DMA,
+    _pins: AdcPins<Analog>,
+}
+
+/// Analog inputs in `adc_channel`.
+pub struct AdcPins<MODE = Unconfigured> {
+    pub current: PA0<MODE>,
+    pub supply: PA3<MODE>,
+    pub ntc: PB7<MODE>,
+}
+
+impl AdcPeripheral {
+    /// Starts converting right away, the DMA interrupt follows the PWM.
+    pub fn new(adc: ADC, dma: DMA, dmamux: DMAMUX, pins: AdcPins) -> Result<Self, InitError> {
+        let pins = AdcPins {
+            current: pins.current.into_analog()?,
+            supply: pins.supply.into_analog()?,
+            ntc: pins.ntc.into_analog()?,
+        };
+        free(|_| {
+            rcc().apbenr2.modify(|_, w| w.adcen().set_bit());
+            rcc().ahbenr.modify(|_, w| w.dmaen().set_bit());
+        });
+        // PCLK/2, 32MHz at most
+        adc.cfgr2.modify(|_, w| w.ckmode().bits(0b01));
+        adc.cr.modify(|_, w| w.advregen().set_bit());
+        cortex_m::asm::delay(clocks().hclk_hz / 1_000_000 * 20); // tADCVREG_STUP 20us
+        adc.cr.modify(|_, w| w.adcal().set_bit());
+        if !wait_until(ADC_TIMEOUT_US, &|| adc.cr.read().adcal().bit_is_clear()) {
+            return Err(InitError::PeripheralUnavailable);
+        }
+
+        // 12bit, rising edge of TIM1_CC4, circular DMA
+        adc.cfgr1.modify(|_, w| unsafe {
+            w.res()
+                .bits(0b00)
+                .exten()
+                .bits(0b01)
+                .extsel()
+                .bits(0b001)
+                .dmaen()
+                .set_bit()
+                .dmacfg()
+                .set_bit()
         });
+        // SMP1 7.5 cycles for the fast channels, SMP2 160.5 cycles (5us)
+        // for the temperature sensor and the high impedance NTC
+        adc.smpr.modify(|_, w| {
+            w.smp1()
+                .bits(0b010)
+                .smp2()
+                .bits(0b111)
+                .smpsel11()
+                .set_bit()
+                .smpsel12()
+                .set_bit()
+        });
+        adc.ccr.modify(|_, w| w.tsen().set_bit());
+        adc.chselr0().write(|w| unsafe { w.bits(ADC_CHSELR) });
+        if !wait_until(ADC_TIMEOUT_US, &|| adc.isr.read().ccrdy().bit_is_set()) {
+            return Err(InitError::PeripheralUnavailable);
+        }
+        adc.isr.write(|w| w.ccrdy().set_bit());
+
+        dmamux.c0cr.modify(|_, w| unsafe { w.dmareq_id().bits(5) }); // ADC
+        dma.ch1
+            .par
+            .write(|w| unsafe { w.bits(adc.dr.as_ptr() as u32) });
+        dma.ch1
+            .mar
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(ADC_BUFFER) as u32) });
+        dma.ch1
+            .ndtr
+            .write(|w| unsafe { w.bits(ADC_CHANNELS as u32) });
+        dma.ch1.cr.modify(|_, w| unsafe {
+            w.msize()
+                .bits(0b01)
+                .psize()
+                .bits(0b01)
+                .minc()
+                .set_bit()
+                .circ()
+                .set_bit()
+                .tcie()
+                .set_bit()
+        });
+        dma.ch1.cr.modify(|_, w| w.en().set_bit());
+
+        adc.isr.write(|w| w.adrdy().set_bit());
+        adc.cr.modify(|_, w| w.aden().set_bit());
+        if !wait_until(ADC_TIMEOUT_US, &|| adc.isr.read().adrdy().bit_is_set()) {
+            return Err(InitError::PeripheralUnavailable);
+        }
+        adc.cr.modify(|_, w| w.adstart().set_bit());
+        unsafe {
+            NVIC::unmask(Interrupt::DMA_CHANNEL1);
+        }
+        Ok(Self {
+            _adc: adc,
+            _dma: dma,
+            _pins: pins,
+        })
     }
 }
 
-pub struct Led1 {}
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BridgeState {
+    Disabled,
+    /// enable requested, waiting for a zero-duty command
+    Arming,
+    Enabled,
+}
 
-impl Indicator for Led1 {
-    fn on(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let gpioa = &perip.GPIOA;
-                gpioa.bsrr.write(|w| w.br5().reset());
+/// Prescaler and full-scale CCR for `config`, taking the smallest prescaler
+/// for the finest duty resolution.
+fn pwm_timing(clock_hz: u32, config: PwmConfig) -> Result<(u16, u16), PwmConfigError> {
+    if config.frequency_hz == 0 {
+        return Err(PwmConfigError::FrequencyTooLow);
+    }
+    let counts = match config.alignment {
+        Alignment::Edge => clock_hz / config.frequency_hz,
+        // up and down
+        Alignment::Centre => clock_hz / config.frequency_hz / 2,
+    };
+    if counts < 2 {
+        return Err(PwmConfigError::FrequencyTooHigh);
+    }
+    // kept below 0xFFFF so that 100% duty (CCR = ARR + 1 when edge-aligned) still fits CCR
+    let psc = (counts - 1) / 0xFFFE + 1;
+    if psc > 0x1_0000 {
+        return Err(PwmConfigError::FrequencyTooLow);
+    }
+    let period = counts / psc;
+    if period < config.min_steps as u32 {
+        return Err(PwmConfigError::FrequencyTooHigh);
+    }
+    Ok(((psc - 1) as u16, period as u16))
+}
+
+/// Last frequency loaded into TIM1, read from the current loop interrupt.
+static G_PWM_FREQUENCY_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// PWM frequency as currently loaded in TIM1.
+pub fn pwm_frequency_hz() -> u32 {
+    G_PWM_FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveLow,
+    ActiveHigh,
+}
+
+/// Open-drain fault outputs, the usual kind, pull the line low.
+impl Default for Polarity {
+    fn default() -> Self {
+        Polarity::ActiveLow
+    }
+}
+
+/// Power stage wiring.
+pub enum BridgeTopology {
+    /// Driver IC with IN1/IN2 inputs on CH1 (PA8) and CH2 (PB3). The
+    /// TSSOP20 board.
+    #[cfg(not(feature = "lqfp48"))]
+    IntegratedDriver,
+    /// Two discrete half-bridges, high side on CH1 (PA8) / CH2 (PB3) and low
+    /// side on CH1N (PB13) / CH2N (PB14). LQFP48 only.
+    ///
+    /// The off-time always recirculates through the low-side FETs, so
+    /// `DecayMode::Fast` behaves like `DecayMode::Slow`.
+    #[cfg(feature = "lqfp48")]
+    DiscreteBridge {
+        dead_time_ns: u32,
+        ch1n: PB13,
+        ch2n: PB14,
+    },
+}
+
+/// Fault lines that turn the bridge off in hardware, within a few timer clocks.
+///
+/// The G030 has no comparators, so an over-current trip needs an external
+/// comparator on one of the pins.
+pub struct BreakInputs {
+    /// BKIN (PB12), gate driver fault line. LQFP48 only.
+    pub bkin: Option<(Polarity, PB12)>,
+    /// BKIN2 (PA11), over-current comparator. Shares the pin with the sync output.
+    pub bkin2: Option<(Polarity, PA11)>,
+}
+
+/// BDTR.DTG for at least `ns` of dead-time with CKD = 1, None if out of range.
+#[cfg(feature = "lqfp48")]
+fn dead_time_bits(clock_hz: u32, ns: u32) -> Option<u8> {
+    // round up, less dead-time than asked for is never fine
+    let ticks = ns.saturating_mul(clock_hz / 1000).div_ceil(1_000_000);
+    match ticks {
+        0..=127 => Some(ticks as u8),
+        128..=254 => Some(0b1000_0000 | (ticks.div_ceil(2) - 64) as u8),
+        255..=504 => Some(0b1100_0000 | (ticks.div_ceil(8) - 32) as u8),
+        505..=1008 => Some(0b1110_0000 | (ticks.div_ceil(16) - 32) as u8),
+        _ => None,
+    }
+}
+
+/// Pins held by a configured `DcPwm`.
+struct BridgePins {
+    _ch1: PA8<Alternate<2>>,
+    _ch2: PB3<Alternate<1>>,
+    _low_side: Option<(PB13<Alternate<2>>, PB14<Alternate<2>>)>,
+    _bkin: Option<PB12<Alternate<2>>>,
+    _bkin2: Option<PA11<Alternate<5>>>,
+}
+
+pub struct DcPwm {
+    tim: TIM1,
+    _pins: BridgePins,
+    /// Low side on CH1N/CH2N
+    discrete: bool,
+    state: Cell<BridgeState>,
+    /// Last commanded duty, -DUTY_ONE..=DUTY_ONE
+    duty: Cell<i32>,
+    /// CCR for 100% duty: ARR + 1 edge-aligned, ARR centre-aligned
+    period: Cell<u16>,
+    sample_point: Cell<SamplePoint>,
+    zero_duty: Cell<bool>,
+    decay_mode: Cell<DecayMode>,
+    clamp_policy: Cell<ClampPolicy>,
+}
+impl DcPwm {
+    /// TIM1 CH1 (PA8) and CH2 (PB3) driving the bridge, disabled until `enable`.
+    pub fn new(
+        tim: TIM1,
+        ch1: PA8,
+        ch2: PB3,
+        topology: BridgeTopology,
+        breaks: BreakInputs,
+    ) -> Result<Self, InitError> {
+        // PWM pin
+        let ch1 = ch1.into_alternate()?; // TIM1 CH1
+        let ch2 = ch2.into_alternate()?; // TIM1 CH2
+        free(|_| rcc().apbenr2.modify(|_, w| w.tim1en().set_bit()));
+
+        // For PWM
+        let (discrete, low_side) = match topology {
+            #[cfg(not(feature = "lqfp48"))]
+            BridgeTopology::IntegratedDriver => (false, None),
+            #[cfg(feature = "lqfp48")]
+            BridgeTopology::DiscreteBridge {
+                dead_time_ns,
+                ch1n,
+                ch2n,
+            } => {
+                let low_side = (ch1n.into_alternate()?, ch2n.into_alternate()?); // TIM1 CH1N, CH2N
+                let dtg = match dead_time_bits(clocks().timer_hz, dead_time_ns) {
+                    Some(dtg) => dtg,
+                    None => {
+                        defmt::warn!("dead-time {}ns out of range", dead_time_ns);
+                        0xFF
+                    }
+                };
+                tim.bdtr.modify(|_, w| unsafe { w.dtg().bits(dtg) });
+                // idle: both FETs off
+                tim.cr2
+                    .modify(|_, w| w.ois1n().clear_bit().ois2n().clear_bit());
+                tim.ccer
+                    .modify(|_, w| w.cc1ne().set_bit().cc2ne().set_bit());
+                (true, Some(low_side))
             }
+        };
+        // BKF/BK2F: 4 samples at fCK_INT against glitches on the fault lines.
+        // No AOE, MOE stays cleared after a break until enable().
+        let bkin = breaks.bkin.map(|(polarity, pin)| -> Result<_, InitError> {
+            let pin = pin.into_alternate()?; // TIM1 BKIN
+            tim.af1.modify(|_, w| w.bkine().set_bit());
+            tim.bdtr.modify(|_, w| unsafe {
+                w.bke()
+                    .set_bit()
+                    .bkp()
+                    .bit(polarity == Polarity::ActiveHigh)
+                    .bkf()
+                    .bits(0b0010)
+            });
+            Ok(pin)
         });
+        let bkin = bkin.transpose()?;
+        let bkin2 = breaks.bkin2.map(|(polarity, pin)| -> Result<_, InitError> {
+            let pin = pin.into_alternate()?; // TIM1 BKIN2
+            tim.af2.modify(|_, w| w.bk2ine().set_bit());
+            tim.bdtr.modify(|_, w| unsafe {
+                w.bk2e()
+                    .set_bit()
+                    .bk2p()
+                    .bit(polarity == Polarity::ActiveHigh)
+                    .bk2f()
+                    .bits(0b0010)
+            });
+            Ok(pin)
+        });
+        let bkin2 = bkin2.transpose()?;
+        // a break latched before the bridge was ever used is stale
+        tim.sr.modify(|_, w| w.bif().clear_bit().b2if().clear_bit());
+
+        // ARR and CCRx preloaded, so that period and duty change on period boundaries
+        tim.cr1.modify(|_, w| w.arpe().set_bit());
+
+        // OCxM mode
+        tim.ccmr1_output()
+            .modify(|_, w| w.oc1m().pwm_mode1().oc1pe().set_bit());
+        tim.ccmr1_output()
+            .modify(|_, w| w.oc2m().pwm_mode1().oc2pe().set_bit());
+        // CCRx
+        tim.ccr1.modify(|_, w| w.ccr1().bits(0));
+        tim.ccr2.modify(|_, w| w.ccr2().bits(0));
+
+        // CC4: sampling trigger, ADC and optionally PA11.
+        // PWM mode 2 rises on the up-counting match, so OC4REF and the
+        // compare event coincide in both alignments
+        tim.ccmr2_output()
+            .modify(|_, w| w.oc4m().pwm_mode2().oc4pe().set_bit());
+        tim.ccr4.modify(|_, w| w.ccr4().bits(1));
+
+        // Set polarity
+        // tim.ccer.modify(|_, w| w.cc1p().clear_bit());
+        // tim.ccer.modify(|_, w| w.cc2p().clear_bit());
+        // MOE=0 の間も出力を Low に駆動する (OSSI=1, OISx=0)
+        tim.bdtr.modify(|_, w| w.ossi().set_bit().moe().clear_bit());
+        tim.cr2
+            .modify(|_, w| w.ois1().clear_bit().ois2().clear_bit());
+
+        // enable tim
+        tim.cr1.modify(|_, w| w.cen().set_bit());
+        // CCxE enable output, MOE is left to enable()
+        tim.ccer.modify(|_, w| w.cc1e().set_bit());
+        tim.ccer.modify(|_, w| w.cc2e().set_bit());
+
+        let pwm = Self {
+            tim,
+            _pins: BridgePins {
+                _ch1: ch1,
+                _ch2: ch2,
+                _low_side: low_side,
+                _bkin: bkin,
+                _bkin2: bkin2,
+            },
+            discrete,
+            state: Cell::new(BridgeState::Disabled),
+            duty: Cell::new(0),
+            period: Cell::new(1),
+            sample_point: Cell::new(SamplePoint::OnTime),
+            zero_duty: Cell::new(true),
+            decay_mode: Cell::new(DecayMode::Fast),
+            clamp_policy: Cell::new(ClampPolicy::Saturate),
+        };
+        // the default fits any timer clock from HSI16 up
+        let _ = pwm.set_pwm_config(PwmConfig::default());
+        Ok(pwm)
     }
-    fn off(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let gpioa = &perip.GPIOA;
-                gpioa.bsrr.write(|w| w.bs5().set());
+    fn rearm_if_zero_duty(&self) {
+        if self.state.get() == BridgeState::Arming && self.zero_duty.get() {
+            self.state.set(BridgeState::Enabled);
+            self.set_main_output(true);
+        }
+    }
+    fn set_main_output(&self, on: bool) {
+        self.tim.bdtr.modify(|_, w| w.moe().bit(on));
+        self.hold_off_after_clock_failure();
+    }
+    /// Undoes a write that raced the clock security NMI. Checked after the
+    /// write, since the NMI sets `clock_failure` before touching TIM1.
+    fn hold_off_after_clock_failure(&self) {
+        if clock_failure() {
+            force_bridge_off(&self.tim);
+        }
+    }
+}
+
+impl DcMotorDriver for DcPwm {
+    fn enable(&self) {
+        if self.state.get() != BridgeState::Disabled || self.fault().is_some() {
+            return;
+        }
+        self.state.set(BridgeState::Arming);
+        self.rearm_if_zero_duty();
+    }
+    fn disable(&self) {
+        self.set_main_output(false);
+        self.state.set(BridgeState::Disabled);
+    }
+    fn is_enabled(&self) -> bool {
+        self.state.get() == BridgeState::Enabled && self.fault().is_none()
+    }
+    fn fault(&self) -> Option<Fault> {
+        let sr = self.tim.sr.read();
+        let fault = if sr.bif().bit_is_set() {
+            Some(Fault::Break)
+        } else if sr.b2if().bit_is_set() {
+            Some(Fault::Break2)
+        } else {
+            None
+        };
+        if fault.is_some() {
+            // MOE has already been cleared by the hardware
+            self.state.set(BridgeState::Disabled);
+        }
+        fault
+    }
+    fn clear_fault(&self) -> Result<(), Fault> {
+        // BIF/B2IF only clear once the input is released
+        self.tim
+            .sr
+            .modify(|_, w| w.bif().clear_bit().b2if().clear_bit());
+        match self.fault() {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
+    }
+    fn set_decay_mode(&self, mode: DecayMode) {
+        self.decay_mode.set(mode);
+    }
+    fn brake(&self) {
+        self.duty.set(0);
+        self.zero_duty.set(true);
+        // IN1 = IN2 = High
+        self.set_channel_outputs(true);
+        self.tim
+            .ccmr1_output()
+            .modify(|_, w| w.oc1m().force_active().oc2m().force_active());
+        self.hold_off_after_clock_failure();
+        self.rearm_if_zero_duty();
+    }
+    fn coast(&self) {
+        self.duty.set(0);
+        self.zero_duty.set(true);
+        if self.discrete {
+            // all FETs off, the gate pull-downs hold them
+            self.set_channel_outputs(false);
+        } else {
+            // IN1 = IN2 = Low
+            self.tim
+                .ccmr1_output()
+                .modify(|_, w| w.oc1m().force_inactive().oc2m().force_inactive());
+        }
+        self.rearm_if_zero_duty();
+    }
+    fn set_pwm_config(&self, config: PwmConfig) -> Result<(), PwmConfigError> {
+        let clock_hz = clocks().timer_hz;
+        let (psc, period) = pwm_timing(clock_hz, config)?;
+        let tim = &self.tim;
+        let disabled = self.state.get() == BridgeState::Disabled;
+        let cms = match config.alignment {
+            Alignment::Edge => 0b00,
+            // compare flags while counting up
+            Alignment::Centre => 0b10,
+        };
+        if tim.cr1.read().cms().bits() != cms {
+            if !disabled {
+                return Err(PwmConfigError::Enabled);
             }
-        });
+            // CMS may only change with the counter stopped
+            tim.cr1.modify(|_, w| w.cen().clear_bit());
+            tim.cr1.modify(|_, w| w.cms().bits(cms));
+            tim.cr1.modify(|_, w| w.cen().set_bit());
+        }
+        // hold the preload transfer so PSC, ARR and CCRx switch together
+        tim.cr1.modify(|_, w| w.udis().set_bit());
+        tim.psc.write(|w| w.psc().bits(psc));
+        let arr = match config.alignment {
+            Alignment::Edge => period - 1,
+            Alignment::Centre => period,
+        };
+        tim.arr.write(|w| w.arr().bits(arr));
+        self.period.set(period);
+        self.sample_point.set(config.sample_point);
+        self.write_compare(self.duty.get());
+        tim.cr1.modify(|_, w| w.udis().clear_bit());
+        if disabled {
+            // outputs are held low, load the shadow registers now
+            tim.egr.write(|w| w.ug().set_bit());
+        }
+        let counts = match config.alignment {
+            Alignment::Edge => period as u32,
+            Alignment::Centre => period as u32 * 2,
+        };
+        G_PWM_FREQUENCY_HZ.store(clock_hz / (psc as u32 + 1) / counts, Ordering::Relaxed);
+        Ok(())
     }
-    fn toggle(&self) {
-        free(|cs| match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-            None => (),
-            Some(perip) => {
-                let gpioa = &perip.GPIOA;
-                if gpioa.odr.read().odr5().is_low() {
-                    gpioa.bsrr.write(|w| w.bs5().set());
+    fn set_clamp_policy(&self, policy: ClampPolicy) {
+        self.clamp_policy.set(policy);
+    }
+    fn set_duty_fixed(&self, duty: i32) -> Result<Duty, DutyError> {
+        let clamped = duty.clamp(-DUTY_ONE, DUTY_ONE);
+        let result = if clamped == duty {
+            Duty::Exact
+        } else if self.clamp_policy.get() == ClampPolicy::Reject {
+            return Err(DutyError::OutOfRange);
+        } else {
+            Duty::Saturated
+        };
+        self.write_duty(clamped);
+        Ok(result)
+    }
+    fn duty_fixed(&self) -> i32 {
+        self.duty.get()
+    }
+}
+
+impl DcPwm {
+    fn write_duty(&self, duty: i32) {
+        self.duty.set(duty);
+        self.zero_duty.set(duty == 0);
+        let forward = duty >= 0;
+        let tim = &self.tim;
+        self.set_channel_outputs(true);
+        match self.decay_mode.get() {
+            // on: drive, off: coast
+            DecayMode::Fast => {
+                tim.ccmr1_output()
+                    .modify(|_, w| w.oc1m().pwm_mode1().oc2m().pwm_mode1());
+            }
+            // on: drive, off: brake
+            DecayMode::Slow => {
+                if forward {
+                    tim.ccmr1_output()
+                        .modify(|_, w| w.oc1m().force_active().oc2m().pwm_mode2());
                 } else {
-                    gpioa.bsrr.write(|w| w.br5().reset());
+                    tim.ccmr1_output()
+                        .modify(|_, w| w.oc1m().pwm_mode2().oc2m().force_active());
                 }
             }
-        });
+            // IN2 = !IN1, 50% で停止
+            DecayMode::LockedAntiphase => {
+                tim.ccmr1_output()
+                    .modify(|_, w| w.oc1m().pwm_mode1().oc2m().pwm_mode2());
+            }
+        }
+        self.hold_off_after_clock_failure();
+        self.write_compare(duty);
+        self.rearm_if_zero_duty();
     }
-}
 
-impl Led1 {
-    pub fn new() -> Self {
-        Self {}
+    /// CCxE/CCxNE of the bridge channels. Cleared only to coast a discrete bridge.
+    fn set_channel_outputs(&self, on: bool) {
+        if self.discrete {
+            self.tim.ccer.modify(|_, w| {
+                w.cc1e()
+                    .bit(on)
+                    .cc1ne()
+                    .bit(on)
+                    .cc2e()
+                    .bit(on)
+                    .cc2ne()
+                    .bit(on)
+            });
+        }
     }
 
-    pub fn init(&self) {
-        free(|cs| {
-            match G_PERIPHERAL.borrow(cs).borrow().as_ref() {
-                None => (),
-                Some(perip) => {
-                    // GPIOポートの電源投入(クロックの有効化)
-                    perip.RCC.iopenr.modify(|_, w| w.iopaen().set_bit());
-                    // gpioモード変更
-                    let gpioa = &perip.GPIOA;
-                    gpioa.moder.modify(|_, w| w.moder5().output());
-                }
+    /// CCRx for `duty` scaled to the current period.
+    fn write_compare(&self, duty: i32) {
+        let tim = &self.tim;
+        let period = self.period.get() as u32;
+        let forward = duty >= 0;
+        let ccr = (duty.unsigned_abs() * period / DUTY_ONE as u32) as u16;
+        let (ccr1, ccr2) = match self.decay_mode.get() {
+            DecayMode::Fast if forward => (ccr, 0),
+            DecayMode::Fast => (0, ccr),
+            DecayMode::Slow => (ccr, ccr),
+            DecayMode::LockedAntiphase => {
+                let mid = (period / 2) as u16;
+                let ccr1 = if forward {
+                    mid + ccr / 2
+                } else {
+                    mid - ccr / 2
+                };
+                (ccr1, ccr1)
             }
-        });
+        };
+        tim.ccr1.modify(|_, w| w.ccr1().bits(ccr1));
+        tim.ccr2.modify(|_, w| w.ccr2().bits(ccr2));
+
+        let period = period as u16;
+        let centre = tim.cr1.read().cms().bits() != 0;
+        let ccr4 = match (self.sample_point.get(), centre) {
+            (SamplePoint::OnTime, false) => ccr / 2,
+            (SamplePoint::OffTime, false) => ccr + (period - ccr) / 2,
+            // on-time is centred on the valley, off-time on the peak
+            (SamplePoint::OnTime, true) => 1,
+            (SamplePoint::OffTime, true) => period,
+        };
+        // CC4 has to match within ARR or the ADC is never triggered again,
+        // e.g. the off-time point at 100% duty when edge-aligned
+        let arr = if centre { period } else { period - 1 };
+        tim.ccr4.modify(|_, w| w.ccr4().bits(ccr4.clamp(1, arr)));
+    }
+
+    /// Mirrors the sampling trigger on PA11 (TIM1_CH4) for external sync.
+    /// Taking the pin rules out BKIN2.
+    pub fn sync_output(&self, pin: PA11) -> Result<PA11<Alternate<2>>, InitError> {
+        let pin = pin.into_alternate()?; // TIM1 CH4
+        self.tim.ccer.modify(|_, w| w.cc4e().set_bit());
+        Ok(pin)
+    }
+}
+
+/// Active-low LED, toggled without a critical section.
+pub struct Led<const P: char, const N: u8> {
+    pin: Pin<P, N, Output>,
+}
+
+pub type Led0 = Led<'A', 4>;
+pub type Led1 = Led<'A', 5>;
+
+impl<const P: char, const N: u8> Indicator for Led<P, N> {
+    fn on(&self) {
+        self.pin.set_low();
+    }
+    fn off(&self) {
+        self.pin.set_high();
+    }
+    fn toggle(&self) {
+        self.pin.toggle();
+    }
+}
+
+impl<const P: char, const N: u8> Led<P, N> {
+    /// Starts off.
+    pub fn new(pin: Pin<P, N, Unconfigured>) -> Result<Self, InitError> {
+        let led = Self {
+            pin: pin.into_output()?,
+        };
+        led.off();
+        Ok(led)
     }
 }
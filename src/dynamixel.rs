@@ -0,0 +1,846 @@
+//! Dynamixel Protocol 2.0 slave.
+//!
+//! This module does not touch any peripheral. Bytes received from the bus are
+//! fed into `Slave::receive`, and the returned status packet is written back by
+//! the caller, so the whole stack can be built and exercised on a host.
+
+pub const HEADER: [u8; 4] = [0xFF, 0xFF, 0xFD, 0x00];
+pub const BROADCAST_ID: u8 = 0xFE;
+
+/// Whole packet including header and CRC.
+pub const MAX_PACKET_LEN: usize = 160;
+
+const PKT_ID: usize = 4;
+const PKT_LENGTH_L: usize = 5;
+const PKT_LENGTH_H: usize = 6;
+const PKT_INSTRUCTION: usize = 7;
+const PKT_PARAMETER: usize = 8;
+
+pub mod instruction {
+    pub const PING: u8 = 0x01;
+    pub const READ: u8 = 0x02;
+    pub const WRITE: u8 = 0x03;
+    pub const REG_WRITE: u8 = 0x04;
+    pub const ACTION: u8 = 0x05;
+    pub const FACTORY_RESET: u8 = 0x06;
+    pub const REBOOT: u8 = 0x08;
+    pub const STATUS: u8 = 0x55;
+    pub const SYNC_READ: u8 = 0x82;
+    pub const SYNC_WRITE: u8 = 0x83;
+    pub const BULK_READ: u8 = 0x92;
+}
+
+/// Value of the error field of a status packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    ResultFail = 0x01,
+    Instruction = 0x02,
+    Crc = 0x03,
+    DataRange = 0x04,
+    DataLength = 0x05,
+    DataLimit = 0x06,
+    Access = 0x07,
+}
+
+/// Set in the error field when the device has a hardware error pending.
+pub const ALERT_BIT: u8 = 0x80;
+
+/// CRC-16 (polynomial 0x8005) used by Protocol 2.0.
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x8005;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub id: u8,
+    pub instruction: u8,
+    pub params: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// Declared length does not fit into the receive buffer.
+    Length,
+    /// CRC mismatch. The id is kept so the addressed device can answer.
+    Crc(u8),
+}
+
+/// Byte-wise packet receiver.
+pub struct Parser {
+    buf: [u8; MAX_PACKET_LEN],
+    pos: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET_LEN],
+            pos: 0,
+        }
+    }
+
+    /// Feed one byte. Returns a packet once a complete frame has been received.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<Packet<'_>, ParseError>> {
+        if self.pos < HEADER.len() {
+            if byte == HEADER[self.pos] {
+                self.buf[self.pos] = byte;
+                self.pos += 1;
+            } else if self.pos == 2 && byte == 0xFF {
+                // FF FF FF: keep the last two bytes as the start of the header
+            } else if byte == HEADER[0] {
+                self.buf[0] = byte;
+                self.pos = 1;
+            } else {
+                self.pos = 0;
+            }
+            return None;
+        }
+
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+        if self.pos <= PKT_LENGTH_H {
+            return None;
+        }
+
+        let length = u16::from_le_bytes([self.buf[PKT_LENGTH_L], self.buf[PKT_LENGTH_H]]) as usize;
+        let total = PKT_INSTRUCTION + length;
+        if length < 3 || total > MAX_PACKET_LEN {
+            self.pos = 0;
+            return Some(Err(ParseError::Length));
+        }
+        if self.pos < total {
+            return None;
+        }
+        self.pos = 0;
+
+        let id = self.buf[PKT_ID];
+        let crc = crc16(0, &self.buf[..total - 2]);
+        if crc != u16::from_le_bytes([self.buf[total - 2], self.buf[total - 1]]) {
+            return Some(Err(ParseError::Crc(id)));
+        }
+
+        let len = remove_stuffing(&mut self.buf[PKT_INSTRUCTION..total - 2]);
+        Some(Ok(Packet {
+            id,
+            instruction: self.buf[PKT_INSTRUCTION],
+            params: &self.buf[PKT_PARAMETER..PKT_INSTRUCTION + len],
+        }))
+    }
+}
+
+/// Removes the 0xFD inserted after every FF FF FD sequence. Returns the new length.
+fn remove_stuffing(payload: &mut [u8]) -> usize {
+    let mut window = [0u8; 3];
+    let mut out = 0;
+    for i in 0..payload.len() {
+        let b = payload[i];
+        let stuffed = window == [0xFF, 0xFF, 0xFD] && b == 0xFD;
+        window = [window[1], window[2], b];
+        if !stuffed {
+            payload[out] = b;
+            out += 1;
+        }
+    }
+    out
+}
+
+/// The frame does not fit into the output buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Overflow;
+
+/// Serialises a frame into `out` and returns its length.
+///
+/// `body` is written right after the instruction byte; for a status packet the
+/// error byte goes in front of the parameters, which is what `prefix` is for.
+pub fn encode(
+    id: u8,
+    instruction: u8,
+    prefix: &[u8],
+    body: &[u8],
+    out: &mut [u8],
+) -> Result<usize, Overflow> {
+    if out.len() < PKT_PARAMETER + 2 {
+        return Err(Overflow);
+    }
+    out[..HEADER.len()].copy_from_slice(&HEADER);
+    out[PKT_ID] = id;
+    out[PKT_INSTRUCTION] = instruction;
+
+    let mut pos = PKT_PARAMETER;
+    for &b in prefix.iter().chain(body.iter()) {
+        // the byte, a stuffed 0xFD after it and the CRC
+        if pos + 1 + 1 + 2 > out.len() {
+            return Err(Overflow);
+        }
+        out[pos] = b;
+        pos += 1;
+        if pos - PKT_INSTRUCTION >= 3 && out[pos - 3..pos] == [0xFF, 0xFF, 0xFD] {
+            out[pos] = 0xFD;
+            pos += 1;
+        }
+    }
+
+    let length = (pos + 2 - PKT_INSTRUCTION) as u16;
+    out[PKT_LENGTH_L..=PKT_LENGTH_H].copy_from_slice(&length.to_le_bytes());
+    let crc = crc16(0, &out[..pos]);
+    out[pos..pos + 2].copy_from_slice(&crc.to_le_bytes());
+    Ok(pos + 2)
+}
+
+pub fn encode_status(id: u8, error: u8, params: &[u8], out: &mut [u8]) -> Result<usize, Overflow> {
+    encode(id, instruction::STATUS, &[error], params, out)
+}
+
+/// Status packet for the slave. If the parameters do not fit into `tx` the
+/// reply carries no data and `ErrorCode::DataLength` instead; 0 only if `tx`
+/// cannot even hold that.
+fn status(id: u8, error: u8, params: &[u8], tx: &mut [u8]) -> usize {
+    encode_status(id, error, params, tx)
+        .or_else(|_| encode_status(id, error | ErrorCode::DataLength as u8, &[], tx))
+        .unwrap_or(0)
+}
+
+/// Access to the register space behind the protocol.
+pub trait Device {
+    fn id(&self) -> u8;
+    fn model_number(&self) -> u16;
+    fn firmware_version(&self) -> u8;
+    /// Value for the error field, including `ALERT_BIT` when needed.
+    fn error_status(&self) -> u8 {
+        0
+    }
+    /// 0: reply to PING only, 1: PING and reads, 2: every instruction.
+    fn status_return_level(&self) -> u8 {
+        2
+    }
+    fn read(&mut self, address: u16, data: &mut [u8]) -> Result<(), ErrorCode>;
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), ErrorCode>;
+    /// 0xFF: everything, 0x01: everything but ID, 0x02: everything but ID and baud rate.
+    fn factory_reset(&mut self, option: u8) -> Result<(), ErrorCode>;
+    /// A REG_WRITE is waiting for ACTION.
+    fn set_registered(&mut self, _registered: bool) {}
+}
+
+const MAX_REG_WRITE_LEN: usize = 32;
+/// Status packet of a PING, the slot each ID waits after a broadcast PING.
+const PING_STATUS_LEN: u32 = 14;
+/// Longest READ whose reply fits in `MAX_PACKET_LEN` even if every third
+/// byte after the instruction needs stuffing.
+const MAX_READ_LEN: usize = (MAX_PACKET_LEN - PKT_PARAMETER - 1 - 2) * 3 / 4;
+
+/// Deferred status for SYNC_READ / BULK_READ, sent once `after` has answered.
+#[derive(Clone, Copy)]
+struct PendingRead {
+    after: u8,
+    address: u16,
+    length: u16,
+}
+
+struct SlaveState {
+    reg_write_address: u16,
+    reg_write_data: [u8; MAX_REG_WRITE_LEN],
+    reg_write_len: Option<usize>,
+    pending_read: Option<PendingRead>,
+    /// Reply slot of the last broadcast PING, our ID.
+    ping_slot: Option<u8>,
+    reboot_requested: bool,
+}
+
+pub struct Slave {
+    parser: Parser,
+    state: SlaveState,
+}
+
+impl Slave {
+    pub const fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            state: SlaveState {
+                reg_write_address: 0,
+                reg_write_data: [0; MAX_REG_WRITE_LEN],
+                reg_write_len: None,
+                pending_read: None,
+                ping_slot: None,
+                reboot_requested: false,
+            },
+        }
+    }
+
+    /// Feed one received byte. Returns the number of bytes written to `tx`
+    /// that must be sent back on the bus (0 for none).
+    pub fn receive<D: Device>(&mut self, byte: u8, device: &mut D, tx: &mut [u8]) -> usize {
+        match self.parser.feed(byte) {
+            None => 0,
+            Some(Ok(packet)) => self.state.handle(&packet, device, tx),
+            Some(Err(ParseError::Crc(id))) if id == device.id() => {
+                status(id, device.error_status() | ErrorCode::Crc as u8, &[], tx)
+            }
+            Some(Err(_)) => 0,
+        }
+    }
+
+    /// Extra wait before sending the reply `receive` just returned. Every
+    /// device answers a broadcast PING, so each waits one PING status per ID
+    /// below its own and the replies come in ID order, as the SDK's
+    /// broadcastPing expects.
+    pub fn take_reply_delay_us(&mut self, baud_rate: u32) -> u32 {
+        match self.state.ping_slot.take() {
+            None => 0,
+            // 10 bits per byte, and a spare byte between replies
+            Some(id) => id as u32 * ((PING_STATUS_LEN + 1) * 10_000_000).div_ceil(baud_rate.max(1)),
+        }
+    }
+
+    /// True once after a REBOOT instruction has been answered.
+    pub fn take_reboot_request(&mut self) -> bool {
+        core::mem::replace(&mut self.state.reboot_requested, false)
+    }
+}
+
+impl Default for Slave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlaveState {
+    fn handle<D: Device>(&mut self, packet: &Packet, device: &mut D, tx: &mut [u8]) -> usize {
+        let id = device.id();
+
+        if packet.instruction == instruction::STATUS {
+            return match self.pending_read {
+                Some(p) if p.after == packet.id => {
+                    self.pending_read = None;
+                    read_status(device, p.address, p.length, tx)
+                }
+                _ => 0,
+            };
+        }
+
+        let broadcast = packet.id == BROADCAST_ID;
+        if packet.id != id && !broadcast {
+            return 0;
+        }
+        // Any new instruction cancels a read that is still waiting for its turn.
+        self.pending_read = None;
+        self.ping_slot = None;
+
+        let level = device.status_return_level();
+        let p = packet.params;
+        let result = match packet.instruction {
+            instruction::PING => {
+                let model = device.model_number().to_le_bytes();
+                let params = [model[0], model[1], device.firmware_version()];
+                if broadcast {
+                    self.ping_slot = Some(id);
+                }
+                return status(id, device.error_status(), &params, tx);
+            }
+            instruction::READ => {
+                if broadcast || level < 1 {
+                    return 0;
+                }
+                if p.len() != 4 {
+                    Err(ErrorCode::DataLength)
+                } else {
+                    let address = u16::from_le_bytes([p[0], p[1]]);
+                    let length = u16::from_le_bytes([p[2], p[3]]);
+                    return read_status(device, address, length, tx);
+                }
+            }
+            instruction::WRITE => {
+                if p.len() < 3 {
+                    Err(ErrorCode::DataLength)
+                } else {
+                    device.write(u16::from_le_bytes([p[0], p[1]]), &p[2..])
+                }
+            }
+            instruction::REG_WRITE => {
+                if p.len() < 3 || p.len() - 2 > MAX_REG_WRITE_LEN {
+                    Err(ErrorCode::DataLength)
+                } else {
+                    let data = &p[2..];
+                    self.reg_write_address = u16::from_le_bytes([p[0], p[1]]);
+                    self.reg_write_data[..data.len()].copy_from_slice(data);
+                    self.reg_write_len = Some(data.len());
+                    device.set_registered(true);
+                    Ok(())
+                }
+            }
+            instruction::ACTION => match self.reg_write_len.take() {
+                None => Err(ErrorCode::ResultFail),
+                Some(len) => {
+                    device.set_registered(false);
+                    device.write(self.reg_write_address, &self.reg_write_data[..len])
+                }
+            },
+            instruction::REBOOT => {
+                self.reboot_requested = true;
+                Ok(())
+            }
+            instruction::FACTORY_RESET => {
+                if broadcast && p.first() == Some(&0xFF) {
+                    // Resetting every ID on the bus to 1 would make them collide.
+                    Err(ErrorCode::DataRange)
+                } else {
+                    device.factory_reset(p.first().copied().unwrap_or(0xFF))
+                }
+            }
+            instruction::SYNC_READ => {
+                if !broadcast || level < 1 || p.len() < 5 {
+                    return 0;
+                }
+                let address = u16::from_le_bytes([p[0], p[1]]);
+                let length = u16::from_le_bytes([p[2], p[3]]);
+                let ids = &p[4..];
+                return match ids.iter().position(|&i| i == id) {
+                    None => 0,
+                    Some(0) => read_status(device, address, length, tx),
+                    Some(n) => {
+                        self.pending_read = Some(PendingRead {
+                            after: ids[n - 1],
+                            address,
+                            length,
+                        });
+                        0
+                    }
+                };
+            }
+            instruction::SYNC_WRITE => {
+                if !broadcast || p.len() < 4 {
+                    return 0;
+                }
+                let address = u16::from_le_bytes([p[0], p[1]]);
+                let length = u16::from_le_bytes([p[2], p[3]]) as usize;
+                for entry in p[4..].chunks(length + 1) {
+                    if entry.len() == length + 1 && entry[0] == id {
+                        let _ = device.write(address, &entry[1..]);
+                    }
+                }
+                return 0;
+            }
+            instruction::BULK_READ => {
+                if !broadcast || level < 1 {
+                    return 0;
+                }
+                let mut previous = None;
+                for entry in p.chunks(5) {
+                    if entry.len() != 5 {
+                        break;
+                    }
+                    if entry[0] == id {
+                        let address = u16::from_le_bytes([entry[1], entry[2]]);
+                        let length = u16::from_le_bytes([entry[3], entry[4]]);
+                        return match previous {
+                            None => read_status(device, address, length, tx),
+                            Some(after) => {
+                                self.pending_read = Some(PendingRead {
+                                    after,
+                                    address,
+                                    length,
+                                });
+                                0
+                            }
+                        };
+                    }
+                    previous = Some(entry[0]);
+                }
+                return 0;
+            }
+            _ => Err(ErrorCode::Instruction),
+        };
+
+        if broadcast || level < 2 {
+            return 0;
+        }
+        let error = match result {
+            Ok(()) => device.error_status(),
+            Err(e) => device.error_status() | e as u8,
+        };
+        status(id, error, &[], tx)
+    }
+}
+
+fn read_status<D: Device>(device: &mut D, address: u16, length: u16, tx: &mut [u8]) -> usize {
+    let id = device.id();
+    let length = length as usize;
+    if length > MAX_READ_LEN {
+        return status(
+            id,
+            device.error_status() | ErrorCode::DataLength as u8,
+            &[],
+            tx,
+        );
+    }
+    let mut data = [0u8; MAX_READ_LEN];
+    match device.read(address, &mut data[..length]) {
+        Ok(()) => status(id, device.error_status(), &data[..length], tx),
+        Err(e) => status(id, device.error_status() | e as u8, &[], tx),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Memory {
+        id: u8,
+        level: u8,
+        data: [u8; 256],
+        registered: bool,
+    }
+
+    impl Memory {
+        fn new(id: u8) -> Self {
+            let mut data = [0u8; 256];
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            Self {
+                id,
+                level: 2,
+                data,
+                registered: false,
+            }
+        }
+    }
+
+    impl Device for Memory {
+        fn id(&self) -> u8 {
+            self.id
+        }
+        fn model_number(&self) -> u16 {
+            1030
+        }
+        fn firmware_version(&self) -> u8 {
+            0x26
+        }
+        fn status_return_level(&self) -> u8 {
+            self.level
+        }
+        fn read(&mut self, address: u16, data: &mut [u8]) -> Result<(), ErrorCode> {
+            let start = address as usize;
+            let src = self
+                .data
+                .get(start..start + data.len())
+                .ok_or(ErrorCode::Access)?;
+            data.copy_from_slice(src);
+            Ok(())
+        }
+        fn write(&mut self, address: u16, data: &[u8]) -> Result<(), ErrorCode> {
+            let start = address as usize;
+            let dst = self
+                .data
+                .get_mut(start..start + data.len())
+                .ok_or(ErrorCode::Access)?;
+            dst.copy_from_slice(data);
+            Ok(())
+        }
+        fn factory_reset(&mut self, _option: u8) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_registered(&mut self, registered: bool) {
+            self.registered = registered;
+        }
+    }
+
+    fn frame(id: u8, instruction: u8, params: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; 512];
+        let n = encode(id, instruction, &[], params, &mut out).unwrap();
+        out[..n].to_vec()
+    }
+
+    /// Bytes the slave sends back after the whole frame went in.
+    fn exchange(slave: &mut Slave, device: &mut Memory, frame: &[u8]) -> Vec<u8> {
+        let mut tx = [0u8; MAX_PACKET_LEN];
+        let mut reply = Vec::new();
+        for &b in frame {
+            let n = slave.receive(b, device, &mut tx);
+            if n > 0 {
+                reply = tx[..n].to_vec();
+            }
+        }
+        reply
+    }
+
+    /// (id, error, params) of a status packet.
+    fn decode(bytes: &[u8]) -> (u8, u8, Vec<u8>) {
+        let mut parser = Parser::new();
+        for (i, &b) in bytes.iter().enumerate() {
+            if let Some(result) = parser.feed(b) {
+                assert_eq!(i, bytes.len() - 1, "trailing bytes");
+                let packet = result.unwrap();
+                assert_eq!(packet.instruction, instruction::STATUS);
+                return (packet.id, packet.params[0], packet.params[1..].to_vec());
+            }
+        }
+        panic!("incomplete packet");
+    }
+
+    #[test]
+    fn crc_matches_the_protocol_examples() {
+        // PING to ID 1, and its status from the protocol documentation
+        let ping = [0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x03, 0x00, 0x01];
+        assert_eq!(crc16(0, &ping), 0x4E19);
+        let status = [
+            0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x07, 0x00, 0x55, 0x00, 0x06, 0x04, 0x26,
+        ];
+        assert_eq!(crc16(0, &status), 0x5D65);
+        assert_eq!(
+            frame(1, instruction::PING, &[]),
+            [&ping[..], &[0x19, 0x4E]].concat()
+        );
+    }
+
+    #[test]
+    fn ping_reply() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let reply = exchange(&mut slave, &mut device, &frame(1, instruction::PING, &[]));
+        assert_eq!(
+            reply,
+            [0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x07, 0x00, 0x55, 0x00, 0x06, 0x04, 0x26, 0x65, 0x5D]
+        );
+    }
+
+    #[test]
+    fn broadcast_ping_replies_in_id_order() {
+        let ping = frame(BROADCAST_ID, instruction::PING, &[]);
+        // 15 bytes at 57600 baud
+        let slot = 2605;
+        let mut slave = Slave::new();
+        for &id in &[1, 2, 7] {
+            let mut device = Memory::new(id);
+            let reply = exchange(&mut slave, &mut device, &ping);
+            assert_eq!(decode(&reply), (id, 0, vec![0x06, 0x04, 0x26]));
+            assert_eq!(slave.take_reply_delay_us(57_600), id as u32 * slot);
+            assert_eq!(slave.take_reply_delay_us(57_600), 0);
+        }
+        // a PING to our own ID goes out at once
+        let mut device = Memory::new(7);
+        exchange(&mut slave, &mut device, &ping);
+        exchange(&mut slave, &mut device, &frame(7, instruction::PING, &[]));
+        assert_eq!(slave.take_reply_delay_us(57_600), 0);
+    }
+
+    #[test]
+    fn stuffing_round_trip() {
+        let params = [0x10, 0xFF, 0xFF, 0xFD, 0x20, 0xFF, 0xFF, 0xFD, 0xFD];
+        let bytes = frame(1, instruction::WRITE, &params);
+        // a 0xFD after each FF FF FD, the length field counts them
+        assert_eq!(
+            &bytes[PKT_PARAMETER..bytes.len() - 2],
+            [0x10, 0xFF, 0xFF, 0xFD, 0xFD, 0x20, 0xFF, 0xFF, 0xFD, 0xFD, 0xFD]
+        );
+        assert_eq!(u16::from_le_bytes([bytes[5], bytes[6]]), 1 + 11 + 2);
+
+        let mut parser = Parser::new();
+        let (last, rest) = bytes.split_last().unwrap();
+        for &b in rest {
+            assert!(parser.feed(b).is_none());
+        }
+        let packet = parser.feed(*last).unwrap().unwrap();
+        assert_eq!(packet.id, 1);
+        assert_eq!(packet.instruction, instruction::WRITE);
+        assert_eq!(packet.params, params);
+    }
+
+    #[test]
+    fn header_inside_the_instruction_is_stuffed() {
+        // the instruction byte counts towards the FF FF FD window
+        let bytes = frame(1, 0xFF, &[0xFF, 0xFD]);
+        assert_eq!(
+            &bytes[PKT_INSTRUCTION..bytes.len() - 2],
+            [0xFF, 0xFF, 0xFD, 0xFD]
+        );
+    }
+
+    #[test]
+    fn encode_never_writes_past_the_buffer() {
+        let body: Vec<u8> = [0xFF, 0xFF, 0xFD]
+            .iter()
+            .copied()
+            .cycle()
+            .take(60)
+            .collect();
+        let fits = encode(1, instruction::STATUS, &[0], &body, &mut [0u8; 256]).unwrap();
+        for len in 0..fits + 4 {
+            let mut out = vec![0u8; len];
+            match encode(1, instruction::STATUS, &[0], &body, &mut out) {
+                Ok(n) => assert_eq!(n, fits),
+                Err(Overflow) => assert!(len < fits),
+            }
+        }
+    }
+
+    #[test]
+    fn longest_read_fits_even_fully_stuffed() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        for i in 0..MAX_READ_LEN {
+            device.data[i] = [0xFF, 0xFF, 0xFD][i % 3];
+        }
+        let mut params = [0u8; 4];
+        params[2..].copy_from_slice(&(MAX_READ_LEN as u16).to_le_bytes());
+        let reply = exchange(
+            &mut slave,
+            &mut device,
+            &frame(1, instruction::READ, &params),
+        );
+        let (id, error, data) = decode(&reply);
+        assert_eq!((id, error), (1, 0));
+        assert_eq!(data, &device.data[..MAX_READ_LEN]);
+
+        params[2..].copy_from_slice(&(MAX_READ_LEN as u16 + 1).to_le_bytes());
+        let reply = exchange(
+            &mut slave,
+            &mut device,
+            &frame(1, instruction::READ, &params),
+        );
+        assert_eq!(decode(&reply), (1, ErrorCode::DataLength as u8, vec![]));
+    }
+
+    #[test]
+    fn crc_error_is_answered_by_the_addressed_device() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let mut bytes = frame(1, instruction::PING, &[]);
+        *bytes.last_mut().unwrap() ^= 1;
+        let reply = exchange(&mut slave, &mut device, &bytes);
+        assert_eq!(decode(&reply), (1, ErrorCode::Crc as u8, vec![]));
+
+        let mut bytes = frame(2, instruction::PING, &[]);
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(exchange(&mut slave, &mut device, &bytes).is_empty());
+    }
+
+    #[test]
+    fn sync_read_waits_for_the_previous_id() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        // ids 3, 1, 5: answer after 3
+        let request = frame(
+            BROADCAST_ID,
+            instruction::SYNC_READ,
+            &[10, 0, 2, 0, 3, 1, 5],
+        );
+        assert!(exchange(&mut slave, &mut device, &request).is_empty());
+
+        let mut other = [0u8; 32];
+        let n = encode_status(5, 0, &[0, 0], &mut other).unwrap();
+        assert!(exchange(&mut slave, &mut device, &other[..n]).is_empty());
+        let n = encode_status(3, 0, &[0, 0], &mut other).unwrap();
+        let reply = exchange(&mut slave, &mut device, &other[..n]);
+        assert_eq!(decode(&reply), (1, 0, vec![10, 11]));
+        // only once
+        assert!(exchange(&mut slave, &mut device, &other[..n]).is_empty());
+
+        // first in the list answers right away
+        let request = frame(BROADCAST_ID, instruction::SYNC_READ, &[20, 0, 1, 0, 1, 3]);
+        let reply = exchange(&mut slave, &mut device, &request);
+        assert_eq!(decode(&reply), (1, 0, vec![20]));
+    }
+
+    #[test]
+    fn bulk_read_waits_for_the_previous_entry() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let request = frame(
+            BROADCAST_ID,
+            instruction::BULK_READ,
+            &[7, 0, 0, 4, 0, 1, 30, 0, 3, 0],
+        );
+        assert!(exchange(&mut slave, &mut device, &request).is_empty());
+        let mut status = [0u8; 32];
+        let n = encode_status(7, 0, &[0, 1, 2, 3], &mut status).unwrap();
+        let reply = exchange(&mut slave, &mut device, &status[..n]);
+        assert_eq!(decode(&reply), (1, 0, vec![30, 31, 32]));
+    }
+
+    #[test]
+    fn new_instruction_cancels_a_pending_read() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let request = frame(BROADCAST_ID, instruction::SYNC_READ, &[10, 0, 2, 0, 3, 1]);
+        exchange(&mut slave, &mut device, &request);
+        exchange(&mut slave, &mut device, &frame(1, instruction::PING, &[]));
+        let mut status = [0u8; 32];
+        let n = encode_status(3, 0, &[0, 0], &mut status).unwrap();
+        assert!(exchange(&mut slave, &mut device, &status[..n]).is_empty());
+    }
+
+    #[test]
+    fn reg_write_waits_for_action() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let reply = exchange(
+            &mut slave,
+            &mut device,
+            &frame(1, instruction::REG_WRITE, &[60, 0, 7, 8]),
+        );
+        assert_eq!(decode(&reply), (1, 0, vec![]));
+        assert!(device.registered);
+        assert_eq!(device.data[60..62], [60, 61]);
+        let action = frame(BROADCAST_ID, instruction::ACTION, &[]);
+        assert!(exchange(&mut slave, &mut device, &action).is_empty());
+        assert!(!device.registered);
+        assert_eq!(device.data[60..62], [7, 8]);
+        // nothing left to act on
+        let reply = exchange(&mut slave, &mut device, &frame(1, instruction::ACTION, &[]));
+        assert_eq!(decode(&reply), (1, ErrorCode::ResultFail as u8, vec![]));
+    }
+
+    #[test]
+    fn sync_write_takes_the_own_entry() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let request = frame(
+            BROADCAST_ID,
+            instruction::SYNC_WRITE,
+            &[40, 0, 2, 0, 2, 0xAA, 0xBB, 1, 0xCC, 0xDD],
+        );
+        assert!(exchange(&mut slave, &mut device, &request).is_empty());
+        assert_eq!(device.data[40..42], [0xCC, 0xDD]);
+    }
+
+    #[test]
+    fn status_return_level() {
+        let read = frame(1, instruction::READ, &[0, 0, 1, 0]);
+        let write = frame(1, instruction::WRITE, &[50, 0, 9]);
+        let ping = frame(1, instruction::PING, &[]);
+        let replies = |level| {
+            let mut slave = Slave::new();
+            let mut device = Memory::new(1);
+            device.level = level;
+            [&ping, &read, &write].map(|f| !exchange(&mut slave, &mut device, f).is_empty())
+        };
+        assert_eq!(replies(0), [true, false, false]);
+        assert_eq!(replies(1), [true, true, false]);
+        assert_eq!(replies(2), [true, true, true]);
+    }
+
+    #[test]
+    fn broadcast_write_is_not_answered() {
+        let mut slave = Slave::new();
+        let mut device = Memory::new(1);
+        let write = frame(BROADCAST_ID, instruction::WRITE, &[50, 0, 9]);
+        assert!(exchange(&mut slave, &mut device, &write).is_empty());
+        assert_eq!(device.data[50], 9);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::velocity::Sample;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+    Stopped,
+}
+
+pub trait Encoder {
+    /// Multi-turn position in counts.
+    fn position(&mut self) -> i64;
+    /// Position truncated to 32 bits, wrapping like the Dynamixel present position.
+    fn position_i32(&mut self) -> i32 {
+        self.position() as i32
+    }
+    /// Counts moved since the previous call.
+    fn delta(&mut self) -> i32;
+    /// Direction of the last observed movement.
+    fn direction(&self) -> Direction;
+    fn preset(&mut self, position: i64);
+    /// Position with timestamps for velocity estimation.
+    fn sample(&mut self) -> Sample;
+    fn reset(&mut self) {
+        self.preset(0);
+    }
+}
+
+/// A missing encoder stands still at 0.
+impl<T: Encoder> Encoder for Option<T> {
+    fn position(&mut self) -> i64 {
+        self.as_mut().map_or(0, |e| e.position())
+    }
+    fn delta(&mut self) -> i32 {
+        self.as_mut().map_or(0, |e| e.delta())
+    }
+    fn direction(&self) -> Direction {
+        self.as_ref().map_or(Direction::Stopped, |e| e.direction())
+    }
+    fn preset(&mut self, position: i64) {
+        if let Some(e) = self {
+            e.preset(position);
+        }
+    }
+    fn sample(&mut self) -> Sample {
+        match self {
+            Some(e) => e.sample(),
+            None => Sample {
+                time_us: 0,
+                position: 0,
+                edge: None,
+            },
+        }
+    }
+}
+
+/// Extends a free running 16-bit hardware counter into a 64-bit position.
+///
+/// `update` has to be called at least once per 32768 counts of travel,
+/// otherwise the direction of a wrap can no longer be told apart.
+pub struct MultiTurnCounter {
+    last_raw: u16,
+    position: i64,
+    delta_origin: i64,
+    direction: Direction,
+}
+
+impl MultiTurnCounter {
+    pub const fn new(raw: u16) -> Self {
+        Self {
+            last_raw: raw,
+            position: 0,
+            delta_origin: 0,
+            direction: Direction::Stopped,
+        }
+    }
+
+    pub fn update(&mut self, raw: u16) -> i64 {
+        let diff = raw.wrapping_sub(self.last_raw) as i16;
+        self.last_raw = raw;
+        self.position += diff as i64;
+        if diff > 0 {
+            self.direction = Direction::Forward;
+        } else if diff < 0 {
+            self.direction = Direction::Backward;
+        }
+        self.position
+    }
+
+    /// Position of a raw value taken close to the last update, e.g. an input capture.
+    pub fn extend(&self, raw: u16) -> i64 {
+        self.position + raw.wrapping_sub(self.last_raw) as i16 as i64
+    }
+
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    pub fn take_delta(&mut self) -> i32 {
+        let delta = self.position - self.delta_origin;
+        self.delta_origin = self.position;
+        delta as i32
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn preset(&mut self, position: i64) {
+        self.position = position;
+        self.delta_origin = position;
+        self.direction = Direction::Stopped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_forward() {
+        let mut c = MultiTurnCounter::new(0xFFF0);
+        assert_eq!(c.update(0x0010), 0x20);
+        assert_eq!(c.direction(), Direction::Forward);
+        // 何周回っても積算される
+        for turn in 1..=4i64 {
+            c.update(0x5000);
+            c.update(0xA000);
+            assert_eq!(c.update(0x0010), 0x20 + turn * 0x10000);
+        }
+    }
+
+    #[test]
+    fn wraps_backward() {
+        let mut c = MultiTurnCounter::new(0x0010);
+        assert_eq!(c.update(0xFFF0), -0x20);
+        assert_eq!(c.direction(), Direction::Backward);
+        for turn in 1..=4i64 {
+            c.update(0xA000);
+            c.update(0x5000);
+            assert_eq!(c.update(0xFFF0), -0x20 - turn * 0x10000);
+        }
+    }
+
+    #[test]
+    fn no_movement_keeps_the_direction() {
+        let mut c = MultiTurnCounter::new(100);
+        assert_eq!(c.direction(), Direction::Stopped);
+        c.update(90);
+        c.update(90);
+        assert_eq!(c.direction(), Direction::Backward);
+    }
+
+    #[test]
+    fn extend_follows_the_last_update() {
+        let mut c = MultiTurnCounter::new(0xFFFE);
+        c.update(0xFFFF);
+        assert_eq!(c.extend(0x0002), 4);
+        assert_eq!(c.extend(0xFFFA), -4);
+    }
+
+    #[test]
+    fn preset_moves_the_origin() {
+        let mut c = MultiTurnCounter::new(0x1234);
+        c.update(0x1300);
+        c.preset(-1_000_000);
+        assert_eq!(c.position(), -1_000_000);
+        assert_eq!(c.direction(), Direction::Stopped);
+        assert_eq!(c.take_delta(), 0);
+        assert_eq!(c.update(0x1310), -1_000_000 + 0x10);
+        assert_eq!(c.take_delta(), 0x10);
+    }
+
+    #[test]
+    fn take_delta_is_relative_to_the_previous_call() {
+        let mut c = MultiTurnCounter::new(0);
+        c.update(1000);
+        c.update(0xFF00);
+        assert_eq!(c.take_delta(), -0x100);
+        assert_eq!(c.take_delta(), 0);
+        c.update(0x0100);
+        c.update(0x0200);
+        assert_eq!(c.take_delta(), 0x300);
+    }
+}
@@ -0,0 +1,263 @@
+//! I²t model of the winding temperature, for motors without a temperature sensor.
+//!
+//! The winding is a first-order thermal system: its temperature rise follows
+//! the square of the current with time constant `time_constant_s`. The state
+//! is the rise as a fraction of the rise at the continuous rating, so 1.0 is
+//! where the winding settles at `continuous_amps`.
+
+use crate::pid::clamp;
+
+/// Where the current fed to the model comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurrentSource {
+    /// The shunt.
+    Measured,
+    /// Duty times supply over the winding resistance, i.e. the stall current.
+    /// Never lower than the real current, so the estimate errs on the hot side.
+    Estimated { winding_ohms: f32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct I2tConfig {
+    pub continuous_amps: f32,
+    pub peak_amps: f32,
+    pub time_constant_s: f32,
+    /// Winding temperature rise at the continuous rating.
+    pub rated_rise_celsius: f32,
+    /// Load at which the current limit starts coming down from the peak rating.
+    pub limit_start: f32,
+    pub source: CurrentSource,
+}
+
+impl Default for I2tConfig {
+    fn default() -> Self {
+        Self {
+            continuous_amps: 1.0,
+            peak_amps: 2.0,
+            time_constant_s: 30.0,
+            rated_rise_celsius: 60.0,
+            limit_start: 0.8,
+            source: CurrentSource::Measured,
+        }
+    }
+}
+
+pub struct I2tModel {
+    config: I2tConfig,
+    load: f32,
+}
+
+impl I2tModel {
+    /// Starts cold.
+    pub fn new(config: I2tConfig) -> Self {
+        Self { config, load: 0.0 }
+    }
+
+    pub fn config(&self) -> &I2tConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: I2tConfig) {
+        self.config = config;
+    }
+
+    pub fn reset(&mut self) {
+        self.load = 0.0;
+    }
+
+    /// Temperature rise relative to the continuous rating.
+    pub fn load(&self) -> f32 {
+        self.load
+    }
+
+    pub fn winding_celsius(&self, ambient_celsius: f32) -> f32 {
+        ambient_celsius + self.load * self.config.rated_rise_celsius
+    }
+
+    /// Current to feed to `update` for a duty in -1.0..=1.0.
+    pub fn amps(&self, measured_amps: f32, duty: f32, supply_volts: f32) -> f32 {
+        match self.config.source {
+            CurrentSource::Measured => measured_amps,
+            CurrentSource::Estimated { winding_ohms } if winding_ohms > 0.0 => {
+                duty * supply_volts / winding_ohms
+            }
+            // no resistance given, assume the worst
+            CurrentSource::Estimated { .. } => self.config.peak_amps,
+        }
+    }
+
+    /// Integrates `amps` over `dt` seconds and returns the new load.
+    pub fn update(&mut self, amps: f32, dt: f32) -> f32 {
+        let c = self.config;
+        let rating = c.continuous_amps * c.continuous_amps;
+        let input = if rating > 0.0 {
+            amps * amps / rating
+        } else {
+            f32::MAX
+        };
+        // backward Euler, stable for any dt
+        let tau = if c.time_constant_s > 0.0 {
+            c.time_constant_s
+        } else {
+            0.0
+        };
+        self.load += (input - self.load) * dt / (tau + dt);
+        self.load
+    }
+
+    /// Peak rating while cool, down to the continuous rating as the load
+    /// reaches 1.0. Holding the limit keeps the winding at its rated rise.
+    pub fn current_limit(&self) -> f32 {
+        let c = self.config;
+        let start = clamp(c.limit_start, 0.0, 1.0);
+        if self.load <= start {
+            c.peak_amps
+        } else if self.load >= 1.0 || start >= 1.0 {
+            c.continuous_amps
+        } else {
+            let t = (self.load - start) / (1.0 - start);
+            c.peak_amps + t * (c.continuous_amps - c.peak_amps)
+        }
+    }
+
+    /// Largest duty magnitude, 0.0..=1.0, that stays within `current_limit`
+    /// with the estimated current. None for `Measured`, where the limit has to
+    /// be applied to the measured current by a current loop instead.
+    pub fn duty_limit(&self, supply_volts: f32) -> Option<f32> {
+        match self.config.source {
+            CurrentSource::Measured => None,
+            CurrentSource::Estimated { winding_ohms } if supply_volts > 0.0 => Some(clamp(
+                self.current_limit() * winding_ohms / supply_volts,
+                0.0,
+                1.0,
+            )),
+            // no supply reading, nothing to scale by
+            CurrentSource::Estimated { .. } => Some(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.001;
+
+    fn model(source: CurrentSource) -> I2tModel {
+        I2tModel::new(I2tConfig {
+            continuous_amps: 1.0,
+            peak_amps: 2.0,
+            time_constant_s: 10.0,
+            rated_rise_celsius: 60.0,
+            limit_start: 0.5,
+            source,
+        })
+    }
+
+    fn run(model: &mut I2tModel, amps: f32, seconds: f32) -> f32 {
+        for _ in 0..(seconds / DT) as usize {
+            model.update(amps, DT);
+        }
+        model.load()
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn step_at_the_continuous_rating() {
+        let mut m = model(CurrentSource::Measured);
+        // 1 - e^-1 after one time constant
+        assert_close(run(&mut m, 1.0, 10.0), 0.632, 0.002);
+        assert_close(run(&mut m, 1.0, 90.0), 1.0, 0.001);
+        assert_close(m.winding_celsius(25.0), 85.0, 0.1);
+    }
+
+    #[test]
+    fn load_follows_the_square_of_the_current() {
+        let mut m = model(CurrentSource::Measured);
+        assert_close(run(&mut m, -2.0, 100.0), 4.0, 0.004);
+        let mut m = model(CurrentSource::Measured);
+        assert_close(run(&mut m, 0.5, 100.0), 0.25, 0.001);
+    }
+
+    #[test]
+    fn cools_down_with_the_same_time_constant() {
+        let mut m = model(CurrentSource::Measured);
+        run(&mut m, 1.0, 100.0);
+        assert_close(run(&mut m, 0.0, 10.0), 0.368, 0.002);
+        assert_close(m.winding_celsius(25.0), 25.0 + 0.368 * 60.0, 0.2);
+    }
+
+    #[test]
+    fn long_steps_do_not_overshoot() {
+        let mut m = model(CurrentSource::Measured);
+        for _ in 0..10 {
+            let load = m.update(1.0, 1000.0);
+            assert!(load <= 1.0);
+        }
+        assert_close(m.load(), 1.0, 0.001);
+    }
+
+    #[test]
+    fn current_limit_comes_down_with_the_load() {
+        let mut m = model(CurrentSource::Measured);
+        assert_eq!(m.current_limit(), 2.0);
+        // from limit_start at 0.5 to the continuous rating at 1.0
+        let mut last = 2.0;
+        while m.load() < 1.0 {
+            m.update(1.5, 0.1);
+            let limit = m.current_limit();
+            assert!(limit <= last);
+            if m.load() <= 0.5 {
+                assert_eq!(limit, 2.0);
+            } else if m.load() < 1.0 {
+                assert_close(limit, 2.0 - (m.load() - 0.5) * 2.0, 1e-4);
+            }
+            last = limit;
+        }
+        assert_eq!(m.current_limit(), 1.0);
+    }
+
+    #[test]
+    fn holding_the_limit_settles_at_the_rated_rise() {
+        let mut m = model(CurrentSource::Measured);
+        for _ in 0..(200.0 / DT) as usize {
+            let amps = m.current_limit();
+            m.update(amps, DT);
+        }
+        assert!(m.load() <= 1.0 + 1e-3, "{}", m.load());
+        assert_close(m.winding_celsius(20.0), 80.0, 0.5);
+    }
+
+    #[test]
+    fn duty_limit_only_with_the_estimate() {
+        let m = model(CurrentSource::Measured);
+        assert_eq!(m.duty_limit(12.0), None);
+
+        let mut m = model(CurrentSource::Estimated { winding_ohms: 3.0 });
+        // 2A peak through 3 ohms out of 12V
+        assert_close(m.duty_limit(12.0).unwrap(), 0.5, 1e-6);
+        assert_eq!(m.duty_limit(0.0), Some(0.0));
+        assert_eq!(m.duty_limit(3.0), Some(1.0));
+        run(&mut m, 2.0, 100.0);
+        assert_close(m.duty_limit(12.0).unwrap(), 0.25, 1e-6);
+    }
+
+    #[test]
+    fn estimated_current() {
+        let m = model(CurrentSource::Estimated { winding_ohms: 4.0 });
+        assert_close(m.amps(0.1, -0.5, 12.0), -1.5, 1e-6);
+        let m = model(CurrentSource::Estimated { winding_ohms: 0.0 });
+        assert_eq!(m.amps(0.1, 0.1, 12.0), 2.0);
+        let m = model(CurrentSource::Measured);
+        assert_eq!(m.amps(0.7, 1.0, 12.0), 0.7);
+    }
+}
@@ -3,3 +3,22 @@ pub trait Indicator {
     fn off(&self);
     fn toggle(&self);
 }
+
+/// A missing indicator, for a pin that could not be set up.
+impl<T: Indicator> Indicator for Option<T> {
+    fn on(&self) {
+        if let Some(led) = self {
+            led.on();
+        }
+    }
+    fn off(&self) {
+        if let Some(led) = self {
+            led.off();
+        }
+    }
+    fn toggle(&self) {
+        if let Some(led) = self {
+            led.toggle();
+        }
+    }
+}
@@ -0,0 +1,21 @@
+//! Hardware independent parts of the firmware: protocol, control table,
+//! control loops and models. Builds for the host as well, for the tests.
+#![cfg_attr(not(test), no_std)]
+
+#[macro_use]
+mod log;
+
+pub mod app;
+pub mod config_store;
+pub mod control_table;
+pub mod current;
+pub mod dc_motor_driver;
+pub mod dynamixel;
+pub mod encoder;
+pub mod i2t;
+pub mod indicator;
+pub mod pid;
+pub mod supply;
+pub mod temperature;
+pub mod velocity;
+pub mod watchdog;
@@ -0,0 +1,35 @@
+//! defmt logging. The host tests have no defmt logger to link against, there
+//! the messages are only type checked.
+
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        #[cfg(not(test))]
+        defmt::info!($($arg)*);
+        #[cfg(test)]
+        if false {
+            std::println!($($arg)*);
+        }
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(not(test))]
+        defmt::warn!($($arg)*);
+        #[cfg(test)]
+        if false {
+            std::println!($($arg)*);
+        }
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        #[cfg(not(test))]
+        defmt::error!($($arg)*);
+        #[cfg(test)]
+        if false {
+            std::println!($($arg)*);
+        }
+    }};
+}
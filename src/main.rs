@@ -1,50 +1,160 @@
 #![no_std]
 #![no_main]
 
-use indicator::Indicator;
-// pick a panicking behavior
-use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
-                     // use panic_abort as _; // requires nightly
-                     // use panic_itm as _; // logs messages over ITM; requires ITM support
-                     // use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
 use defmt_rtt as _;
 
 use core::cell::RefCell;
-use core::fmt::Write;
-use core::ops::DerefMut;
+use core::panic::PanicInfo;
 
 use cortex_m::interrupt::{free, Mutex};
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception, ExceptionFrame};
 
 use stm32g0::stm32g030::interrupt;
-use stm32g0::stm32g030::Interrupt::EXTI0_1;
-use stm32g0::stm32g030::Interrupt::TIM14;
 
-use crate::dc_motor_driver::DcMotorDriver;
+use motor_core::{app, config_store, control_table, dynamixel, temperature, watchdog};
 
-mod app;
-mod indicator;
+mod crash;
 mod dc_motor_driver_stm32g0;
-mod dc_motor_driver;
-
-static G_APP: Mutex<
-    RefCell<
-        Option<
-            app::App<
-                dc_motor_driver_stm32g0::Led0,
-                dc_motor_driver_stm32g0::Led1,
-            >,
-        >,
-    >,
-> = Mutex::new(RefCell::new(None));
+
+type DriverApp = app::App<
+    Option<dc_motor_driver_stm32g0::Led0>,
+    Option<dc_motor_driver_stm32g0::Led1>,
+    Option<dc_motor_driver_stm32g0::DcPwm>,
+    Option<dc_motor_driver_stm32g0::EncoderPeripheral>,
+>;
+
+static G_APP: dc_motor_driver_stm32g0::ControlCell<DriverApp> =
+    dc_motor_driver_stm32g0::ControlCell::new();
+
+static G_SUPERVISOR: Mutex<RefCell<Option<watchdog::Supervisor<dc_motor_driver_stm32g0::Iwdg>>>> =
+    Mutex::new(RefCell::new(None));
+
+const WATCHDOG_TIMEOUT_MS: u32 = 100;
+/// 48MHz oscillator on OSC_IN, PLL up to 64MHz. APB undivided, the USART
+/// and ADC run off the full 64MHz.
+const CLOCK_CONFIG: dc_motor_driver_stm32g0::ClockConfig =
+    dc_motor_driver_stm32g0::ClockConfig::new(dc_motor_driver_stm32g0::ClockSource::HseBypass {
+        hz: 48_000_000,
+    })
+    .sysclk(64_000_000)
+    .apb_divider(1);
+/// Gate driver of the discrete bridge on the LQFP48 board.
+#[cfg(feature = "lqfp48")]
+const DEAD_TIME_NS: u32 = 200;
+/// Led1 blinks when the peripherals cannot be taken, following the
+/// Panic (1) and HardFault (2) codes of `crash::CrashKind`.
+const INIT_FAILED_BLINKS: u32 = 3;
+
+fn init_failed<T>(e: dc_motor_driver_stm32g0::InitError) -> T {
+    defmt::error!("init failed: {}", e as u8);
+    dc_motor_driver_stm32g0::fault_blink_and_reset(INIT_FAILED_BLINKS)
+}
+
+/// A driver that did not come up is left out, the first error is kept for the host.
+fn init_or_disable<T>(
+    result: Result<T, dc_motor_driver_stm32g0::InitError>,
+    first_error: &mut Option<dc_motor_driver_stm32g0::InitError>,
+) -> Option<T> {
+    match result {
+        Ok(driver) => Some(driver),
+        Err(e) => {
+            defmt::error!("init failed: {}, running without it", e as u8);
+            first_error.get_or_insert(e);
+            None
+        }
+    }
+}
+
+fn check_in(task: watchdog::Task) {
+    let now = dc_motor_driver_stm32g0::micros();
+    free(|cs| {
+        if let Some(supervisor) = G_SUPERVISOR.borrow(cs).borrow_mut().as_mut() {
+            supervisor.check_in(task, now);
+        }
+    });
+}
 
 // 4Mbps = 0.25us = 250ns
 // 0.25 x 8bit(1Byte) x 4? = 8us?
 
+// 1kHz
+#[interrupt]
+fn TIM16() {
+    dc_motor_driver_stm32g0::tick_interrupt_task();
+    // SAFETY: at the current loop's priority
+    unsafe {
+        G_APP.borrow_from_interrupt(|app| {
+            app.control_task();
+            let adc = dc_motor_driver_stm32g0::adc_latest();
+            app.temperature_task(
+                adc[dc_motor_driver_stm32g0::adc_channel::TEMPERATURE],
+                adc[dc_motor_driver_stm32g0::adc_channel::NTC],
+            );
+            if dc_motor_driver_stm32g0::clock_failure() {
+                app.clock_failure();
+            }
+        })
+    };
+    check_in(watchdog::Task::ControlLoop);
+}
 
 #[interrupt]
-fn TIM14() {
-    dc_motor_driver_stm32g0::timer_interrupt_task();
+fn TIM17() {
+    dc_motor_driver_stm32g0::micros_interrupt_task();
+}
+
+#[interrupt]
+fn TIM3() {
+    dc_motor_driver_stm32g0::encoder_interrupt_task();
+}
+
+#[interrupt]
+fn DMA_CHANNEL1() {
+    if let Some(adc) = dc_motor_driver_stm32g0::adc_dma_interrupt_task() {
+        check_in(watchdog::Task::CurrentLoop);
+        let dt = dc_motor_driver_stm32g0::CURRENT_LOOP_DECIMATION as f32
+            / dc_motor_driver_stm32g0::pwm_frequency_hz() as f32;
+        // SAFETY: at the control tick's priority
+        unsafe {
+            G_APP.borrow_from_interrupt(|app| {
+                app.supply_task(adc[dc_motor_driver_stm32g0::adc_channel::SUPPLY]);
+                app.current_task(adc[dc_motor_driver_stm32g0::adc_channel::CURRENT], dt);
+            })
+        };
+    }
+}
+
+#[cfg(feature = "usart1")]
+#[interrupt]
+fn USART1() {
+    dc_motor_driver_stm32g0::usart_interrupt_task(dc_motor_driver_stm32g0::UsartPort::Usart1);
+}
+
+#[cfg(not(feature = "usart1"))]
+#[interrupt]
+fn USART2() {
+    dc_motor_driver_stm32g0::usart_interrupt_task(dc_motor_driver_stm32g0::UsartPort::Usart2);
+}
+
+// The bridge goes off first, everything else may fail again.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    dc_motor_driver_stm32g0::emergency_stop();
+    crash::record_panic();
+    dc_motor_driver_stm32g0::fault_blink_and_reset(crash::CrashKind::Panic as u32)
+}
+
+#[exception]
+unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
+    dc_motor_driver_stm32g0::emergency_stop();
+    crash::record_hard_fault(ef.pc());
+    dc_motor_driver_stm32g0::fault_blink_and_reset(crash::CrashKind::HardFault as u32)
+}
+
+#[exception]
+unsafe fn NonMaskableInt() {
+    dc_motor_driver_stm32g0::clock_security_task();
 }
 
 #[entry]
@@ -52,86 +162,253 @@ fn main() -> ! {
     use stm32g0::stm32g030;
 
     defmt::info!("Hello from STM32G0!");
+    let last_crash = crash::take();
+    if let Some(crash) = &last_crash {
+        match crash.kind() {
+            crash::CrashKind::Panic => defmt::error!("last run panicked"),
+            crash::CrashKind::HardFault => {
+                defmt::error!("last run hit a HardFault at {=u32:#x}", crash.address())
+            }
+        }
+    }
     // stm32f401モジュールより、ペリフェラルの入り口となるオブジェクトを取得する。
-    let perip = stm32g030::Peripherals::take().unwrap();
-    let mut core_perip = stm32g030::CorePeripherals::take().unwrap();
+    let (perip, mut core_perip) = match (
+        stm32g030::Peripherals::take(),
+        stm32g030::CorePeripherals::take(),
+    ) {
+        (Some(perip), Some(core_perip)) => (perip, core_perip),
+        _ => init_failed(dc_motor_driver_stm32g0::InitError::PeripheralUnavailable),
+    };
 
-    dc_motor_driver_stm32g0::clock_init(&perip, &mut core_perip);
+    // 失敗しても HSI16 で動作を続ける
+    let clock_error =
+        match dc_motor_driver_stm32g0::clock_init(&perip, &mut core_perip, CLOCK_CONFIG) {
+            Ok(clocks) => {
+                defmt::info!("SYSCLK {}Hz, PCLK {}Hz", clocks.sysclk_hz, clocks.pclk_hz);
+                None
+            }
+            Err(e) => {
+                defmt::error!("clock init failed: {}, running on HSI16", e as u8);
+                Some(e)
+            }
+        };
     // dc_motor_driver_stm32g0::exti_init(&perip, &mut core_perip);
 
-    // init g peripheral
-    dc_motor_driver_stm32g0::init_g_peripheral(perip);
-
-    let led0 = dc_motor_driver_stm32g0::Led0::new();
-    led0.init();
-    led0.off();
-    let led1 = dc_motor_driver_stm32g0::Led1::new();
-    led1.init();
-    led1.off();
-    let md = dc_motor_driver_stm32g0::DcPwm::new();
-    md.init();
-    let enc = dc_motor_driver_stm32g0::EncoderPeripheral::new();
-    enc.init();
-
-    md.set_pwm(1.0, 0.5);
-    let app = app::App::new(led0, led1);
-    free(|cs| G_APP.borrow(cs).replace(Some(app)));
-
-    let mut t = 0;
-    free(|cs| {
-        match dc_motor_driver_stm32g0::G_PERIPHERAL
-            .borrow(cs)
-            .borrow()
-            .as_ref()
-        {
-            None => (),
-            Some(perip) => {
-                t = perip.TIM16.cnt.read().cnt().bits();
-                // t = perip.TIM14.cnt.read().cnt().bits();
-            }
-        }
+    // ペリフェラルを機能ごとに分けて各ドライバに渡す
+    let board = dc_motor_driver_stm32g0::Board::split(perip);
+    let pins = board.pins;
+    let reset_cause = dc_motor_driver_stm32g0::reset_cause();
+    defmt::info!("reset cause: {}", reset_cause as u8);
+
+    let mut driver_error = None;
+    let led0 = init_or_disable(
+        dc_motor_driver_stm32g0::Led0::new(pins.pa4),
+        &mut driver_error,
+    );
+    let led1 = init_or_disable(
+        dc_motor_driver_stm32g0::Led1::new(pins.pa5),
+        &mut driver_error,
+    );
+    let md = init_or_disable(
+        dc_motor_driver_stm32g0::DcPwm::new(
+            board.tim1,
+            pins.pa8,
+            pins.pb3,
+            #[cfg(not(feature = "lqfp48"))]
+            dc_motor_driver_stm32g0::BridgeTopology::IntegratedDriver,
+            #[cfg(feature = "lqfp48")]
+            dc_motor_driver_stm32g0::BridgeTopology::DiscreteBridge {
+                dead_time_ns: DEAD_TIME_NS,
+                ch1n: pins.pb13,
+                ch2n: pins.pb14,
+            },
+            dc_motor_driver_stm32g0::BreakInputs {
+                // nFAULT of the discrete bridge's gate driver
+                #[cfg(feature = "lqfp48")]
+                bkin: Some((dc_motor_driver_stm32g0::Polarity::ActiveLow, pins.pb12)),
+                #[cfg(not(feature = "lqfp48"))]
+                bkin: None,
+                bkin2: None,
+            },
+        ),
+        &mut driver_error,
+    );
+    // sampling trigger on PA11 to line the ADC up against the bridge on a scope
+    let pa11 = pins.pa11;
+    let _sync = md
+        .as_ref()
+        .and_then(|md| init_or_disable(md.sync_output(pa11), &mut driver_error));
+    let enc = init_or_disable(
+        dc_motor_driver_stm32g0::EncoderPeripheral::new(
+            board.tim3,
+            pins.pa6,
+            pins.pa7,
+            &board.dma,
+            &board.dmamux,
+        ),
+        &mut driver_error,
+    );
+    // without the ADC the current loop never runs and current is never
+    // calibrated, so torque stays off
+    let adc = init_or_disable(
+        dc_motor_driver_stm32g0::AdcPeripheral::new(
+            board.adc,
+            board.dma,
+            board.dmamux,
+            dc_motor_driver_stm32g0::AdcPins {
+                current: pins.pa0,
+                supply: pins.pa3,
+                ntc: pins.pb7,
+            },
+        ),
+        &mut driver_error,
+    );
+    #[cfg(feature = "usart1")]
+    let usart = dc_motor_driver_stm32g0::HalfDuplexUsart::usart1(board.usart1, pins.pb6, pins.pa12);
+    #[cfg(not(feature = "usart1"))]
+    let usart = dc_motor_driver_stm32g0::HalfDuplexUsart::usart2(board.usart2, pins.pa2, pins.pa1);
+    let usart = init_or_disable(usart, &mut driver_error);
+    let tick = dc_motor_driver_stm32g0::Tick::new(board.tim16);
+    let mut slave = dynamixel::Slave::new();
+    let mut tx = [0u8; dynamixel::MAX_PACKET_LEN];
+    // (received at, delay, length) of the status in `tx`
+    let mut reply: Option<(u32, u32, usize)> = None;
+
+    let encoder_missing = enc.is_none();
+    let mut app = app::App::new(led0, led1, md, enc);
+    if let Some(crash) = &last_crash {
+        app.report_crash(crash.kind() as u8, crash.address());
+    }
+    app.report_reset_cause(reset_cause);
+    if let Some(e) = clock_error {
+        app.report_init_error(e as u8);
+    }
+    if let Some(e) = driver_error {
+        app.report_init_failure(e as u8);
+    }
+    if encoder_missing {
+        app.set_hardware_error(control_table::hardware_error::MOTOR_ENCODER);
+    }
+    // no NTC fitted on this board
+    app.set_temperature_config(temperature::TemperatureConfig {
+        ts_cal1: dc_motor_driver_stm32g0::ts_cal1(),
+        ntc: None,
+        ..Default::default()
     });
+    let mut config_store =
+        config_store::ConfigStore::new(dc_motor_driver_stm32g0::ConfigFlash::new(board.flash));
+    let mut config_buf = [0u8; 256];
+    match config_store.load(&mut config_buf) {
+        Some(record) => app.restore_config(record.version, &config_buf[..record.len]),
+        None => defmt::info!("no saved config, using defaults"),
+    }
+    if let Some(usart) = &usart {
+        usart.init(app.baud_rate());
+    }
+    G_APP.init(app);
+
+    // deadlines in Task order: current loop, control loop, communication
+    let supervisor = watchdog::Supervisor::new(
+        dc_motor_driver_stm32g0::Iwdg::new(board.iwdg, board.dbg),
+        [5_000, 5_000, 50_000],
+    );
+    supervisor.start(WATCHDOG_TIMEOUT_MS);
+    free(|cs| G_SUPERVISOR.borrow(cs).replace(Some(supervisor)));
+
+    let mut t = tick.count();
     let mut prev = t;
+    let mut prev_tick = t;
 
     loop {
-        free(|cs| {
-            match dc_motor_driver_stm32g0::G_PERIPHERAL
-                .borrow(cs)
-                .borrow()
-                .as_ref()
-            {
-                None => (),
-                Some(perip) => {
-                    t = perip.TIM16.cnt.read().cnt().bits();
-                    // t = perip.TIM14.cnt.read().cnt().bits();
+        match &usart {
+            Some(usart) => {
+                // the reply goes out from the USART interrupt once its delay is
+                // over, a new baud rate or a reboot waits until it has
+                while !usart.busy() {
+                    match reply {
+                        Some((since, delay_us, n)) => {
+                            if dc_motor_driver_stm32g0::micros().wrapping_sub(since) >= delay_us {
+                                usart.write(&tx[..n]);
+                                reply = None;
+                                continue;
+                            }
+                        }
+                        None => {
+                            let baud_rate =
+                                G_APP.lock(|app| app.take_pending_baud_rate()).flatten();
+                            if let Some(baud_rate) = baud_rate {
+                                usart.init(baud_rate);
+                            }
+                            if slave.take_reboot_request() {
+                                cortex_m::peripheral::SCB::sys_reset();
+                            }
+                        }
+                    }
+                    let byte = match usart.read() {
+                        Some(byte) => byte,
+                        None => break,
+                    };
+                    // a newer reply replaces one still waiting
+                    let (n, delay_us) = G_APP
+                        .lock(|app| {
+                            let n = slave.receive(byte, app, &mut tx);
+                            let delay_us =
+                                app.return_delay_us() + slave.take_reply_delay_us(app.baud_rate());
+                            (n, delay_us)
+                        })
+                        .unwrap_or((0, 0));
+                    if n > 0 {
+                        reply = Some((dc_motor_driver_stm32g0::micros(), delay_us, n));
+                    }
+                }
+                // received bytes drained and the reply, if any, on time
+                if !usart.stalled() {
+                    check_in(watchdog::Task::Communication);
                 }
             }
-        });
+            // nothing to poll
+            None => check_in(watchdog::Task::Communication),
+        }
 
+        t = tick.count();
 
-        if t.wrapping_sub(prev) > 500 {
-            free(|cs| match G_APP.borrow(cs).borrow_mut().deref_mut() {
-                None => (),
-                Some(app) => {
-                    app.periodic_task();
-                }
-            });
+        // 1kHz, the control task itself runs from the TIM16 interrupt
+        if t != prev_tick {
+            if adc.is_none() {
+                // no current loop to supervise
+                check_in(watchdog::Task::CurrentLoop);
+            }
+            prev_tick = t;
 
+            let now = dc_motor_driver_stm32g0::micros();
             free(|cs| {
-                match dc_motor_driver_stm32g0::G_PERIPHERAL
-                    .borrow(cs)
-                    .borrow()
-                    .as_ref()
-                {
-                    None => (),
-                    Some(perip) => {
-                        let cnt_l = perip.TIM3.cnt.read().cnt_l().bits();
-                        let cnt_h = perip.TIM3.cnt.read().cnt_h().bits();
-                        defmt::info!("cnt_l: {}, cnt_h: {}", cnt_l, cnt_h);
+                if let Some(supervisor) = G_SUPERVISOR.borrow(cs).borrow().as_ref() {
+                    if let Err(task) = supervisor.supervise(now) {
+                        defmt::error!("watchdog: task {} is late", task as u8);
                     }
                 }
             });
+        }
+
+        if t.wrapping_sub(prev) > 500 {
+            G_APP.lock(|app| app.periodic_task());
 
+            // at most every 500ms, so a burst of writes ends up in one record.
+            // Runs with torque off only: a page erase stalls the CPU for up to
+            // 40ms, and bytes arriving meanwhile overrun the USART, so the host
+            // sees a missed reply and retries
+            let config = G_APP
+                .lock(|app| app.config_to_save(&mut config_buf))
+                .flatten();
+            if let Some(len) = config {
+                match config_store.save(control_table::CONFIG_VERSION, &config_buf[..len]) {
+                    Ok(()) => {
+                        G_APP.lock(|app| app.config_saved());
+                    }
+                    // still pending, tried again next time
+                    Err(_) => defmt::error!("config save failed"),
+                }
+            }
 
             prev = t;
         }
@@ -0,0 +1,321 @@
+//! PID controller and setpoint ramp used by the control loops in `app`.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AntiWindup {
+    None,
+    /// Stop integrating while the output is saturated in the direction of the error.
+    Conditional,
+    /// Bleed the integrator by `kt * (saturated - unsaturated)`.
+    BackCalculation {
+        kt: f32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+    pub anti_windup: AntiWindup,
+    /// Low-pass on the derivative term, 1.0 disables filtering.
+    pub derivative_alpha: f32,
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        Self {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            output_min: -1.0,
+            output_max: 1.0,
+            anti_windup: AntiWindup::Conditional,
+            derivative_alpha: 1.0,
+        }
+    }
+}
+
+pub struct Pid {
+    config: PidConfig,
+    integral: f32,
+    derivative: f32,
+    last_measurement: Option<f32>,
+    output: f32,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            derivative: 0.0,
+            last_measurement: None,
+            output: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> &PidConfig {
+        &self.config
+    }
+
+    /// Gains and limits may change on every call without a bump in the output,
+    /// since the integral is kept in output units.
+    pub fn set_config(&mut self, config: PidConfig) {
+        self.config = config;
+    }
+
+    /// Restarts so that the next output starts from `output`.
+    pub fn reset(&mut self, output: f32) {
+        self.integral = clamp(output, self.config.output_min, self.config.output_max);
+        self.derivative = 0.0;
+        self.last_measurement = None;
+        self.output = self.integral;
+    }
+
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    pub fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        let c = self.config;
+        let error = setpoint - measurement;
+
+        // derivative on measurement, so setpoint steps do not kick the output
+        let derivative = match self.last_measurement {
+            Some(last) if dt > 0.0 => -(measurement - last) / dt,
+            _ => 0.0,
+        };
+        self.last_measurement = Some(measurement);
+        self.derivative += c.derivative_alpha * (derivative - self.derivative);
+
+        let unsaturated = c.kp * error + self.integral + c.kd * self.derivative;
+        let output = clamp(unsaturated, c.output_min, c.output_max);
+
+        match c.anti_windup {
+            AntiWindup::None => self.integral += c.ki * error * dt,
+            AntiWindup::Conditional => {
+                let pushing_further = (unsaturated > c.output_max && error > 0.0)
+                    || (unsaturated < c.output_min && error < 0.0);
+                if !pushing_further {
+                    self.integral += c.ki * error * dt;
+                }
+            }
+            AntiWindup::BackCalculation { kt } => {
+                self.integral += (c.ki * error + kt * (output - unsaturated)) * dt;
+            }
+        }
+
+        self.output = output;
+        output
+    }
+}
+
+/// Rate limiter for setpoints.
+pub struct Ramp {
+    value: f32,
+}
+
+impl Ramp {
+    pub const fn new(value: f32) -> Self {
+        Self { value }
+    }
+
+    pub fn reset(&mut self, value: f32) {
+        self.value = value;
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Moves towards `target` by at most `rate * dt`. A rate of 0 means no limit.
+    pub fn update(&mut self, target: f32, rate: f32, dt: f32) -> f32 {
+        if rate <= 0.0 {
+            self.value = target;
+        } else {
+            let step = rate * dt;
+            self.value += clamp(target - self.value, -step, step);
+        }
+        self.value
+    }
+}
+
+pub fn clamp(value: f32, min: f32, max: f32) -> f32 {
+    if value < min {
+        min
+    } else if value > max {
+        max
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.001;
+
+    /// Motor speed as a first order lag, `gain` rad/s at full output.
+    struct Plant {
+        gain: f32,
+        tau: f32,
+        y: f32,
+    }
+
+    impl Plant {
+        fn new() -> Self {
+            Self {
+                gain: 100.0,
+                tau: 0.05,
+                y: 0.0,
+            }
+        }
+
+        fn step(&mut self, u: f32) -> f32 {
+            self.y += (self.gain * u - self.y) * DT / self.tau;
+            self.y
+        }
+    }
+
+    fn pi(anti_windup: AntiWindup) -> Pid {
+        Pid::new(PidConfig {
+            kp: 0.02,
+            ki: 0.4,
+            anti_windup,
+            ..PidConfig::default()
+        })
+    }
+
+    /// Runs the loop for `seconds`, returns the largest plant output seen.
+    fn run(pid: &mut Pid, plant: &mut Plant, setpoint: f32, seconds: f32) -> f32 {
+        let mut peak = f32::MIN;
+        for _ in 0..(seconds / DT) as usize {
+            let u = pid.update(setpoint, plant.y, DT);
+            assert!((-1.0..=1.0).contains(&u));
+            peak = peak.max(plant.step(u));
+        }
+        peak
+    }
+
+    /// Stalls the motor for a second with the output saturated, then releases
+    /// it and returns the overshoot above the setpoint.
+    fn overshoot_after_stall(anti_windup: AntiWindup) -> f32 {
+        let mut pid = pi(anti_windup);
+        let mut plant = Plant::new();
+        plant.gain = 0.0;
+        run(&mut pid, &mut plant, 50.0, 1.0);
+        assert_eq!(pid.output(), 1.0);
+        plant.gain = 100.0;
+        run(&mut pid, &mut plant, 50.0, 2.0) - 50.0
+    }
+
+    #[test]
+    fn settles_without_steady_state_error() {
+        for &anti_windup in &[
+            AntiWindup::None,
+            AntiWindup::Conditional,
+            AntiWindup::BackCalculation { kt: 20.0 },
+        ] {
+            let mut pid = pi(anti_windup);
+            let mut plant = Plant::new();
+            run(&mut pid, &mut plant, 40.0, 1.0);
+            assert!(
+                (plant.y - 40.0).abs() < 0.01,
+                "{:?}: {}",
+                anti_windup,
+                plant.y
+            );
+        }
+    }
+
+    #[test]
+    fn anti_windup_limits_the_overshoot() {
+        // 積分が飽和中に溜まると設定値を下げた後に大きく行き過ぎる
+        let none = overshoot_after_stall(AntiWindup::None);
+        assert!(none > 40.0, "{}", none);
+        let conditional = overshoot_after_stall(AntiWindup::Conditional);
+        assert!(conditional < 1.0, "{}", conditional);
+        // kt = ki/kp の程度では一部残る、kt を上げるほど小さくなる
+        let back_calculation = overshoot_after_stall(AntiWindup::BackCalculation { kt: 20.0 });
+        assert!(back_calculation < none / 3.0, "{}", back_calculation);
+        let fast = overshoot_after_stall(AntiWindup::BackCalculation { kt: 200.0 });
+        assert!(fast < back_calculation / 4.0, "{}", fast);
+    }
+
+    #[test]
+    fn saturated_output_recovers_at_once() {
+        for &anti_windup in &[
+            AntiWindup::Conditional,
+            AntiWindup::BackCalculation { kt: 20.0 },
+        ] {
+            let mut pid = pi(anti_windup);
+            let mut plant = Plant::new();
+            run(&mut pid, &mut plant, 150.0, 2.0);
+            // within a few periods of the setpoint coming back in range
+            let mut periods = 0;
+            while pid.update(50.0, plant.y, DT) >= 1.0 {
+                plant.step(pid.output());
+                periods += 1;
+                assert!(periods < 20, "{:?}", anti_windup);
+            }
+        }
+    }
+
+    #[test]
+    fn derivative_ignores_setpoint_steps() {
+        let mut pid = Pid::new(PidConfig {
+            kd: 0.01,
+            output_min: -100.0,
+            output_max: 100.0,
+            ..PidConfig::default()
+        });
+        pid.update(0.0, 10.0, DT);
+        assert_eq!(pid.update(1000.0, 10.0, DT), 0.0);
+        assert!((pid.update(1000.0, 11.0, DT) + 10.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reset_is_bumpless() {
+        let mut pid = pi(AntiWindup::Conditional);
+        pid.reset(0.3);
+        assert_eq!(pid.output(), 0.3);
+        assert!((pid.update(20.0, 20.0, DT) - 0.3).abs() < 1e-6);
+        pid.reset(5.0);
+        assert_eq!(pid.output(), 1.0);
+    }
+
+    #[test]
+    fn ramp_limits_the_rate() {
+        let mut ramp = Ramp::new(0.0);
+        let mut last = 0.0;
+        for _ in 0..500 {
+            let value = ramp.update(100.0, 400.0, DT);
+            assert!(value - last <= 400.0 * DT + 1e-4);
+            last = value;
+        }
+        assert_eq!(ramp.update(100.0, 400.0, DT), 100.0);
+        for _ in 0..1000 {
+            ramp.update(-50.0, 400.0, DT);
+        }
+        assert_eq!(ramp.value(), -50.0);
+        assert_eq!(ramp.update(70.0, 0.0, DT), 70.0);
+    }
+
+    #[test]
+    fn ramped_setpoint_is_followed_without_saturation() {
+        let mut pid = pi(AntiWindup::Conditional);
+        let mut plant = Plant::new();
+        let mut ramp = Ramp::new(0.0);
+        for _ in 0..1000 {
+            let setpoint = ramp.update(80.0, 200.0, DT);
+            let u = pid.update(setpoint, plant.y, DT);
+            assert!(u < 1.0);
+            plant.step(u);
+            assert!(plant.y <= 80.5);
+        }
+        assert!((plant.y - 80.0).abs() < 0.5, "{}", plant.y);
+    }
+}
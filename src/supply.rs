@@ -0,0 +1,144 @@
+//! Motor supply rail measurement and under/over-voltage detection.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SupplyConfig {
+    /// Divider and ADC scale in volts per ADC count.
+    pub volts_per_count: f32,
+    pub offset_volts: f32,
+    /// v += alpha * (raw - v)
+    pub filter_alpha: f32,
+    /// A fault clears this far back inside the limit.
+    pub hysteresis_volts: f32,
+}
+
+impl Default for SupplyConfig {
+    fn default() -> Self {
+        // 3.3V / 4096, 100k/10k divider
+        Self {
+            volts_per_count: 3.3 / 4096.0 * 11.0,
+            offset_volts: 0.0,
+            filter_alpha: 0.05,
+            hysteresis_volts: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SupplyFault {
+    Undervoltage,
+    Overvoltage,
+}
+
+pub struct SupplyMonitor {
+    config: SupplyConfig,
+    volts: Option<f32>,
+    fault: Option<SupplyFault>,
+}
+
+impl SupplyMonitor {
+    pub fn new(config: SupplyConfig) -> Self {
+        Self {
+            config,
+            volts: None,
+            fault: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: SupplyConfig) {
+        self.config = config;
+    }
+
+    /// Filtered supply voltage, 0 before the first sample.
+    pub fn volts(&self) -> f32 {
+        self.volts.unwrap_or(0.0)
+    }
+
+    pub fn fault(&self) -> Option<SupplyFault> {
+        self.fault
+    }
+
+    /// Feeds one sample and checks it against `min..=max` volts.
+    pub fn update(&mut self, raw: u16, min: f32, max: f32) -> Option<SupplyFault> {
+        let c = self.config;
+        let sample = raw as f32 * c.volts_per_count + c.offset_volts;
+        let v = match self.volts {
+            None => sample,
+            Some(v) => v + c.filter_alpha * (sample - v),
+        };
+        self.volts = Some(v);
+
+        self.fault = match self.fault {
+            Some(SupplyFault::Undervoltage) if v < min + c.hysteresis_volts => {
+                Some(SupplyFault::Undervoltage)
+            }
+            Some(SupplyFault::Overvoltage) if v > max - c.hysteresis_volts => {
+                Some(SupplyFault::Overvoltage)
+            }
+            _ if v < min => Some(SupplyFault::Undervoltage),
+            _ if v > max => Some(SupplyFault::Overvoltage),
+            _ => None,
+        };
+        self.fault
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: f32 = 9.5;
+    const MAX: f32 = 16.0;
+
+    /// 10mV per count, so `raw` reads in hundredths of a volt.
+    fn monitor(filter_alpha: f32) -> SupplyMonitor {
+        SupplyMonitor::new(SupplyConfig {
+            volts_per_count: 0.01,
+            offset_volts: 0.0,
+            filter_alpha,
+            hysteresis_volts: 0.5,
+        })
+    }
+
+    #[test]
+    fn undervoltage_clears_past_the_hysteresis() {
+        let mut m = monitor(1.0);
+        assert_eq!(m.update(1200, MIN, MAX), None);
+        assert_eq!(m.update(940, MIN, MAX), Some(SupplyFault::Undervoltage));
+        // back above the limit, not yet past the hysteresis
+        assert_eq!(m.update(970, MIN, MAX), Some(SupplyFault::Undervoltage));
+        assert_eq!(m.update(1010, MIN, MAX), None);
+        assert_eq!(m.fault(), None);
+    }
+
+    #[test]
+    fn overvoltage_clears_past_the_hysteresis() {
+        let mut m = monitor(1.0);
+        assert_eq!(m.update(1650, MIN, MAX), Some(SupplyFault::Overvoltage));
+        assert_eq!(m.update(1580, MIN, MAX), Some(SupplyFault::Overvoltage));
+        assert_eq!(m.update(1540, MIN, MAX), None);
+    }
+
+    #[test]
+    fn filtered_dip_trips_late_and_recovers() {
+        let mut m = monitor(0.05);
+        // the first sample seeds the filter
+        assert_eq!(m.update(1200, MIN, MAX), None);
+        assert_eq!(m.volts(), 12.0);
+
+        // supply gone: 12V * 0.95^n drops below 9.5V on the 5th sample
+        for _ in 0..4 {
+            assert_eq!(m.update(0, MIN, MAX), None);
+        }
+        assert_eq!(m.update(0, MIN, MAX), Some(SupplyFault::Undervoltage));
+
+        // supply back: held until the filtered value passes 10V
+        let mut samples = 0;
+        while m.update(1200, MIN, MAX).is_some() {
+            assert!(m.volts() < MIN + 0.5);
+            samples += 1;
+            assert!(samples < 100);
+        }
+        assert!(samples > 0);
+        assert!(m.volts() >= MIN + 0.5);
+    }
+}
@@ -0,0 +1,259 @@
+//! MCU and driver/motor temperature, derating and over-temperature shutdown.
+
+/// VDDA the ADC runs from.
+const VDDA: f32 = 3.3;
+/// Factory calibration: TS_CAL1 taken at 30°C with VDDA = 3.0V.
+const TS_CAL1_CELSIUS: f32 = 30.0;
+const TS_CAL1_VDDA: f32 = 3.0;
+/// Average slope of the internal sensor, V/°C (datasheet).
+const TS_SLOPE: f32 = 0.0025;
+
+/// External NTC from the ADC input to ground, fixed resistor to VDDA.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NtcConfig {
+    pub r25_ohms: f32,
+    pub beta: f32,
+    pub pullup_ohms: f32,
+}
+
+impl Default for NtcConfig {
+    fn default() -> Self {
+        Self {
+            r25_ohms: 10_000.0,
+            beta: 3380.0,
+            pullup_ohms: 10_000.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TemperatureConfig {
+    /// TS_CAL1 raw value read from system memory.
+    pub ts_cal1: u16,
+    pub ntc: Option<NtcConfig>,
+    /// Derating starts this far below the shutdown temperature.
+    pub derating_band: f32,
+    /// Shutdown clears this far below the shutdown temperature.
+    pub hysteresis: f32,
+    /// v += alpha * (raw - v)
+    pub filter_alpha: f32,
+}
+
+impl Default for TemperatureConfig {
+    fn default() -> Self {
+        Self {
+            // nominal 30°C reading
+            ts_cal1: 1034,
+            ntc: None,
+            derating_band: 15.0,
+            hysteresis: 10.0,
+            filter_alpha: 0.01,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermalStatus {
+    pub mcu_celsius: f32,
+    pub ntc_celsius: Option<f32>,
+    /// 1.0 at or below the derating start, down to 0.0 at shutdown.
+    pub derating: f32,
+    pub shutdown: bool,
+}
+
+impl ThermalStatus {
+    /// Hottest of the sensors.
+    pub fn celsius(&self) -> f32 {
+        match self.ntc_celsius {
+            Some(ntc) if ntc > self.mcu_celsius => ntc,
+            _ => self.mcu_celsius,
+        }
+    }
+
+    pub fn is_derating(&self) -> bool {
+        self.derating < 1.0
+    }
+}
+
+pub struct TemperatureMonitor {
+    config: TemperatureConfig,
+    status: Option<ThermalStatus>,
+}
+
+impl TemperatureMonitor {
+    pub fn new(config: TemperatureConfig) -> Self {
+        Self {
+            config,
+            status: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: TemperatureConfig) {
+        self.config = config;
+        self.status = None;
+    }
+
+    /// Before the first update the status reads 25°C and no derating.
+    pub fn status(&self) -> ThermalStatus {
+        self.status.unwrap_or(ThermalStatus {
+            mcu_celsius: 25.0,
+            ntc_celsius: None,
+            derating: 1.0,
+            shutdown: false,
+        })
+    }
+
+    /// Feeds one reading of both channels against the shutdown temperature `limit`.
+    pub fn update(&mut self, mcu_raw: u16, ntc_raw: u16, limit: f32) -> ThermalStatus {
+        let c = self.config;
+        let mcu = self.mcu_celsius(mcu_raw);
+        let ntc = c.ntc.map(|ntc| ntc_celsius(&ntc, ntc_raw));
+        let mut status = match self.status {
+            None => ThermalStatus {
+                mcu_celsius: mcu,
+                ntc_celsius: ntc,
+                derating: 1.0,
+                shutdown: false,
+            },
+            Some(s) => ThermalStatus {
+                mcu_celsius: s.mcu_celsius + c.filter_alpha * (mcu - s.mcu_celsius),
+                ntc_celsius: match (s.ntc_celsius, ntc) {
+                    (Some(v), Some(t)) => Some(v + c.filter_alpha * (t - v)),
+                    (_, t) => t,
+                },
+                ..s
+            },
+        };
+
+        let t = status.celsius();
+        let start = limit - c.derating_band;
+        status.derating = if t <= start {
+            1.0
+        } else if t >= limit || c.derating_band <= 0.0 {
+            0.0
+        } else {
+            (limit - t) / c.derating_band
+        };
+        status.shutdown = if status.shutdown {
+            t > limit - c.hysteresis
+        } else {
+            t >= limit
+        };
+        self.status = Some(status);
+        status
+    }
+
+    fn mcu_celsius(&self, raw: u16) -> f32 {
+        let v30 = self.config.ts_cal1 as f32 * TS_CAL1_VDDA / 4095.0;
+        let v = raw as f32 * VDDA / 4095.0;
+        (v - v30) / TS_SLOPE + TS_CAL1_CELSIUS
+    }
+}
+
+fn ntc_celsius(ntc: &NtcConfig, raw: u16) -> f32 {
+    // open or shorted sensor reads as hot, so it fails safe
+    if raw == 0 || raw >= 4095 {
+        return 150.0;
+    }
+    let r = ntc.pullup_ohms * raw as f32 / (4095 - raw) as f32;
+    let inv_t = 1.0 / 298.15 + ln(r / ntc.r25_ohms) / ntc.beta;
+    1.0 / inv_t - 273.15
+}
+
+/// Natural logarithm for x > 0, no libm here.
+fn ln(x: f32) -> f32 {
+    // x = m * 2^e with m in [1, 2)
+    let bits = x.to_bits();
+    let e = ((bits >> 23) & 0xFF) as i32 - 127;
+    let m = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+    // ln(m) = 2 atanh(s), s <= 1/3
+    let s = (m - 1.0) / (m + 1.0);
+    let s2 = s * s;
+    let atanh = s * (1.0 + s2 * (1.0 / 3.0 + s2 * (1.0 / 5.0 + s2 * (1.0 / 7.0))));
+    e as f32 * core::f32::consts::LN_2 + 2.0 * atanh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: f32 = 80.0;
+
+    /// Unfiltered, so every reading lands as it is.
+    fn monitor(ntc: Option<NtcConfig>) -> TemperatureMonitor {
+        TemperatureMonitor::new(TemperatureConfig {
+            ntc,
+            filter_alpha: 1.0,
+            ..TemperatureConfig::default()
+        })
+    }
+
+    /// Internal sensor reading at `celsius`, about 0.3°C per count.
+    fn mcu_raw(celsius: f32) -> u16 {
+        let c = TemperatureConfig::default();
+        let v30 = c.ts_cal1 as f32 * TS_CAL1_VDDA / 4095.0;
+        let v = v30 + (celsius - TS_CAL1_CELSIUS) * TS_SLOPE;
+        (v * 4095.0 / VDDA + 0.5) as u16
+    }
+
+    /// NTC divider reading at `celsius`, from the beta equation.
+    fn ntc_raw(ntc: &NtcConfig, celsius: f32) -> u16 {
+        let t = celsius + 273.15;
+        let r = ntc.r25_ohms * (ntc.beta * (1.0 / t - 1.0 / 298.15)).exp();
+        (4095.0 * r / (r + ntc.pullup_ohms) + 0.5) as u16
+    }
+
+    #[test]
+    fn ln_matches_std() {
+        for &x in &[1e-3, 0.1, 0.5, 0.99, 1.0, 1.5, 2.0, 3.7, 10.0, 1234.5] {
+            let x: f32 = x;
+            assert!((ln(x) - x.ln()).abs() < 1e-4, "ln({})", x);
+        }
+    }
+
+    #[test]
+    fn ntc_follows_the_beta_curve() {
+        let ntc = NtcConfig::default();
+        for &t in &[-10.0, 0.0, 25.0, 60.0, 85.0, 110.0] {
+            let celsius = ntc_celsius(&ntc, ntc_raw(&ntc, t));
+            assert!((celsius - t).abs() < 0.5, "{} read as {}", t, celsius);
+        }
+        // open or shorted sensor reads hot
+        assert_eq!(ntc_celsius(&ntc, 0), 150.0);
+        assert_eq!(ntc_celsius(&ntc, 4095), 150.0);
+    }
+
+    #[test]
+    fn hottest_sensor_wins() {
+        let ntc = NtcConfig::default();
+        let mut m = monitor(Some(ntc));
+        let s = m.update(mcu_raw(40.0), ntc_raw(&ntc, 70.0), LIMIT);
+        assert!((s.celsius() - 70.0).abs() < 0.5);
+        let s = m.update(mcu_raw(75.0), ntc_raw(&ntc, 30.0), LIMIT);
+        assert!((s.celsius() - 75.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn derating_ramps_across_the_band() {
+        let mut m = monitor(None);
+        // band is 15°C: full output up to 65°C, none from 80°C
+        let s = m.update(mcu_raw(60.0), 0, LIMIT);
+        assert_eq!(s.derating, 1.0);
+        assert!(!s.is_derating());
+        let s = m.update(mcu_raw(72.5), 0, LIMIT);
+        assert!((s.derating - 0.5).abs() < 0.03, "{}", s.derating);
+        let s = m.update(mcu_raw(85.0), 0, LIMIT);
+        assert_eq!(s.derating, 0.0);
+    }
+
+    #[test]
+    fn shutdown_holds_until_past_the_hysteresis() {
+        let mut m = monitor(None);
+        assert!(!m.update(mcu_raw(79.0), 0, LIMIT).shutdown);
+        assert!(m.update(mcu_raw(81.0), 0, LIMIT).shutdown);
+        // 10°C hysteresis: still off at 75°C, back on below 70°C
+        assert!(m.update(mcu_raw(75.0), 0, LIMIT).shutdown);
+        assert!(!m.update(mcu_raw(69.0), 0, LIMIT).shutdown);
+        assert!(!m.status().shutdown);
+    }
+}
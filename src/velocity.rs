@@ -0,0 +1,427 @@
+//! Encoder velocity estimation.
+//!
+//! Uses the M/T method: the number of counts between two captured edges is
+//! divided by the time between those edges, so the resolution at low speed is
+//! set by the timestamp clock rather than by the sampling period. The raw
+//! estimate can be smoothed by a first order low-pass or by an alpha-beta
+//! tracking filter.
+
+use core::f32::consts::PI;
+
+/// Encoder position captured on an edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub time_us: u32,
+    pub position: i64,
+}
+
+/// One observation, taken once per control period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub time_us: u32,
+    pub position: i64,
+    /// First edge seen since the previous sample, if any.
+    pub edge: Option<Edge>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    None,
+    /// v += alpha * (raw - v)
+    LowPass {
+        alpha: f32,
+    },
+    /// Position/velocity tracking filter, corrected on every captured edge.
+    AlphaBeta {
+        alpha: f32,
+        beta: f32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VelocityConfig {
+    /// Counts per motor revolution, quadrature included.
+    pub counts_per_rev: f32,
+    /// Counts between two captured edges, 4 when only one edge of one
+    /// channel is captured.
+    pub counts_per_edge: u32,
+    /// Motor revolutions per output shaft revolution.
+    pub gear_ratio: f32,
+    pub filter: Filter,
+    /// Without an edge for this long the speed is taken as zero.
+    pub timeout_us: u32,
+}
+
+impl Default for VelocityConfig {
+    fn default() -> Self {
+        Self {
+            counts_per_rev: 4.0 * 12.0,
+            // TIM3 captures the rising edges of channel A
+            counts_per_edge: 4,
+            gear_ratio: 1.0,
+            filter: Filter::LowPass { alpha: 0.2 },
+            timeout_us: 200_000,
+        }
+    }
+}
+
+pub struct VelocityEstimator {
+    config: VelocityConfig,
+    last_edge: Option<Edge>,
+    raw: f32,
+    velocity: f32,
+    // alpha-beta state, position kept relative to `ab_origin` to stay within f32 precision
+    ab_origin: i64,
+    ab_position: f32,
+    ab_time_us: Option<u32>,
+}
+
+impl VelocityEstimator {
+    pub fn new(config: VelocityConfig) -> Self {
+        Self {
+            config,
+            last_edge: None,
+            raw: 0.0,
+            velocity: 0.0,
+            ab_origin: 0,
+            ab_position: 0.0,
+            ab_time_us: None,
+        }
+    }
+
+    pub fn config(&self) -> &VelocityConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: VelocityConfig) {
+        self.config = config;
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.last_edge = None;
+        self.raw = 0.0;
+        self.velocity = 0.0;
+        self.ab_time_us = None;
+    }
+
+    /// Feeds one sample and returns the filtered velocity in counts/s.
+    pub fn update(&mut self, sample: Sample) -> f32 {
+        self.raw = self.measure(&sample);
+        self.velocity = match self.config.filter {
+            Filter::None => self.raw,
+            Filter::LowPass { alpha } => self.velocity + alpha * (self.raw - self.velocity),
+            Filter::AlphaBeta { alpha, beta } => self.track(&sample, alpha, beta),
+        };
+        self.velocity
+    }
+
+    fn measure(&mut self, sample: &Sample) -> f32 {
+        match (sample.edge, self.last_edge) {
+            (Some(edge), Some(last)) => {
+                self.last_edge = Some(edge);
+                let dt = edge.time_us.wrapping_sub(last.time_us);
+                let dp = edge.position - last.position;
+                if dt == 0 || dt > self.config.timeout_us {
+                    0.0
+                } else {
+                    dp as f32 * 1e6 / dt as f32
+                }
+            }
+            (Some(edge), None) => {
+                self.last_edge = Some(edge);
+                0.0
+            }
+            (None, Some(last)) => {
+                // No new edge: the speed is at most one edge spacing over the time elapsed since the last one.
+                let elapsed = sample.time_us.wrapping_sub(last.time_us);
+                if elapsed > self.config.timeout_us {
+                    self.last_edge = None;
+                    0.0
+                } else {
+                    let bound = self.config.counts_per_edge as f32 * 1e6 / elapsed.max(1) as f32;
+                    self.raw.max(-bound).min(bound)
+                }
+            }
+            (None, None) => 0.0,
+        }
+    }
+
+    fn track(&mut self, sample: &Sample, alpha: f32, beta: f32) -> f32 {
+        let edge = match sample.edge {
+            Some(edge) => edge,
+            // Between edges only the M/T bound applies, which also brings a stopped motor to zero.
+            None => {
+                let bound = if self.raw < 0.0 { -self.raw } else { self.raw };
+                return self.velocity.max(-bound).min(bound);
+            }
+        };
+        let last_time_us = match self.ab_time_us {
+            None => {
+                self.ab_time_us = Some(edge.time_us);
+                self.ab_origin = edge.position;
+                self.ab_position = 0.0;
+                return 0.0;
+            }
+            Some(t) => t,
+        };
+        let elapsed = edge.time_us.wrapping_sub(last_time_us);
+        if elapsed == 0 {
+            return self.velocity;
+        }
+        if elapsed > self.config.timeout_us {
+            self.ab_time_us = None;
+            return self.track(sample, alpha, beta);
+        }
+        let dt = elapsed as f32 * 1e-6;
+        self.ab_time_us = Some(edge.time_us);
+
+        let measured = (edge.position - self.ab_origin) as f32;
+        let predicted = self.ab_position + self.velocity * dt;
+        let residual = measured - predicted;
+        let velocity = self.velocity + beta * residual / dt;
+        // rebase on the new measurement
+        self.ab_position = predicted + alpha * residual - measured;
+        self.ab_origin = edge.position;
+        velocity
+    }
+
+    /// Unfiltered M/T estimate of the last update in counts/s.
+    pub fn raw_counts_per_second(&self) -> f32 {
+        self.raw
+    }
+
+    pub fn counts_per_second(&self) -> f32 {
+        self.velocity
+    }
+
+    /// Output shaft speed.
+    pub fn rad_per_second(&self) -> f32 {
+        self.velocity * 2.0 * PI / (self.config.counts_per_rev * self.config.gear_ratio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_US: u32 = 1000;
+    /// Captures on one edge of one channel, as `VelocityConfig::default`.
+    const COUNTS_PER_EDGE: i64 = 4;
+
+    /// Encoder turning at a constant `speed` counts/s from `start_us`, sampled
+    /// every control period like `EncoderPeripheral::sample`. Edges are
+    /// captured on every `COUNTS_PER_EDGE`th count.
+    struct Motor {
+        speed: f64,
+        start_us: u32,
+        origin: i64,
+        now_us: u32,
+    }
+
+    impl Motor {
+        fn new(speed: f64, start_us: u32) -> Self {
+            Self {
+                speed,
+                start_us,
+                origin: 0,
+                now_us: start_us,
+            }
+        }
+
+        /// Changes speed at the current sample, keeping the position.
+        fn set_speed(&mut self, speed: f64) {
+            self.origin += self.counts(self.now_us.wrapping_sub(self.start_us));
+            self.start_us = self.now_us;
+            self.speed = speed;
+        }
+
+        fn counts(&self, elapsed_us: u32) -> i64 {
+            if self.speed == 0.0 {
+                return 0;
+            }
+            let n = (self.speed.abs() * elapsed_us as f64 / 1e6).floor() as i64;
+            if self.speed < 0.0 {
+                -n
+            } else {
+                n
+            }
+        }
+
+        fn edge_time(&self, n: i64) -> u32 {
+            (n.abs() as f64 * 1e6 / self.speed.abs()).ceil() as u32
+        }
+
+        fn sample(&mut self) -> Sample {
+            let prev = self.now_us.wrapping_sub(self.start_us);
+            self.now_us = self.now_us.wrapping_add(PERIOD_US);
+            let elapsed = self.now_us.wrapping_sub(self.start_us);
+            let (p0, p1) = (self.counts(prev), self.counts(elapsed));
+            // 前回のサンプル以降の最初のエッジ
+            let step = (p1 - p0).signum();
+            let edge = (1..=(p1 - p0).abs())
+                .map(|i| p0 + i * step)
+                .find(|n| (self.origin + n) % COUNTS_PER_EDGE == 0)
+                .map(|first| Edge {
+                    time_us: self.start_us.wrapping_add(self.edge_time(first)),
+                    position: self.origin + first,
+                });
+            Sample {
+                time_us: self.now_us,
+                position: self.origin + p1,
+                edge,
+            }
+        }
+    }
+
+    fn config(filter: Filter) -> VelocityConfig {
+        VelocityConfig {
+            filter,
+            ..VelocityConfig::default()
+        }
+    }
+
+    fn run(estimator: &mut VelocityEstimator, motor: &mut Motor, periods: usize) -> f32 {
+        let mut v = 0.0;
+        for _ in 0..periods {
+            v = estimator.update(motor.sample());
+        }
+        v
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn mt_resolves_speeds_below_one_count_per_period() {
+        // 1 count every 2.5 periods, the M method alone would read 0 or 1000
+        for &speed in &[400.0, -400.0, 73.0] {
+            let mut estimator = VelocityEstimator::new(config(Filter::None));
+            let mut motor = Motor::new(speed, 0);
+            // two edges at the slowest
+            run(&mut estimator, &mut motor, 120);
+            for _ in 0..20 {
+                let v = estimator.update(motor.sample());
+                assert_close(v, speed as f32, speed.abs() as f32 * 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn slow_speed_holds_between_edges() {
+        // an edge every 40 periods
+        let mut estimator = VelocityEstimator::new(config(Filter::None));
+        let mut motor = Motor::new(100.0, 0);
+        run(&mut estimator, &mut motor, 100);
+        for _ in 0..200 {
+            let v = estimator.update(motor.sample());
+            assert_close(v, 100.0, 1.0);
+        }
+    }
+
+    #[test]
+    fn mt_at_high_speed() {
+        let mut estimator = VelocityEstimator::new(config(Filter::None));
+        let mut motor = Motor::new(123_456.0, 0);
+        assert_close(run(&mut estimator, &mut motor, 10), 123_456.0, 123.0);
+    }
+
+    #[test]
+    fn timestamps_wrap() {
+        let mut estimator = VelocityEstimator::new(config(Filter::None));
+        let mut motor = Motor::new(-2500.0, u32::MAX - 7_500);
+        assert_close(run(&mut estimator, &mut motor, 20), -2500.0, 25.0);
+    }
+
+    #[test]
+    fn stopping_decays_to_zero() {
+        let mut estimator = VelocityEstimator::new(config(Filter::None));
+        let mut motor = Motor::new(500.0, 0);
+        run(&mut estimator, &mut motor, 20);
+        let last = motor.now_us;
+        motor.set_speed(0.0);
+        let mut previous = estimator.raw_counts_per_second();
+        for _ in 0..100 {
+            let v = estimator.update(motor.sample());
+            // 最後のエッジからの経過時間でエッジ 1 つ分に抑えられる
+            let bound = COUNTS_PER_EDGE as f32 * 1e6 / (motor.now_us - last) as f32;
+            assert!(v <= previous && v <= bound + 1.0);
+            previous = v;
+        }
+        run(&mut estimator, &mut motor, 200);
+        assert_eq!(estimator.counts_per_second(), 0.0);
+    }
+
+    #[test]
+    fn first_edge_reads_zero() {
+        let mut estimator = VelocityEstimator::new(config(Filter::None));
+        let mut motor = Motor::new(5000.0, 0);
+        assert_eq!(estimator.update(motor.sample()), 0.0);
+        assert_close(estimator.update(motor.sample()), 5000.0, 50.0);
+    }
+
+    #[test]
+    fn low_pass_step_response() {
+        let alpha = 0.2;
+        let mut estimator = VelocityEstimator::new(config(Filter::LowPass { alpha }));
+        let mut motor = Motor::new(10_000.0, 0);
+        estimator.update(motor.sample());
+        let mut expected = 0.0;
+        for _ in 0..30 {
+            let v = estimator.update(motor.sample());
+            expected += alpha * (10_000.0 - expected);
+            assert_close(v, expected, 100.0);
+        }
+    }
+
+    #[test]
+    fn alpha_beta_tracks_a_constant_speed() {
+        let filter = Filter::AlphaBeta {
+            alpha: 0.5,
+            beta: 0.1,
+        };
+        let mut estimator = VelocityEstimator::new(config(filter));
+        let mut motor = Motor::new(-3000.0, 0);
+        let v = run(&mut estimator, &mut motor, 200);
+        assert_close(v, -3000.0, 30.0);
+        // no drift between edges at low speed either
+        motor.set_speed(200.0);
+        let v = run(&mut estimator, &mut motor, 500);
+        assert_close(v, 200.0, 4.0);
+    }
+
+    #[test]
+    fn alpha_beta_resets_after_the_timeout() {
+        let filter = Filter::AlphaBeta {
+            alpha: 0.5,
+            beta: 0.1,
+        };
+        let mut estimator = VelocityEstimator::new(config(filter));
+        let mut motor = Motor::new(1000.0, 0);
+        run(&mut estimator, &mut motor, 200);
+        motor.set_speed(0.0);
+        run(&mut estimator, &mut motor, 300);
+        assert_eq!(estimator.counts_per_second(), 0.0);
+    }
+
+    #[test]
+    fn rad_per_second_includes_the_gear() {
+        let mut estimator = VelocityEstimator::new(VelocityConfig {
+            counts_per_rev: 48.0,
+            counts_per_edge: 4,
+            gear_ratio: 10.0,
+            filter: Filter::None,
+            timeout_us: 200_000,
+        });
+        let mut motor = Motor::new(4800.0, 0);
+        run(&mut estimator, &mut motor, 10);
+        assert_close(estimator.rad_per_second(), 2.0 * PI * 10.0, 0.1);
+    }
+}
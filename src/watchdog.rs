@@ -0,0 +1,169 @@
+//! Watchdog supervisor: the hardware watchdog is only fed while every
+//! supervised task keeps checking in.
+
+pub trait Watchdog {
+    /// Starts the watchdog. It cannot be stopped again until reset.
+    fn start(&self, timeout_ms: u32);
+    fn feed(&self);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    /// Current loop, run from the ADC DMA interrupt
+    CurrentLoop,
+    /// 1kHz position/velocity loop
+    ControlLoop,
+    /// Bus polling in the main loop
+    Communication,
+}
+
+const TASKS: usize = 3;
+
+/// Why the MCU came out of reset, most specific cause first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetCause {
+    IndependentWatchdog = 1,
+    WindowWatchdog = 2,
+    LowPower = 3,
+    Software = 4,
+    /// Power-on or brown-out
+    BrownOut = 5,
+    OptionByteLoad = 6,
+    Pin = 7,
+    Unknown = 0,
+}
+
+pub struct Supervisor<W: Watchdog> {
+    watchdog: W,
+    deadline_us: [u32; TASKS],
+    last_check_in_us: [Option<u32>; TASKS],
+}
+
+impl<W: Watchdog> Supervisor<W> {
+    /// `deadline_us` is the longest gap allowed between two check-ins of each
+    /// task, in `Task` order.
+    pub fn new(watchdog: W, deadline_us: [u32; TASKS]) -> Self {
+        Self {
+            watchdog,
+            deadline_us,
+            last_check_in_us: [None; TASKS],
+        }
+    }
+
+    pub fn start(&self, timeout_ms: u32) {
+        self.watchdog.start(timeout_ms);
+    }
+
+    pub fn check_in(&mut self, task: Task, now_us: u32) {
+        self.last_check_in_us[task as usize] = Some(now_us);
+    }
+
+    /// First task past its deadline. A task that never checked in counts as late.
+    pub fn overdue(&self, now_us: u32) -> Option<Task> {
+        let tasks = [Task::CurrentLoop, Task::ControlLoop, Task::Communication];
+        tasks
+            .iter()
+            .copied()
+            .find(|&task| match self.last_check_in_us[task as usize] {
+                None => true,
+                Some(t) => now_us.wrapping_sub(t) > self.deadline_us[task as usize],
+            })
+    }
+
+    /// Feeds the watchdog if every task is on time. Call more often than the
+    /// watchdog timeout.
+    pub fn supervise(&self, now_us: u32) -> Result<(), Task> {
+        match self.overdue(now_us) {
+            None => {
+                self.watchdog.feed();
+                Ok(())
+            }
+            Some(task) => Err(task),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    #[derive(Default)]
+    struct Counter {
+        started: Cell<Option<u32>>,
+        feeds: Cell<u32>,
+    }
+
+    impl Watchdog for &Counter {
+        fn start(&self, timeout_ms: u32) {
+            self.started.set(Some(timeout_ms));
+        }
+        fn feed(&self) {
+            self.feeds.set(self.feeds.get() + 1);
+        }
+    }
+
+    const ALL: [Task; TASKS] = [Task::CurrentLoop, Task::ControlLoop, Task::Communication];
+
+    fn supervisor(counter: &Counter) -> Supervisor<&Counter> {
+        let supervisor = Supervisor::new(counter, [5_000, 5_000, 50_000]);
+        supervisor.start(100);
+        assert_eq!(counter.started.get(), Some(100));
+        supervisor
+    }
+
+    #[test]
+    fn fed_once_every_task_checked_in() {
+        let counter = Counter::default();
+        let mut supervisor = supervisor(&counter);
+        // never checked in counts as late
+        assert_eq!(supervisor.supervise(0), Err(Task::CurrentLoop));
+        supervisor.check_in(Task::CurrentLoop, 0);
+        supervisor.check_in(Task::ControlLoop, 0);
+        assert_eq!(supervisor.supervise(0), Err(Task::Communication));
+        assert_eq!(counter.feeds.get(), 0);
+        supervisor.check_in(Task::Communication, 0);
+        assert_eq!(supervisor.supervise(1_000), Ok(()));
+        assert_eq!(counter.feeds.get(), 1);
+    }
+
+    #[test]
+    fn a_late_task_blocks_the_feed() {
+        let counter = Counter::default();
+        let mut supervisor = supervisor(&counter);
+        for &task in &ALL {
+            supervisor.check_in(task, 0);
+        }
+        // the control loop stops checking in
+        for now in (1_000..=10_000).step_by(1_000) {
+            supervisor.check_in(Task::CurrentLoop, now);
+            supervisor.check_in(Task::Communication, now);
+            let expected = if now <= 5_000 {
+                Ok(())
+            } else {
+                Err(Task::ControlLoop)
+            };
+            assert_eq!(supervisor.supervise(now), expected);
+        }
+        assert_eq!(counter.feeds.get(), 5);
+    }
+
+    #[test]
+    fn feeding_resumes_after_the_task_recovers() {
+        let counter = Counter::default();
+        let mut supervisor = supervisor(&counter);
+        let start = u32::MAX - 20_000;
+        for &task in &ALL {
+            supervisor.check_in(task, start);
+        }
+        assert_eq!(supervisor.supervise(start.wrapping_add(4_000)), Ok(()));
+        // the current loop stalls, the others carry on across the wrap
+        let late = start.wrapping_add(30_000);
+        supervisor.check_in(Task::ControlLoop, late);
+        supervisor.check_in(Task::Communication, late);
+        assert_eq!(supervisor.supervise(late), Err(Task::CurrentLoop));
+        supervisor.check_in(Task::CurrentLoop, late);
+        assert_eq!(supervisor.supervise(late.wrapping_add(1)), Ok(()));
+        assert_eq!(counter.feeds.get(), 2);
+    }
+}